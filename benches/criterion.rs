@@ -0,0 +1,177 @@
+// Criterion version of the encoding benchmarks. Runs on stable via
+// `cargo bench`; the libtest `#[bench]` functions in src/lib.rs need nightly
+// and `--features nightly-bench`.
+
+#[macro_use]
+extern crate criterion;
+extern crate encoding_bench;
+#[cfg(feature = "compare-crates")]
+extern crate leb128;
+
+use std::mem;
+
+use criterion::{black_box, Criterion, Throughput};
+use encoding_bench::*;
+
+const DATASETS: &[(&str, &str)] = &[
+    ("metadata", METADATA),
+    ("dep_graph", DEP_GRAPH),
+    ("query_cache", QUERY_CACHE),
+    ("max_length", MAX_LENGTH),
+];
+
+macro_rules! values_of {
+    ($fn_name:ident, $variant:ident) => (
+        fn $fn_name(data: &'static str) -> Vec<u128> {
+            load_test_data(data).iter().filter_map(|entry| {
+                match *entry {
+                    Value::$variant(val) => Some(val as u128),
+                    _ => None,
+                }
+            }).collect()
+        }
+    )
+}
+
+values_of!(u32_values, U32);
+values_of!(u64_values, U64);
+values_of!(usize_values, Usize);
+
+fn bench_write<E: VarintEncoder>(c: &mut Criterion,
+                                 name: &str,
+                                 values: fn(&'static str) -> Vec<u128>,
+                                 int_size: usize) {
+    let mut group = c.benchmark_group(name);
+
+    for &(data_name, data) in DATASETS {
+        let values = values(data);
+        if values.is_empty() {
+            continue
+        }
+
+        group.throughput(Throughput::Bytes((values.len() * int_size) as u64));
+        group.bench_function(data_name, |b| b.iter(|| {
+            let mut output = Vec::with_capacity(values.len() * int_size * 2);
+            let mut position = 0;
+            for &val in values.iter() {
+                position += E::encode(&mut output, position, val);
+            }
+            black_box(output)
+        }));
+    }
+
+    group.finish();
+}
+
+fn bench_read<E: VarintEncoder, D: VarintDecoder>(c: &mut Criterion,
+                                                  name: &str,
+                                                  values: fn(&'static str) -> Vec<u128>,
+                                                  int_size: usize) {
+    let mut group = c.benchmark_group(name);
+
+    for &(data_name, data) in DATASETS {
+        let values = values(data);
+        if values.is_empty() {
+            continue
+        }
+
+        let mut encoded = Vec::new();
+        for &val in values.iter() {
+            let pos = encoded.len();
+            E::encode(&mut encoded, pos, val);
+        }
+
+        group.throughput(Throughput::Bytes((values.len() * int_size) as u64));
+        group.bench_function(data_name, |b| b.iter(|| {
+            let mut position = 0;
+            for _ in 0 .. values.len() {
+                let (val, count) = D::decode(&encoded, position);
+                black_box(val);
+                position += count;
+            }
+        }));
+    }
+
+    group.finish();
+}
+
+fn writers(c: &mut Criterion) {
+    bench_write::<Leb128CU32>(c, "write_leb128c_u32", u32_values, mem::size_of::<u32>());
+    bench_write::<Leb128CU64>(c, "write_leb128c_u64", u64_values, mem::size_of::<u64>());
+    bench_write::<Leb128CUsize>(c, "write_leb128c_usize", usize_values, mem::size_of::<usize>());
+    bench_write::<LesqliteU32>(c, "write_lesqlite_u32", u32_values, mem::size_of::<u32>());
+    bench_write::<LesqliteUsize>(c, "write_lesqlite_usize", usize_values, mem::size_of::<usize>());
+    bench_write::<PrefixU32>(c, "write_prefix_u32", u32_values, mem::size_of::<u32>());
+    bench_write::<PrefixUsize>(c, "write_prefix_usize", usize_values, mem::size_of::<usize>());
+    bench_write::<VlqU64>(c, "write_vlq_u64", u64_values, mem::size_of::<u64>());
+    bench_write::<Sqlite4U64>(c, "write_sqlite4_u64", u64_values, mem::size_of::<u64>());
+}
+
+fn readers(c: &mut Criterion) {
+    bench_read::<Leb128C, Leb128Ref>(c, "read_leb128_ref_u64", u64_values, mem::size_of::<u64>());
+    bench_read::<Leb128C, Leb128Fixed2U64>(c, "read_leb128_fixed2_u64", u64_values, mem::size_of::<u64>());
+    bench_read::<Leb128C, Leb128WeirdU64>(c, "read_leb128_weird_u64", u64_values, mem::size_of::<u64>());
+    bench_read::<Leb128C, Leb128BranchlessU64>(c, "read_leb128_branchless_u64", u64_values, mem::size_of::<u64>());
+    bench_read::<LesqliteU32, LesqliteU32>(c, "read_lesqlite_u32", u32_values, mem::size_of::<u32>());
+    bench_read::<LesqliteU32, LesqliteTableU32>(c, "read_lesqlite_u32_table", u32_values, mem::size_of::<u32>());
+    bench_read::<LesqliteUsize, LesqliteUsize>(c, "read_lesqlite_usize", usize_values, mem::size_of::<usize>());
+    bench_read::<PrefixU32, PrefixU32>(c, "read_prefix_u32", u32_values, mem::size_of::<u32>());
+    bench_read::<PrefixUsize, PrefixUsize>(c, "read_prefix_usize", usize_values, mem::size_of::<usize>());
+    bench_read::<VlqU64, VlqU64>(c, "read_vlq_u64", u64_values, mem::size_of::<u64>());
+    bench_read::<Sqlite4U64, Sqlite4U64>(c, "read_sqlite4_u64", u64_values, mem::size_of::<u64>());
+}
+
+// The `leb128` crate as an external baseline for `write_leb128c_u64` and
+// `read_leb128_ref_u64` above. It writes through `io::Write` and reads through
+// `io::Read`, so it appends instead of writing at a position.
+#[cfg(feature = "compare-crates")]
+fn leb128_crate(c: &mut Criterion) {
+    let int_size = mem::size_of::<u64>();
+
+    let mut group = c.benchmark_group("write_leb128_crate_u64");
+    for &(data_name, data) in DATASETS {
+        let values = u64_values(data);
+        if values.is_empty() {
+            continue
+        }
+
+        group.throughput(Throughput::Bytes((values.len() * int_size) as u64));
+        group.bench_function(data_name, |b| b.iter(|| {
+            let mut output = Vec::with_capacity(values.len() * int_size * 2);
+            for &val in values.iter() {
+                leb128::write::unsigned(&mut output, val as u64).unwrap();
+            }
+            black_box(output)
+        }));
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("read_leb128_crate_u64");
+    for &(data_name, data) in DATASETS {
+        let values = u64_values(data);
+        if values.is_empty() {
+            continue
+        }
+
+        let mut encoded = Vec::new();
+        for &val in values.iter() {
+            let pos = encoded.len();
+            Leb128C::encode(&mut encoded, pos, val);
+        }
+
+        group.throughput(Throughput::Bytes((values.len() * int_size) as u64));
+        group.bench_function(data_name, |b| b.iter(|| {
+            let mut input = &encoded[..];
+            for _ in 0 .. values.len() {
+                black_box(leb128::read::unsigned(&mut input).unwrap());
+            }
+        }));
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "compare-crates"))]
+criterion_group!(benches, writers, readers);
+#[cfg(feature = "compare-crates")]
+criterion_group!(benches, writers, readers, leb128_crate);
+criterion_main!(benches);
@@ -0,0 +1,40 @@
+// Prints the encoded bytes of an integer in each of the variable-length
+// schemes, e.g. `cargo run --example encode -- 12345`.
+
+extern crate encoding_bench;
+
+use std::env;
+use std::process;
+
+use encoding_bench::*;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+fn show(name: &str, value: u128, write: fn(&mut Vec<u8>, usize, u128) -> usize, read: fn(&[u8], usize) -> (u128, usize)) {
+    let mut out = Vec::new();
+    let written = write(&mut out, 0, value);
+    let (decoded, read) = read(&out, 0);
+
+    println!("{:<10} {:<57} ({} bytes) -> {}{}",
+             name,
+             hex(&out[.. written]),
+             written,
+             decoded,
+             if decoded == value && read == written { "" } else { "  MISMATCH" });
+}
+
+fn main() {
+    let value = match env::args().nth(1).map(|arg| arg.parse::<u128>()) {
+        Some(Ok(value)) => value,
+        _ => {
+            eprintln!("usage: encode <unsigned integer>");
+            process::exit(1);
+        }
+    };
+
+    show("leb128", value, write_leb128c_u128, read_leb128_ref_u128);
+    show("lesqlite", value, impl_write_u128_lesqlite, read_lesqlite_u128);
+    show("prefix", value, impl_write_u128_prefix, read_prefix_u128);
+}
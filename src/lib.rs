@@ -1,21 +1,43 @@
-
-#![feature(test)]
-#![feature(concat_idents)]
+// The codecs themselves only need `alloc`. Loading the test data and timing
+// the benchmarks needs the `std` feature, which is on by default.
+#![no_std]
+#![cfg_attr(all(test, feature = "nightly-bench"), feature(test))]
 #![allow(unused)]
-#![feature(stdsimd)]
 
-extern crate test;
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
+#[macro_use]
+extern crate std;
 
-use std::rc::Rc;
-use std::cell::RefCell;
+#[cfg(all(test, feature = "nightly-bench"))]
+extern crate test;
+#[cfg(all(test, feature = "nightly-bench"))]
+extern crate memmap2;
+#[cfg(all(test, feature = "perf-counters"))]
+extern crate perf_event;
+
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::cmp;
+use core::mem;
+use core::str::FromStr;
+
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "std")]
 use std::collections::HashMap;
+#[cfg(feature = "std")]
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::cmp;
-use std::mem;
-use std::str::FromStr;
-
-enum Value {
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, BufReader, Read};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
     U8(u8),
     U16(u16),
     U32(u32),
@@ -30,59 +52,252 @@ enum Value {
     Isize(isize),
 }
 
-thread_local! {
-    static TEST_DATA: RefCell<Option<HashMap<&'static str, Rc<Vec<Value>>>>> = RefCell::new(None);
+#[cfg(feature = "std")]
+type TestDataMap = HashMap<&'static str, Arc<Vec<Value>>>;
+
+// Shared by all threads so that tests, which each run on their own thread,
+// only parse every file once.
+#[cfg(feature = "std")]
+static TEST_DATA: Mutex<Option<TestDataMap>> = Mutex::new(None);
+
+pub const METADATA: &str = "test_data/regex_metadata.txt";
+pub const DEP_GRAPH: &str = "test_data/regex_dep_graph.txt";
+pub const QUERY_CACHE: &str = "test_data/regex_query_cache.txt";
+
+// Not a file: generated on load, see `generate_max_length_data`.
+pub const MAX_LENGTH: &str = "synthetic/max_length";
+
+#[cfg(feature = "std")]
+#[doc(hidden)]
+pub fn load_test_data(name: &'static str) -> Arc<Vec<Value>> {
+    let mut map = TEST_DATA.lock().unwrap();
+    let map = map.get_or_insert_with(HashMap::new);
+
+    if let Some(data) = map.get(name) {
+        return data.clone();
+    }
+
+    let data = if name == MAX_LENGTH {
+        generate_max_length_data()
+    } else {
+        parse_test_data(BufReader::new(File::open(name).unwrap()))
+    };
+
+    let data = Arc::new(data);
+    map.insert(name, data.clone());
+    data
 }
 
-const METADATA: &'static str = "test_data/regex_metadata.txt";
-const DEP_GRAPH: &'static str = "test_data/regex_dep_graph.txt";
-const QUERY_CACHE: &'static str = "test_data/regex_query_cache.txt";
+// Worst case for the variable-length encodings: every value has the top bit
+// of its type set, so it takes the longest possible leb128 encoding. The
+// signed values are all negative with a large magnitude for the same reason.
+#[cfg(feature = "std")]
+fn generate_max_length_data() -> Vec<Value> {
+    const COUNT: u64 = 10_000;
+
+    macro_rules! top_bit_set {
+        ($bits:expr, $t:ident) => (($bits as $t) | !($t::MAX >> 1))
+    }
+
+    let mut data = Vec::new();
+
+    for i in 0 .. COUNT {
+        let bits = i.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+
+        data.push(Value::U8(top_bit_set!(bits, u8)));
+        data.push(Value::U16(top_bit_set!(bits, u16)));
+        data.push(Value::U32(top_bit_set!(bits, u32)));
+        data.push(Value::U64(top_bit_set!(bits, u64)));
+        data.push(Value::U128(top_bit_set!(bits, u128)));
+        data.push(Value::Usize(top_bit_set!(bits, usize)));
+        data.push(Value::I8(top_bit_set!(bits, u8) as i8));
+        data.push(Value::I16(top_bit_set!(bits, u16) as i16));
+        data.push(Value::I32(top_bit_set!(bits, u32) as i32));
+        data.push(Value::I64(top_bit_set!(bits, u64) as i64));
+        data.push(Value::I128(top_bit_set!(bits, u128) as i128));
+        data.push(Value::Isize(top_bit_set!(bits, usize) as isize));
+    }
 
-fn load_test_data(name: &'static str) -> Rc<Vec<Value>> {
-    TEST_DATA.with(|test_data| {
-        let mut map = test_data.borrow_mut();
+    data
+}
 
-        if map.is_none() {
-            *map = Some(HashMap::new());
+// A file may start with an `# endian=le` or `# endian=be` line recording the
+// byte order of the machine it was captured on. Values from a file with the
+// other byte order than the host get byte-swapped on load. Files without the
+// line are taken to be in host order. Other `#` lines are comments.
+#[cfg(feature = "std")]
+fn parse_test_data<R: BufRead>(reader: R) -> Vec<Value> {
+    let mut data = Vec::new();
+    let mut swap = false;
+
+    for line in reader.lines() {
+        let line = line.unwrap();
+
+        if let Some(comment) = line.strip_prefix("#") {
+            match comment.trim() {
+                "endian=le" => swap = cfg!(target_endian = "big"),
+                "endian=be" => swap = cfg!(target_endian = "little"),
+                _ => {}
+            }
+            continue;
         }
 
-        let mut map = map.as_mut().unwrap();
+        let sep = line.find(" ").unwrap();
+        let ty = &line[..sep];
+        let value = &line[sep + 1 ..];
 
-        if let Some(data) = map.get(name) {
-            return data.clone();
+        macro_rules! parse {
+            ($int_ty:ident) => ({
+                let v = $int_ty::from_str_radix(value, 16).unwrap();
+                if swap { v.swap_bytes() } else { v }
+            })
         }
 
-        let file = BufReader::new(File::open(name).unwrap());
+        data.push(match ty {
+            "u8" => Value::U8(parse!(u8)),
+            "u16" => Value::U16(parse!(u16)),
+            "u32" => Value::U32(parse!(u32)),
+            "u64" => Value::U64(parse!(u64)),
+            "u128" => Value::U128(parse!(u128)),
+            "usize" => Value::Usize(parse!(usize)),
+            "i8" => Value::I8(parse!(i8)),
+            "i16" => Value::I16(parse!(i16)),
+            "i32" => Value::I32(parse!(i32)),
+            "i64" => Value::I64(parse!(i64)),
+            "i128" => Value::I128(parse!(i128)),
+            "isize" => Value::Isize(parse!(isize)),
+            _ => panic!(),
+        });
+    }
+
+    data
+}
 
-        let mut data = Vec::new();
+#[cfg(target_endian = "little")]
+#[cfg(feature = "std")]
+#[test]
+fn load_test_data_swaps_foreign_endian() {
+    let text = "# endian=be\nu8 12\nu16 1234\nu32 12345678\nu64 0102030405060708\n\
+                i16 -2\nusize 1\n";
+
+    let path = ::std::env::temp_dir().join(format!("encoding-bench-endian-{}.txt", ::std::process::id()));
+    io::Write::write_all(&mut File::create(&path).unwrap(), text.as_bytes()).unwrap();
+
+    // `load_test_data` caches by name for the whole process.
+    let name: &'static str = Box::leak(Box::<str>::from(path.to_str().unwrap()));
+    let data = load_test_data(name);
+    ::std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(*data, vec![
+        Value::U8(0x12),
+        Value::U16(0x3412),
+        Value::U32(0x7856_3412),
+        Value::U64(0x0807_0605_0403_0201),
+        Value::I16((-2i16).swap_bytes()),
+        Value::Usize(1usize.swap_bytes()),
+    ]);
+
+    let native = parse_test_data(BufReader::new("# endian=le\nu16 1234\n".as_bytes()));
+    assert_eq!(native, vec![Value::U16(0x1234)]);
+}
 
-        for line in file.lines() {
-            let line = line.unwrap();
-            let sep = line.find(" ").unwrap();
-            let ty = &line[..sep];
-            let value = &line[sep + 1 ..];
+// Binary version of the test data: one `mixed_tag` byte per value, followed
+// by the value in little-endian. usize and isize always take eight bytes so
+// that the files don't depend on the target they were written on.
 
-            data.push(match ty {
-                "u8" => Value::U8(u8::from_str_radix(value, 16).unwrap()),
-                "u16" => Value::U16(u16::from_str_radix(value, 16).unwrap()),
-                "u32" => Value::U32(u32::from_str_radix(value, 16).unwrap()),
-                "u64" => Value::U64(u64::from_str_radix(value, 16).unwrap()),
-                "u128" => Value::U128(u128::from_str_radix(value, 16).unwrap()),
-                "usize" => Value::Usize(usize::from_str_radix(value, 16).unwrap()),
-                "i8" => Value::I8(i8::from_str_radix(value, 16).unwrap()),
-                "i16" => Value::I16(i16::from_str_radix(value, 16).unwrap()),
-                "i32" => Value::I32(i32::from_str_radix(value, 16).unwrap()),
-                "i64" => Value::I64(i64::from_str_radix(value, 16).unwrap()),
-                "i128" => Value::I128(i128::from_str_radix(value, 16).unwrap()),
-                "isize" => Value::Isize(isize::from_str_radix(value, 16).unwrap()),
-                _ => panic!(),
-            });
+#[cfg(feature = "std")]
+#[doc(hidden)]
+pub fn load_test_data_binary(name: &str) -> Arc<Vec<Value>> {
+    let mut bytes = Vec::new();
+    File::open(name).unwrap().read_to_end(&mut bytes).unwrap();
+
+    let mut data = Vec::new();
+    let mut position = 0;
+
+    macro_rules! take {
+        ($int_ty:ident) => ({
+            let size = mem::size_of::<$int_ty>();
+            let mut raw = [0u8; mem::size_of::<$int_ty>()];
+            raw.copy_from_slice(&bytes[position .. position + size]);
+            position += size;
+            $int_ty::from_le_bytes(raw)
+        })
+    }
+
+    while position < bytes.len() {
+        let tag = bytes[position];
+        position += 1;
+
+        data.push(match tag {
+            0 => Value::U8(take!(u8)),
+            1 => Value::U16(take!(u16)),
+            2 => Value::U32(take!(u32)),
+            3 => Value::U64(take!(u64)),
+            4 => Value::U128(take!(u128)),
+            5 => Value::Usize(take!(u64) as usize),
+            6 => Value::I8(take!(i8)),
+            7 => Value::I16(take!(i16)),
+            8 => Value::I32(take!(i32)),
+            9 => Value::I64(take!(i64)),
+            10 => Value::I128(take!(i128)),
+            11 => Value::Isize(take!(i64) as isize),
+            _ => panic!("unknown type tag {}", tag),
+        });
+    }
+
+    Arc::new(data)
+}
+
+#[cfg(feature = "std")]
+#[doc(hidden)]
+pub fn convert_text_to_binary(text_path: &str, binary_path: &str) -> io::Result<()> {
+    let data = parse_test_data(BufReader::new(File::open(text_path)?));
+    let mut out = Vec::new();
+
+    for value in data.iter() {
+        out.push(mixed_tag(value));
+
+        match *value {
+            Value::U8(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::U16(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::U32(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::U64(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::U128(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::Usize(v) => out.extend_from_slice(&(v as u64).to_le_bytes()),
+            Value::I8(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::I16(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::I32(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::I64(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::I128(v) => out.extend_from_slice(&v.to_le_bytes()),
+            Value::Isize(v) => out.extend_from_slice(&(v as i64).to_le_bytes()),
         }
+    }
+
+    io::Write::write_all(&mut File::create(binary_path)?, &out)
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn binary_test_data_round_trip() {
+    let text = "u8 ff\nu16 1234\nu32 deadbeef\nu64 550c36efc7b731a3\n\
+                u128 ffffffffffffffffffffffffffffffff\nusize 29246\n\
+                i8 -5\ni16 7fff\ni32 -80000000\ni64 -1\ni128 -7\nisize 0\n";
+
+    let dir = ::std::env::temp_dir();
+    let text_path = dir.join(format!("encoding-bench-binary-{}.txt", ::std::process::id()));
+    let binary_path = text_path.with_extension("bin");
+    let text_path = text_path.to_str().unwrap();
+    let binary_path = binary_path.to_str().unwrap();
 
-        let data = Rc::new(data);
-        map.insert(name, data.clone());
-        data
-    })
+    io::Write::write_all(&mut File::create(text_path).unwrap(), text.as_bytes()).unwrap();
+    convert_text_to_binary(text_path, binary_path).unwrap();
+
+    let expected = parse_test_data(text.as_bytes());
+    assert_eq!(expected.len(), 12);
+    assert_eq!(*load_test_data_binary(binary_path), expected);
+
+    ::std::fs::remove_file(text_path).unwrap();
+    ::std::fs::remove_file(binary_path).unwrap();
 }
 
 
@@ -95,6 +310,139 @@ macro_rules! next_size {
 }
 
 
+// Common interface for encoding schemes ---------------------------------------
+
+pub trait VarintEncoder {
+    fn encode(out: &mut Vec<u8>, pos: usize, v: u128) -> usize;
+
+    // The number of bytes `encode` would write, for schemes that can tell
+    // without encoding.
+    fn encoded_len(v: u128) -> Option<usize> {
+        None
+    }
+}
+
+pub trait VarintDecoder {
+    fn decode(data: &[u8], pos: usize) -> (u128, usize);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEof,
+    Overlong,
+    NonCanonical,
+    Overflow,
+    ChecksumMismatch,
+    TrailingBytes,
+}
+
+macro_rules! impl_varint_encoder {
+    ($marker:ident, $fun:ident, $int_ty:ident) => (
+        pub struct $marker;
+
+        impl VarintEncoder for $marker {
+            #[inline]
+            fn encode(out: &mut Vec<u8>, pos: usize, v: u128) -> usize {
+                $fun(out, pos, v as $int_ty)
+            }
+        }
+    );
+    ($marker:ident, $fun:ident, $int_ty:ident, $len:ident) => (
+        pub struct $marker;
+
+        impl VarintEncoder for $marker {
+            #[inline]
+            fn encode(out: &mut Vec<u8>, pos: usize, v: u128) -> usize {
+                $fun(out, pos, v as $int_ty)
+            }
+
+            #[inline]
+            fn encoded_len(v: u128) -> Option<usize> {
+                Some($len(v as $int_ty))
+            }
+        }
+    )
+}
+
+macro_rules! impl_varint_codec {
+    ($marker:ident, $write:ident, $read:ident, $int_ty:ident) => (
+        pub struct $marker;
+
+        impl VarintEncoder for $marker {
+            #[inline]
+            fn encode(out: &mut Vec<u8>, pos: usize, v: u128) -> usize {
+                $write(out, pos, v as $int_ty)
+            }
+        }
+
+        impl VarintDecoder for $marker {
+            #[inline]
+            fn decode(data: &[u8], pos: usize) -> (u128, usize) {
+                let (val, read) = $read(data, pos);
+                (val as u128, read)
+            }
+        }
+    );
+    ($marker:ident, $write:ident, $read:ident, $int_ty:ident, $len:ident) => (
+        pub struct $marker;
+
+        impl VarintEncoder for $marker {
+            #[inline]
+            fn encode(out: &mut Vec<u8>, pos: usize, v: u128) -> usize {
+                $write(out, pos, v as $int_ty)
+            }
+
+            #[inline]
+            fn encoded_len(v: u128) -> Option<usize> {
+                Some($len(v as $int_ty))
+            }
+        }
+
+        impl VarintDecoder for $marker {
+            #[inline]
+            fn decode(data: &[u8], pos: usize) -> (u128, usize) {
+                let (val, read) = $read(data, pos);
+                (val as u128, read)
+            }
+        }
+    )
+}
+
+macro_rules! impl_varint_decoder {
+    ($marker:ident, $fun:ident) => (
+        pub struct $marker;
+
+        impl VarintDecoder for $marker {
+            #[inline]
+            fn decode(data: &[u8], pos: usize) -> (u128, usize) {
+                let (val, read) = $fun(data, pos);
+                (val as u128, read)
+            }
+        }
+    )
+}
+
+
+// xorshift64, so we don't need a dependency for a bit of randomness. The state
+// must not be zero.
+#[inline]
+fn xorshift64(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+// Fisher-Yates with a fixed seed, so every run sees the same order.
+fn shuffle<T>(values: &mut [T], seed: u64) {
+    let mut state = seed;
+
+    for i in (1 .. values.len()).rev() {
+        let j = (xorshift64(&mut state) % (i as u64 + 1)) as usize;
+        values.swap(i, j);
+    }
+}
+
 
 // Different ways of writing a slice to a vector -------------------------------
 
@@ -117,7 +465,7 @@ fn write_slice_to_vec(output: &mut Vec<u8>, start_position: usize, input: &[u8])
     let first_half = cmp::min(capacity, input_len);
 
     if first_half > 0 {
-        (&mut output[start_position..]).copy_from_slice(&input[.. first_half]);
+        output[start_position .. start_position + first_half].copy_from_slice(&input[.. first_half]);
     }
 
     if first_half < input_len {
@@ -133,7 +481,7 @@ fn write_slice_to_vec_cold(output: &mut Vec<u8>, start_position: usize, input: &
     let first_half = cmp::min(capacity, input_len);
 
     if first_half > 0 {
-        (&mut output[start_position..]).copy_from_slice(&input[.. first_half]);
+        output[start_position .. start_position + first_half].copy_from_slice(&input[.. first_half]);
     }
 
     if first_half < input_len {
@@ -150,6 +498,31 @@ fn write_slice_to_vec_skewed(output: &mut Vec<u8>, start_position: usize, input:
     }
 }
 
+// No split into an overwrite and an append part. For the few bytes a varint
+// takes, this might beat the setup of `copy_from_slice`.
+#[inline]
+fn write_slice_to_vec_manual(output: &mut Vec<u8>, start_position: usize, input: &[u8]) {
+    for (i, &byte) in input.iter().enumerate() {
+        write_to_vec(output, start_position + i, byte);
+    }
+}
+
+#[test]
+fn write_slice_to_vec_overwrite() {
+    for &write in &[write_slice_to_vec, write_slice_to_vec_cold, write_slice_to_vec_skewed,
+                    write_slice_to_vec_manual] {
+        let mut output = vec![0; 6];
+        write(&mut output, 1, &[1, 2, 3]);
+        assert_eq!(output, [0, 1, 2, 3, 0, 0]);
+
+        write(&mut output, 4, &[4, 5, 6]);
+        assert_eq!(output, [0, 1, 2, 3, 4, 5, 6]);
+
+        write(&mut output, 7, &[7]);
+        assert_eq!(output, [0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+}
+
 #[inline]
 fn write_to_vec(vec: &mut Vec<u8>, position: usize, byte: u8) {
     if position == vec.len() {
@@ -159,6 +532,31 @@ fn write_to_vec(vec: &mut Vec<u8>, position: usize, byte: u8) {
     }
 }
 
+// For callers that only ever append: skips the overwrite check.
+#[inline]
+fn write_to_vec_unchecked_append(vec: &mut Vec<u8>, position: usize, byte: u8) {
+    debug_assert_eq!(position, vec.len());
+    vec.push(byte);
+}
+
+
+/// Makes `written` bytes stored at `start` through a raw pointer part of the
+/// vector, growing its length if the write went past the old end.
+///
+/// Callers must guarantee that `start <= out.len()`, that
+/// `start + written <= out.capacity()`, and that all bytes in
+/// `start .. start + written` have been initialized.
+#[inline]
+unsafe fn commit_written(out: &mut Vec<u8>, start: usize, written: usize) {
+    debug_assert!(start <= out.len());
+    debug_assert!(start + written <= out.capacity());
+
+    let end = start + written;
+
+    if end > out.len() {
+        out.set_len(end);
+    }
+}
 
 // Uncompressed, little-endian -------------------------------------------------
 
@@ -166,32 +564,24 @@ macro_rules! impl_write_raw {
     ($fun:ident, $t:ident, $push:ident) => (
         #[inline]
         fn $fun(output: &mut Vec<u8>, start_position: usize, x: $t) -> usize {
-            #[repr(packed)] struct Unaligned<T>(T);
+            #[repr(C, packed)] struct Unaligned<T>(T);
 
             let initial_len = output.len();
-            // assert!(output.capacity() >= initial_len + mem::size_of::<$t>());
+            let end = start_position + mem::size_of::<$t>();
+            assert!(start_position <= initial_len);
+
+            // A no-op for the pre-sized outputs of the benchmarks, but writing
+            // past the capacity would be undefined behavior.
+            output.reserve(end.saturating_sub(initial_len));
 
             unsafe {
-                let ptr = output.as_mut_ptr().offset(start_position as isize);
+                let ptr = output.as_mut_ptr().add(start_position);
                 *(ptr as *mut Unaligned<$t>) = Unaligned(x.to_le());
 
-                let bytes_written = mem::size_of::<$t>();
-
-                if start_position == initial_len {
-                    unsafe {
-                        output.set_len(initial_len + bytes_written);
-                    }
+                // Also covers writes that start before the end and run past it.
+                if end > initial_len {
+                    output.set_len(end);
                 }
-                // else {
-                //     let bytes_overwritten = initial_len - start_position;
-                //     let additional_bytes = bytes_written.saturating_sub(bytes_overwritten);
-
-                //     if additional_bytes > 0 {
-                //         unsafe {
-                //             output.set_len(initial_len + additional_bytes);
-                //         }
-                //     }
-                // }
             }
 
             mem::size_of::<$t>()
@@ -212,31 +602,174 @@ impl_write_raw!(write_raw_i64_solo, i64, write_to_vec_solo);
 impl_write_raw!(write_raw_i128_solo, i128, write_to_vec_solo);
 impl_write_raw!(write_raw_isize_solo, isize, write_to_vec_solo);
 
-// impl_write_raw!(write_raw_u8_slice, u8, write_slice_to_vec);
-// impl_write_raw!(write_raw_u16_slice, u16, write_slice_to_vec);
-// impl_write_raw!(write_raw_u32_slice, u32, write_slice_to_vec);
-// impl_write_raw!(write_raw_u64_slice, u64, write_slice_to_vec);
-// impl_write_raw!(write_raw_u128_slice, u128, write_slice_to_vec);
-// impl_write_raw!(write_raw_usize_slice, usize, write_slice_to_vec);
-// impl_write_raw!(write_raw_i8_slice, i8, write_slice_to_vec);
-// impl_write_raw!(write_raw_i16_slice, i16, write_slice_to_vec);
-// impl_write_raw!(write_raw_i32_slice, i32, write_slice_to_vec);
-// impl_write_raw!(write_raw_i64_slice, i64, write_slice_to_vec);
-// impl_write_raw!(write_raw_i128_slice, i128, write_slice_to_vec);
-// impl_write_raw!(write_raw_isize_slice, isize, write_slice_to_vec);
-
-// impl_write_raw!(write_raw_u8_skewed, u8, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_u16_skewed, u16, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_u32_skewed, u32, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_u64_skewed, u64, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_u128_skewed, u128, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_usize_skewed, usize, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_i8_skewed, i8, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_i16_skewed, i16, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_i32_skewed, i32, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_i64_skewed, i64, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_i128_skewed, i128, write_slice_to_vec_skewed);
-// impl_write_raw!(write_raw_isize_skewed, isize, write_slice_to_vec_skewed);
+impl_varint_encoder!(RawU8Solo, write_raw_u8_solo, u8);
+impl_varint_encoder!(RawU16Solo, write_raw_u16_solo, u16);
+impl_varint_encoder!(RawU32Solo, write_raw_u32_solo, u32);
+impl_varint_encoder!(RawU64Solo, write_raw_u64_solo, u64);
+impl_varint_encoder!(RawU128Solo, write_raw_u128_solo, u128);
+impl_varint_encoder!(RawUsizeSolo, write_raw_usize_solo, usize);
+
+// The variants above write through a pointer and ignore the push strategy, so
+// the slice-based flavors get their own macro.
+macro_rules! impl_write_raw_slice {
+    ($fun:ident, $t:ident, $write:ident) => (
+        #[inline]
+        fn $fun(output: &mut Vec<u8>, start_position: usize, x: $t) -> usize {
+            let x = x.to_le();
+            let bytes = unsafe {
+                ::core::slice::from_raw_parts(&x as *const $t as *const u8, mem::size_of::<$t>())
+            };
+
+            $write(output, start_position, bytes);
+            mem::size_of::<$t>()
+        }
+    )
+}
+
+impl_write_raw_slice!(write_raw_u8_slice, u8, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_u16_slice, u16, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_u32_slice, u32, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_u64_slice, u64, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_u128_slice, u128, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_usize_slice, usize, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_i8_slice, i8, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_i16_slice, i16, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_i32_slice, i32, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_i64_slice, i64, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_i128_slice, i128, write_slice_to_vec);
+impl_write_raw_slice!(write_raw_isize_slice, isize, write_slice_to_vec);
+
+impl_write_raw_slice!(write_raw_u8_skewed, u8, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_u16_skewed, u16, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_u32_skewed, u32, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_u64_skewed, u64, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_u128_skewed, u128, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_usize_skewed, usize, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_i8_skewed, i8, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_i16_skewed, i16, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_i32_skewed, i32, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_i64_skewed, i64, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_i128_skewed, i128, write_slice_to_vec_skewed);
+impl_write_raw_slice!(write_raw_isize_skewed, isize, write_slice_to_vec_skewed);
+
+impl_write_raw_slice!(write_raw_u64_manual, u64, write_slice_to_vec_manual);
+
+impl_varint_encoder!(RawU8Slice, write_raw_u8_slice, u8);
+impl_varint_encoder!(RawU16Slice, write_raw_u16_slice, u16);
+impl_varint_encoder!(RawU32Slice, write_raw_u32_slice, u32);
+impl_varint_encoder!(RawU64Slice, write_raw_u64_slice, u64);
+impl_varint_encoder!(RawU128Slice, write_raw_u128_slice, u128);
+impl_varint_encoder!(RawUsizeSlice, write_raw_usize_slice, usize);
+
+impl_varint_encoder!(RawU8Skewed, write_raw_u8_skewed, u8);
+impl_varint_encoder!(RawU16Skewed, write_raw_u16_skewed, u16);
+impl_varint_encoder!(RawU32Skewed, write_raw_u32_skewed, u32);
+impl_varint_encoder!(RawU64Skewed, write_raw_u64_skewed, u64);
+impl_varint_encoder!(RawU128Skewed, write_raw_u128_skewed, u128);
+impl_varint_encoder!(RawUsizeSkewed, write_raw_usize_skewed, usize);
+
+impl_varint_encoder!(RawU64Manual, write_raw_u64_manual, u64);
+
+// Same as `impl_write_raw_slice!` but gets the bytes via `to_le_bytes()`
+// instead of reinterpreting the integer through a pointer.
+macro_rules! impl_write_raw_safe {
+    ($fun:ident, $t:ident, $write:ident) => (
+        #[inline]
+        fn $fun(output: &mut Vec<u8>, start_position: usize, x: $t) -> usize {
+            $write(output, start_position, &x.to_le_bytes());
+            mem::size_of::<$t>()
+        }
+    )
+}
+
+impl_write_raw_safe!(write_raw_u8_safe, u8, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_u16_safe, u16, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_u32_safe, u32, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_u64_safe, u64, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_u128_safe, u128, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_usize_safe, usize, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_i8_safe, i8, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_i16_safe, i16, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_i32_safe, i32, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_i64_safe, i64, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_i128_safe, i128, write_slice_to_vec);
+impl_write_raw_safe!(write_raw_isize_safe, isize, write_slice_to_vec);
+
+impl_varint_encoder!(RawU8Safe, write_raw_u8_safe, u8);
+impl_varint_encoder!(RawU16Safe, write_raw_u16_safe, u16);
+impl_varint_encoder!(RawU32Safe, write_raw_u32_safe, u32);
+impl_varint_encoder!(RawU64Safe, write_raw_u64_safe, u64);
+impl_varint_encoder!(RawU128Safe, write_raw_u128_safe, u128);
+impl_varint_encoder!(RawUsizeSafe, write_raw_usize_safe, usize);
+
+#[test]
+fn write_raw_safe_matches_slice() {
+    macro_rules! check {
+        ($safe:ident, $slice:ident, $values:expr) => ({
+            for &value in $values.iter() {
+                let mut safe = Vec::new();
+                let mut slice = Vec::new();
+                assert_eq!($safe(&mut safe, 0, value), $slice(&mut slice, 0, value));
+                assert_eq!(safe, slice);
+            }
+        })
+    }
+
+    check!(write_raw_u8_safe, write_raw_u8_slice, [0, 1, u8::MAX]);
+    check!(write_raw_u16_safe, write_raw_u16_slice, [0, 0x1234, u16::MAX]);
+    check!(write_raw_u32_safe, write_raw_u32_slice, [0, 0x1234_5678, u32::MAX]);
+    check!(write_raw_u64_safe, write_raw_u64_slice, [0, 0x0123_4567_89AB_CDEF, u64::MAX]);
+    check!(write_raw_u128_safe, write_raw_u128_slice, [0, 1 << 100, u128::MAX]);
+    check!(write_raw_usize_safe, write_raw_usize_slice, [0, 12345, usize::MAX]);
+    check!(write_raw_i32_safe, write_raw_i32_slice, [0, -1, i32::MIN, i32::MAX]);
+    check!(write_raw_i64_safe, write_raw_i64_slice, [0, -1, i64::MIN, i64::MAX]);
+}
+
+#[test]
+fn raw_writers_byte_identical() {
+    const PREFILLED: usize = 12;
+
+    macro_rules! check {
+        ($solo:ident, $slice:ident, $skewed:ident, $values:expr) => ({
+            // At the start, straddling the end, at the end, and fully inside.
+            for &position in &[0, PREFILLED - 4, PREFILLED, 3] {
+                for &value in $values.iter() {
+                    let prefilled: Vec<u8> = (0 .. PREFILLED as u8).map(|i| 0xA0 | i).collect();
+
+                    let mut solo = prefilled.clone();
+                    let mut slice = prefilled.clone();
+                    let mut skewed = prefilled.clone();
+
+                    let len = $solo(&mut solo, position, value);
+                    assert_eq!($slice(&mut slice, position, value), len);
+                    assert_eq!($skewed(&mut skewed, position, value), len);
+
+                    assert_eq!(solo, slice, "position {}", position);
+                    assert_eq!(solo, skewed, "position {}", position);
+                    assert_eq!(solo.len(), cmp::max(PREFILLED, position + len));
+                }
+            }
+        })
+    }
+
+    check!(write_raw_u16_solo, write_raw_u16_slice, write_raw_u16_skewed, [0, 0x1234, u16::MAX]);
+    check!(write_raw_u32_solo, write_raw_u32_slice, write_raw_u32_skewed, [0, 0x1234_5678, u32::MAX]);
+    check!(write_raw_u64_solo, write_raw_u64_slice, write_raw_u64_skewed, [0, 0x0123_4567_89AB_CDEF, u64::MAX]);
+    check!(write_raw_u128_solo, write_raw_u128_slice, write_raw_u128_skewed, [0, 1 << 100, u128::MAX]);
+    check!(write_raw_usize_solo, write_raw_usize_slice, write_raw_usize_skewed, [0, 12345, usize::MAX]);
+    check!(write_raw_i32_solo, write_raw_i32_slice, write_raw_i32_skewed, [0, -1, i32::MIN, i32::MAX]);
+    check!(write_raw_i64_solo, write_raw_i64_slice, write_raw_i64_skewed, [0, -1, i64::MIN, i64::MAX]);
+}
+
+// The simplest possible version. Only supports appending.
+#[inline]
+fn write_raw_u64_extend(output: &mut Vec<u8>, start_position: usize, x: u64) -> usize {
+    debug_assert_eq!(start_position, output.len());
+    output.extend_from_slice(&x.to_le_bytes());
+    mem::size_of::<u64>()
+}
+
+impl_varint_encoder!(RawU64Extend, write_raw_u64_extend, u64);
 
 
 
@@ -244,12 +777,12 @@ macro_rules! impl_write_shift {
     ($fun:ident, $t:ident) => (
         #[inline]
         fn $fun(out: &mut Vec<u8>, start_position: usize, x: $t) -> usize {
-            for i in 0 .. ::std::mem::size_of::<$t>() {
+            for i in 0 .. ::core::mem::size_of::<$t>() {
 
                 write_to_vec(out, start_position + i, (x >> i * 8) as u8);
             }
 
-            ::std::mem::size_of::<$t>()
+            ::core::mem::size_of::<$t>()
         }
     )
 }
@@ -261,6 +794,77 @@ impl_write_shift!(write_shift_u64, u64);
 impl_write_shift!(write_shift_u128, u128);
 impl_write_shift!(write_shift_usize, usize);
 
+impl_varint_encoder!(ShiftU8, write_shift_u8, u8);
+impl_varint_encoder!(ShiftU16, write_shift_u16, u16);
+impl_varint_encoder!(ShiftU32, write_shift_u32, u32);
+impl_varint_encoder!(ShiftU64, write_shift_u64, u64);
+impl_varint_encoder!(ShiftU128, write_shift_u128, u128);
+impl_varint_encoder!(ShiftUsize, write_shift_usize, usize);
+
+macro_rules! impl_write_shift_be {
+    ($fun:ident, $t:ident) => (
+        #[inline]
+        fn $fun(out: &mut Vec<u8>, start_position: usize, x: $t) -> usize {
+            const SIZE: usize = ::core::mem::size_of::<$t>();
+
+            for i in 0 .. SIZE {
+                write_to_vec(out, start_position + i, (x >> ((SIZE - 1 - i) * 8)) as u8);
+            }
+
+            SIZE
+        }
+    )
+}
+
+impl_write_shift_be!(write_shift_be_u8, u8);
+impl_write_shift_be!(write_shift_be_u16, u16);
+impl_write_shift_be!(write_shift_be_u32, u32);
+impl_write_shift_be!(write_shift_be_u64, u64);
+impl_write_shift_be!(write_shift_be_u128, u128);
+impl_write_shift_be!(write_shift_be_usize, usize);
+
+#[inline]
+pub fn read_shift_be_u64(data: &[u8], start_position: usize) -> (u64, usize) {
+    let mut value = 0;
+
+    for i in 0 .. 8 {
+        value = (value << 8) | data[start_position + i] as u64;
+    }
+
+    (value, 8)
+}
+
+impl_varint_encoder!(ShiftBeU8, write_shift_be_u8, u8);
+impl_varint_encoder!(ShiftBeU16, write_shift_be_u16, u16);
+impl_varint_encoder!(ShiftBeU32, write_shift_be_u32, u32);
+impl_varint_codec!(ShiftBeU64, write_shift_be_u64, read_shift_be_u64, u64);
+impl_varint_encoder!(ShiftBeU128, write_shift_be_u128, u128);
+impl_varint_encoder!(ShiftBeUsize, write_shift_be_usize, usize);
+
+#[test]
+fn write_shift_be_matches_to_be_bytes() {
+    macro_rules! check {
+        ($fun:ident, $values:expr) => ({
+            for &value in $values.iter() {
+                let mut out = Vec::new();
+                assert_eq!($fun(&mut out, 0, value), out.len());
+                assert_eq!(out, value.to_be_bytes());
+            }
+        })
+    }
+
+    check!(write_shift_be_u8, [0, 0x12, u8::MAX]);
+    check!(write_shift_be_u16, [0, 0x1234, u16::MAX]);
+    check!(write_shift_be_u32, [0, 0x1234_5678, u32::MAX]);
+    check!(write_shift_be_u64, [0, 0x0123_4567_89AB_CDEF, u64::MAX]);
+    check!(write_shift_be_u128, [0, 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210, u128::MAX]);
+    check!(write_shift_be_usize, [0, 0x1234, usize::MAX]);
+
+    let mut out = Vec::new();
+    write_shift_be_u64(&mut out, 0, 0x0123_4567_89AB_CDEF);
+    assert_eq!(read_shift_be_u64(&out, 0), (0x0123_4567_89AB_CDEF, 8));
+}
+
 
 // Reference implementation of leb128 ------------------------------------------
 
@@ -273,7 +877,7 @@ macro_rules! impl_write_unsigned_leb128a {
 
             loop {
                 let mut byte = (value as u8) & 0b0111_1111;
-                value = value >> 7;
+                value >>= 7;
 
                 if value == 0 {
                     if position == out.len() {
@@ -304,6 +908,12 @@ impl_write_unsigned_leb128a!(write_leb128a_u64, u64);
 impl_write_unsigned_leb128a!(write_leb128a_u128, u128);
 impl_write_unsigned_leb128a!(write_leb128a_usize, usize);
 
+impl_varint_encoder!(Leb128AU16, write_leb128a_u16, u16);
+impl_varint_encoder!(Leb128AU32, write_leb128a_u32, u32);
+impl_varint_encoder!(Leb128AU64, write_leb128a_u64, u64);
+impl_varint_encoder!(Leb128AU128, write_leb128a_u128, u128);
+impl_varint_encoder!(Leb128AUsize, write_leb128a_usize, usize);
+
 
 
 // leb128 with fixed iteration counts ------------------------------------------
@@ -321,6 +931,21 @@ macro_rules! leb128_size {
     (usize) => (USIZE_LEB128_SIZE);
 }
 
+// Longest possible encoding of each type, for sizing buffers up front.
+pub const MAX_LEB128_LEN_U16: usize = leb128_size!(u16);
+pub const MAX_LEB128_LEN_U32: usize = leb128_size!(u32);
+pub const MAX_LEB128_LEN_U64: usize = leb128_size!(u64);
+pub const MAX_LEB128_LEN_U128: usize = leb128_size!(u128);
+pub const MAX_LEB128_LEN_USIZE: usize = leb128_size!(usize);
+
+// Every 7 bits of the type take one byte, so these have to agree with the
+// bit widths, including the target dependent one of usize.
+const _: () = assert!(MAX_LEB128_LEN_U16 == (u16::BITS as usize).div_ceil(7));
+const _: () = assert!(MAX_LEB128_LEN_U32 == (u32::BITS as usize).div_ceil(7));
+const _: () = assert!(MAX_LEB128_LEN_U64 == (u64::BITS as usize).div_ceil(7));
+const _: () = assert!(MAX_LEB128_LEN_U128 == (u128::BITS as usize).div_ceil(7));
+const _: () = assert!(MAX_LEB128_LEN_USIZE == (usize::BITS as usize).div_ceil(7));
+
 macro_rules! impl_write_unsigned_leb128b {
     ($fn_name:ident, $int_ty:ident) => (
         #[inline]
@@ -352,6 +977,22 @@ impl_write_unsigned_leb128b!(write_leb128b_u64_solo, u64);
 impl_write_unsigned_leb128b!(write_leb128b_u128_solo, u128);
 impl_write_unsigned_leb128b!(write_leb128b_usize_solo, usize);
 
+impl_varint_encoder!(Leb128BU16, write_leb128b_u16_solo, u16);
+impl_varint_encoder!(Leb128BU32, write_leb128b_u32_solo, u32);
+impl_varint_encoder!(Leb128BU64, write_leb128b_u64_solo, u64);
+impl_varint_encoder!(Leb128BU128, write_leb128b_u128_solo, u128);
+impl_varint_encoder!(Leb128BUsize, write_leb128b_usize_solo, usize);
+
+#[test]
+fn max_leb128_len_matches_writers() {
+    let mut out = Vec::new();
+    assert_eq!(write_leb128b_u16_solo(&mut out, 0, u16::MAX), MAX_LEB128_LEN_U16);
+    assert_eq!(write_leb128b_u32_solo(&mut out, 0, u32::MAX), MAX_LEB128_LEN_U32);
+    assert_eq!(write_leb128b_u64_solo(&mut out, 0, u64::MAX), MAX_LEB128_LEN_U64);
+    assert_eq!(write_leb128b_u128_solo(&mut out, 0, u128::MAX), MAX_LEB128_LEN_U128);
+    assert_eq!(write_leb128b_usize_solo(&mut out, 0, usize::MAX), MAX_LEB128_LEN_USIZE);
+}
+
 
 
 // Current leb128 implementation from Rust compiler ----------------------------
@@ -394,6 +1035,160 @@ impl_write_unsigned_leb128c!(write_leb128c_u64, u64);
 impl_write_unsigned_leb128c!(write_leb128c_u128, u128);
 impl_write_unsigned_leb128c!(write_leb128c_usize, usize);
 
+// Encoded lengths, computed from the number of significant bits alone.
+macro_rules! impl_leb128_len {
+    ($fn_name:ident, $int_ty:ident) => (
+        #[inline]
+        pub fn $fn_name(value: $int_ty) -> usize {
+            let bits = mem::size_of::<$int_ty>() * 8 - (value | 1).leading_zeros() as usize;
+            bits.div_ceil(7)
+        }
+    )
+}
+
+impl_leb128_len!(leb128_len_u16, u16);
+impl_leb128_len!(leb128_len_u32, u32);
+impl_leb128_len!(leb128_len_u64, u64);
+impl_leb128_len!(leb128_len_u128, u128);
+impl_leb128_len!(leb128_len_usize, usize);
+
+impl_varint_encoder!(Leb128C, write_leb128c_u128, u128, leb128_len_u128);
+impl_varint_encoder!(Leb128CU16, write_leb128c_u16, u16, leb128_len_u16);
+impl_varint_encoder!(Leb128CU32, write_leb128c_u32, u32, leb128_len_u32);
+impl_varint_encoder!(Leb128CU64, write_leb128c_u64, u64, leb128_len_u64);
+impl_varint_encoder!(Leb128CUsize, write_leb128c_usize, usize, leb128_len_usize);
+
+#[inline]
+pub fn write_leb128_append_only_u64(out: &mut Vec<u8>, start_position: usize, value: u64) -> usize {
+    write_unsigned_leb128_to(value as u128, |i, v| write_to_vec_unchecked_append(out, start_position + i, v))
+}
+
+impl_varint_encoder!(Leb128AppendOnlyU64, write_leb128_append_only_u64, u64);
+
+// Appends `value` and hands back just the bytes it took, e.g. for feeding
+// them to a hasher. The borrow keeps `out` from growing (and reallocating)
+// while the slice is alive.
+#[inline]
+pub fn write_leb128c_u64_slice(out: &mut Vec<u8>, value: u64) -> &[u8] {
+    let start = out.len();
+    let len = write_leb128c_u64(out, start, value);
+    &out[start .. start + len]
+}
+
+#[test]
+fn write_leb128c_u64_slice_returns_new_bytes() {
+    let mut out = Vec::new();
+
+    assert_eq!(write_leb128c_u64_slice(&mut out, 300), &[0xAC, 0x02]);
+
+    let mut expected = Vec::new();
+    write_leb128c_u64(&mut expected, 0, u64::MAX);
+    assert_eq!(write_leb128c_u64_slice(&mut out, u64::MAX), &expected[..]);
+
+    // Each call only sees its own bytes, the earlier ones stay in place.
+    assert_eq!(write_leb128c_u64_slice(&mut out, 0), &[0x00]);
+    assert_eq!(out.len(), 2 + expected.len() + 1);
+    assert_eq!(&out[.. 2], &[0xAC, 0x02]);
+}
+
+#[test]
+fn leb128_append_only_matches_leb128c() {
+    let mut expected = Vec::new();
+    let mut actual = Vec::new();
+
+    for &value in &[0, 1, 127, 128, 300, 1 << 35, u64::MAX] {
+        let pos = expected.len();
+        assert_eq!(write_leb128_append_only_u64(&mut actual, pos, value),
+                   write_leb128c_u64(&mut expected, pos, value));
+    }
+
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn leb128_is_minimal() {
+    fn expected_len(value: u64) -> usize {
+        let bits = 64 - value.leading_zeros() as usize;
+        cmp::max(1, bits.div_ceil(7))
+    }
+
+    let mut values: Vec<u64> = (0 .. 256).collect();
+
+    for groups in 1 .. 10 {
+        let boundary = 1u64 << (groups * 7);
+        values.push(boundary - 1);
+        values.push(boundary);
+        values.push(boundary + 1);
+    }
+
+    values.push(u64::MAX);
+
+    let mut state = 0x2545_F491_4F6C_DD1D;
+    for _ in 0 .. 10_000 {
+        let random = xorshift64(&mut state);
+        values.push(random >> (random % 64));
+    }
+
+    for &value in values.iter() {
+        let mut out = Vec::new();
+        let written = write_leb128c_u64(&mut out, 0, value);
+        assert_eq!(written, expected_len(value), "value: {}", value);
+        assert_eq!(out.len(), written);
+    }
+}
+
+// The `size: X%` the write benches print, turned into limits. Each one sits
+// one or two points above the size at the time it was added, so an encoder
+// that gets noticeably bigger fails here. The u64 values are mostly hashes
+// and end up larger than raw.
+#[cfg(feature = "std")]
+#[test]
+fn leb128_ratio_within_bounds() {
+    // The usize values are compared against the size of a usize, so the same
+    // encoded bytes make twice the ratio on a 32-bit target.
+    #[cfg(target_pointer_width = "32")]
+    let usize_limits = [30, 28, 35];
+    #[cfg(target_pointer_width = "64")]
+    let usize_limits = [15, 14, 18];
+
+    let limits: &[(&str, usize, usize, usize)] = &[
+        // (dataset, u32 %, u64 %, usize %)
+        (METADATA, 55, 117, usize_limits[0]),
+        (DEP_GRAPH, 70, 118, usize_limits[1]),
+        (QUERY_CACHE, 42, 71, usize_limits[2]),
+    ];
+
+    for &(name, u32_limit, u64_limit, usize_limit) in limits {
+        let mut raw = [0usize; 3];
+        let mut encoded = [0usize; 3];
+
+        for entry in load_test_data(name).iter() {
+            let mut out = Vec::new();
+            match *entry {
+                Value::U32(v) => {
+                    raw[0] += mem::size_of::<u32>();
+                    encoded[0] += write_leb128c_u32(&mut out, 0, v);
+                }
+                Value::U64(v) => {
+                    raw[1] += mem::size_of::<u64>();
+                    encoded[1] += write_leb128c_u64(&mut out, 0, v);
+                }
+                Value::Usize(v) => {
+                    raw[2] += mem::size_of::<usize>();
+                    encoded[2] += write_leb128c_usize(&mut out, 0, v);
+                }
+                _ => {}
+            }
+        }
+
+        for (i, &limit) in [u32_limit, u64_limit, usize_limit].iter().enumerate() {
+            assert!(raw[i] > 0);
+            let percent = (100 * encoded[i]) / raw[i];
+            assert!(percent <= limit, "{} {}: {}% > {}%", name, ["u32", "u64", "usize"][i], percent, limit);
+        }
+    }
+}
+
 
 // Unsafe leb128 implementation without bounds checks --------------------------
 
@@ -421,7 +1216,7 @@ macro_rules! impl_write_unsigned_leb128d {
                 }
 
                 unsafe {
-                    *out.get_unchecked_mut(position) = byte;
+                    *out.as_mut_ptr().offset(position as isize) = byte;
                 }
 
                 position += 1;
@@ -432,21 +1227,9 @@ macro_rules! impl_write_unsigned_leb128d {
             }
 
             let bytes_written = position - start_position;
-            let initial_len = out.len();
-
-            if start_position == initial_len {
-                unsafe {
-                    out.set_len(initial_len + bytes_written);
-                }
-            } else {
-                let bytes_overwritten = initial_len - start_position;
-                let additional_bytes = bytes_written.saturating_sub(bytes_overwritten);
 
-                if additional_bytes > 0 {
-                    unsafe {
-                        out.set_len(initial_len + additional_bytes);
-                    }
-                }
+            unsafe {
+                commit_written(out, start_position, bytes_written);
             }
 
             bytes_written
@@ -460,29 +1243,141 @@ impl_write_unsigned_leb128d!(write_leb128d_u64, u64);
 impl_write_unsigned_leb128d!(write_leb128d_u128, u128);
 impl_write_unsigned_leb128d!(write_leb128d_usize, usize);
 
+impl_varint_encoder!(Leb128DU16, write_leb128d_u16, u16);
+impl_varint_encoder!(Leb128DU32, write_leb128d_u32, u32);
+impl_varint_encoder!(Leb128DU64, write_leb128d_u64, u64);
+impl_varint_encoder!(Leb128DU128, write_leb128d_u128, u128);
+impl_varint_encoder!(Leb128DUsize, write_leb128d_usize, usize);
 
+#[test]
+fn commit_written_lengths() {
+    let mut out = Vec::with_capacity(16);
+    out.extend_from_slice(&[1, 2, 3, 4]);
 
-// leb128 with fixed iteration counts ------------------------------------------
+    // Entirely within the initialized part: length stays.
+    unsafe { commit_written(&mut out, 1, 2) };
+    assert_eq!(out.len(), 4);
 
-macro_rules! impl_write_unsigned_leb128e {
-    ($fn_name:ident, $int_ty:ident) => (
-        #[inline]
-        pub fn $fn_name(out: &mut Vec<u8>, start_position: usize, mut value: $int_ty) -> usize {
+    // Starting inside, ending past the end.
+    unsafe {
+        *out.as_mut_ptr().offset(4) = 5;
+        commit_written(&mut out, 2, 3);
+    }
+    assert_eq!(out, [1, 2, 3, 4, 5]);
 
-            #[inline(never)]
-            #[cold]
-            fn reserve(out: &mut Vec<u8>) {
-                out.reserve(1000);
-            }
+    // Pure append.
+    unsafe {
+        *out.as_mut_ptr().offset(5) = 6;
+        commit_written(&mut out, 5, 1);
+    }
+    assert_eq!(out, [1, 2, 3, 4, 5, 6]);
+}
 
-            if start_position + leb128_size!($int_ty) * 2 >= out.capacity() {
-                reserve(out);
-            }
+#[test]
+fn leb128d_into_empty_vec() {
+    let mut out = Vec::new();
 
-            let mut position = start_position;
-            for i in 0 .. leb128_size!($int_ty) {
-                let mut byte = (value & 0x7F) as u8;
-                value >>= 7;
+    assert_eq!(write_leb128d_u64(&mut out, 0, 624485), 3);
+    assert_eq!(out, [0xE5, 0x8E, 0x26]);
+    assert_eq!(out.len(), 3);
+}
+
+#[test]
+fn leb128d_at_exact_end() {
+    let mut out = vec![0xAA; 4];
+
+    assert_eq!(write_leb128d_u64(&mut out, 4, 624485), 3);
+    assert_eq!(out, [0xAA, 0xAA, 0xAA, 0xAA, 0xE5, 0x8E, 0x26]);
+    assert_eq!(out.len(), 7);
+}
+
+#[test]
+fn leb128d_into_middle() {
+    // Fully inside the existing data.
+    let mut out = vec![0xAA; 8];
+
+    assert_eq!(write_leb128d_u64(&mut out, 2, 624485), 3);
+    assert_eq!(out, [0xAA, 0xAA, 0xE5, 0x8E, 0x26, 0xAA, 0xAA, 0xAA]);
+    assert_eq!(out.len(), 8);
+
+    // Overlapping the end.
+    let mut out = vec![0xAA; 4];
+
+    assert_eq!(write_leb128d_u64(&mut out, 3, 624485), 3);
+    assert_eq!(out, [0xAA, 0xAA, 0xAA, 0xE5, 0x8E, 0x26]);
+    assert_eq!(out.len(), 6);
+
+    let mut expected = vec![0xAA; 4];
+    write_leb128c_u64(&mut expected, 3, 624485);
+    assert_eq!(out, expected);
+}
+
+// The tests above all start from `vec!`, whose capacity equals its length, so
+// they never find spare capacity to write into. These write straight into it,
+// which is the path where the bytes land in uninitialized memory before
+// `commit_written` extends the length over them.
+#[test]
+fn leb128d_into_spare_capacity() {
+    let mut out = Vec::with_capacity(100);
+    let capacity = out.capacity();
+    let buffer = out.as_ptr();
+
+    assert_eq!(write_leb128d_u64(&mut out, 0, 624485), 3);
+    assert_eq!(out, [0xE5, 0x8E, 0x26]);
+    assert_eq!(read_leb128_ref_u64(&out, 0), (624485, 3));
+
+    assert_eq!(write_leb128d_u64(&mut out, 3, u64::MAX), 10);
+    assert_eq!(out.len(), 13);
+    assert_eq!(read_leb128_ref_u64(&out, 3), (u64::MAX, 10));
+
+    // No reallocation happened, so nothing went through `reserve`.
+    assert_eq!(out.as_ptr(), buffer);
+    assert_eq!(out.capacity(), capacity);
+}
+
+#[test]
+fn leb128d_inside_len_with_spare_capacity() {
+    let mut out = Vec::with_capacity(100);
+    out.extend_from_slice(&[0xAA; 4]);
+    let capacity = out.capacity();
+    let buffer = out.as_ptr();
+
+    // start_position < len < capacity, ending past len.
+    assert_eq!(write_leb128d_u64(&mut out, 2, 624485), 3);
+    assert_eq!(out, [0xAA, 0xAA, 0xE5, 0x8E, 0x26]);
+    assert_eq!(read_leb128_ref_u64(&out, 2), (624485, 3));
+
+    // start_position < len < capacity, ending before len.
+    assert_eq!(write_leb128d_u64(&mut out, 0, 1), 1);
+    assert_eq!(out, [0x01, 0xAA, 0xE5, 0x8E, 0x26]);
+
+    assert_eq!(out.as_ptr(), buffer);
+    assert_eq!(out.capacity(), capacity);
+}
+
+
+
+// leb128 with fixed iteration counts ------------------------------------------
+
+macro_rules! impl_write_unsigned_leb128e {
+    ($fn_name:ident, $int_ty:ident) => (
+        #[inline]
+        pub fn $fn_name(out: &mut Vec<u8>, start_position: usize, mut value: $int_ty) -> usize {
+
+            #[inline(never)]
+            #[cold]
+            fn reserve(out: &mut Vec<u8>) {
+                out.reserve(1000);
+            }
+
+            if start_position + leb128_size!($int_ty) * 2 >= out.capacity() {
+                reserve(out);
+            }
+
+            let mut position = start_position;
+            for i in 0 .. leb128_size!($int_ty) {
+                let mut byte = (value & 0x7F) as u8;
+                value >>= 7;
                 if value != 0 {
                     byte |= 0x80;
                 }
@@ -506,6 +1401,12 @@ impl_write_unsigned_leb128e!(write_leb128e_u64, u64);
 impl_write_unsigned_leb128e!(write_leb128e_u128, u128);
 impl_write_unsigned_leb128e!(write_leb128e_usize, usize);
 
+impl_varint_encoder!(Leb128EU16, write_leb128e_u16, u16);
+impl_varint_encoder!(Leb128EU32, write_leb128e_u32, u32);
+impl_varint_encoder!(Leb128EU64, write_leb128e_u64, u64);
+impl_varint_encoder!(Leb128EU128, write_leb128e_u128, u128);
+impl_varint_encoder!(Leb128EUsize, write_leb128e_usize, usize);
+
 
 macro_rules! impl_write_unsigned_leb128f {
     ($fn_name:ident, $int_ty:ident) => (
@@ -540,7 +1441,7 @@ macro_rules! impl_write_unsigned_leb128f {
                     let additional_bytes = bytes_written.saturating_sub(bytes_overwritten);
 
                     unsafe {
-                        #[repr(packed)] struct Ua<T>(T);
+                        #[repr(C, packed)] struct Ua<T>(T);
                         out.set_len(initial_len + additional_bytes);
                         let ptr = out.as_mut_ptr().offset(start_position as isize);
                         *(ptr as *mut Ua<next_size!($int_ty)>) = Ua(result);
@@ -561,6 +1462,11 @@ impl_write_unsigned_leb128f!(write_leb128f_u64, u64);
 // impl_write_unsigned_leb128f!(write_leb128f_u128, u128);
 impl_write_unsigned_leb128f!(write_leb128f_usize, usize);
 
+impl_varint_encoder!(Leb128FU16, write_leb128f_u16, u16);
+impl_varint_encoder!(Leb128FU32, write_leb128f_u32, u32);
+impl_varint_encoder!(Leb128FU64, write_leb128f_u64, u64);
+impl_varint_encoder!(Leb128FUsize, write_leb128f_usize, usize);
+
 #[cfg(target_pointer_width = "32")]
 const USIZE_PREFIX_SIZE: usize = 5;
 #[cfg(target_pointer_width = "64")]
@@ -575,47 +1481,142 @@ macro_rules! prefix_size {
 }
 
 
+// The number of bytes is stored in unary in the low bits of the first byte,
+// the value in the remaining bits. Values that need more than 56 bits get a zero
+// first byte followed by the full little-endian integer.
 macro_rules! impl_write_unsigned_prefix {
     ($fn_name:ident, $int_ty:ident, $write:ident) => (
         #[inline]
-        pub fn $fn_name(_out: &mut Vec<u8>, _start_position: usize, _value: $int_ty) -> usize {
-            return 0;
+        pub fn $fn_name(out: &mut Vec<u8>, start_position: usize, value: $int_ty) -> usize {
+            let bits = mem::size_of::<$int_ty>() * 8 - (value | 1).leading_zeros() as usize;
 
+            if bits <= 56 {
+                let total_bytes = bits.div_ceil(7);
+                let value = (((value as u64) << total_bytes) | (1 << (total_bytes - 1))).to_le_bytes();
 
-            // let bits = prefix_size!($int_ty) * 8 - (value | 1).leading_zeros() as usize;
-            // let prefix_bits = (bits + 7) / 8;
-            // let total_bits = bits + prefix_bits;
+                $write(out, start_position, &value[.. total_bytes]);
+                total_bytes
+            } else {
+                write_to_vec(out, start_position, 0);
 
-            // if total_bits <= 64 {
-            //     let mut value = ((value as u64) << prefix_bits) | (1 << (prefix_bits - 1));
+                let value = value.to_le_bytes();
+                $write(out, start_position + 1, &value);
+                1 + value.len()
+            }
+        }
+    )
+}
 
-            //     let total_bytes = (bits + prefix_bits + 7) / 8;
+impl_write_unsigned_prefix!(impl_write_usize_prefix, usize, write_slice_to_vec_skewed);
+impl_write_unsigned_prefix!(impl_write_u32_prefix, u32, write_slice_to_vec_skewed);
+impl_write_unsigned_prefix!(impl_write_u128_prefix, u128, write_slice_to_vec_skewed);
 
-            //     let value = value.to_le();
-            //     let value = unsafe {
-            //         ::std::slice::from_raw_parts(&value as *const _ as *const u8, total_bytes)
-            //     };
+macro_rules! impl_prefix_len {
+    ($fn_name:ident, $int_ty:ident) => (
+        #[inline]
+        pub fn $fn_name(value: $int_ty) -> usize {
+            let bits = mem::size_of::<$int_ty>() * 8 - (value | 1).leading_zeros() as usize;
 
-            //     write_slice_to_vec_skewed(out, start_position, value);
-            //     total_bytes
-            // } else {
-            //     write_to_vec(out, start_position, 0);
-            // }
+            if bits <= 56 {
+                bits.div_ceil(7)
+            } else {
+                1 + mem::size_of::<$int_ty>()
+            }
         }
     )
 }
 
-impl_write_unsigned_prefix!(impl_write_usize_prefix, usize, write_slice_to_vec_skewed);
-impl_write_unsigned_prefix!(impl_write_u32_prefix, u32, write_slice_to_vec_skewed);
+impl_prefix_len!(prefix_len_usize, usize);
+impl_prefix_len!(prefix_len_u32, u32);
+impl_prefix_len!(prefix_len_u128, u128);
+
+macro_rules! impl_read_unsigned_prefix {
+    ($fn_name:ident, $int_ty:ident) => (
+        #[inline]
+        pub fn $fn_name(data: &[u8], start_position: usize) -> ($int_ty, usize) {
+            let first = data[start_position];
+
+            if first == 0 {
+                let size = mem::size_of::<$int_ty>();
+                let mut result: $int_ty = 0;
+
+                for i in 0 .. size {
+                    result |= (data[start_position + 1 + i] as $int_ty) << (i * 8);
+                }
+
+                (result, size + 1)
+            } else {
+                let total_bytes = first.trailing_zeros() as usize + 1;
+                let mut bytes = [0u8; 8];
+                bytes[.. total_bytes].copy_from_slice(&data[start_position .. start_position + total_bytes]);
+
+                ((u64::from_le_bytes(bytes) >> total_bytes) as $int_ty, total_bytes)
+            }
+        }
+    )
+}
+
+impl_read_unsigned_prefix!(read_prefix_usize, usize);
+impl_read_unsigned_prefix!(read_prefix_u32, u32);
+impl_read_unsigned_prefix!(read_prefix_u128, u128);
+
+impl_varint_codec!(PrefixUsize, impl_write_usize_prefix, read_prefix_usize, usize, prefix_len_usize);
+impl_varint_codec!(PrefixU32, impl_write_u32_prefix, read_prefix_u32, u32, prefix_len_u32);
+impl_varint_codec!(PrefixU128, impl_write_u128_prefix, read_prefix_u128, u128, prefix_len_u128);
+
+#[test]
+fn prefix_round_trip() {
+    let mut values = vec![0, u128::MAX, u64::MAX as u128, u64::MAX as u128 + 1];
+    for shift in 0 .. 128 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        let written = impl_write_u128_prefix(&mut encoded, position, val);
+        assert!(written <= prefix_size!(u128));
+        position += written;
+    }
+
+    let mut position = 0;
+    for &val in values.iter() {
+        let (decoded, read) = read_prefix_u128(&encoded, position);
+        assert_eq!(decoded, val);
+        position += read;
+    }
+
+    assert_eq!(position, encoded.len());
+
+    for &val in &[0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, u32::MAX] {
+        let mut out = Vec::new();
+        let written = impl_write_u32_prefix(&mut out, 0, val);
+        assert!(written <= prefix_size!(u32));
+        assert_eq!(read_prefix_u32(&out, 0), (val, written));
+    }
+
+    let mut out = Vec::new();
+    assert_eq!(impl_write_u32_prefix(&mut out, 0, 0x80), 2);
+    assert_eq!(out, [0x02, 0x02]);
+}
+
 
 
+// The first byte has to hold a length tag for every possible byte count of the
+// slow path, i.e. 2 to 16 for u128. That leaves less room for two-byte values.
+macro_rules! lesqlite_cut2 {
+    (u32) => (249);
+    (usize) => (249);
+    (u128) => (241);
+}
 
 macro_rules! impl_write_unsigned_lesqlite {
     ($fn_name:ident, $int_ty:ident, $write:ident) => (
         // #[inline]
         pub fn $fn_name(out: &mut Vec<u8>, start_position: usize, mut value: $int_ty) -> usize {
             const CUT1: $int_ty = 185;
-            const CUT2: $int_ty = 249;
+            const CUT2: $int_ty = lesqlite_cut2!($int_ty);
 
             if value < CUT1 {
                 write_to_vec(out, start_position, value as u8);
@@ -629,14 +1630,14 @@ macro_rules! impl_write_unsigned_lesqlite {
             } else {
 
 
-                let bits = ::std::mem::size_of::<$int_ty>() * 8 - value.leading_zeros() as usize;
-                let bytes = (bits + 7) / 8;
+                let bits = ::core::mem::size_of::<$int_ty>() * 8 - value.leading_zeros() as usize;
+                let bytes = bits.div_ceil(8);
 
                 write_to_vec(out, start_position, CUT2 as u8 + (bytes - 2) as u8);
 
                 let value = value.to_le();
                 let value = unsafe {
-                    ::std::slice::from_raw_parts(&value as *const _ as *const u8, bytes)
+                    ::core::slice::from_raw_parts(&value as *const _ as *const u8, bytes)
                 };
                 write_slice_to_vec_skewed(out, start_position + 1, value);
                 bytes + 1
@@ -646,7 +1647,155 @@ macro_rules! impl_write_unsigned_lesqlite {
 }
 
 impl_write_unsigned_lesqlite!(impl_write_usize_lesqlite, usize, write_slice_to_vec_skewed);
-impl_write_unsigned_lesqlite!(impl_write_u32_lesqlite, u32, write_slice_to_vec_skewed);
+impl_write_unsigned_lesqlite!(impl_write_u128_lesqlite, u128, write_slice_to_vec_skewed);
+
+pub fn impl_write_u32_lesqlite(out: &mut Vec<u8>, start_position: usize, value: u32) -> usize {
+    write_lesqlite_tuned_u32(out, start_position, value, 185, lesqlite_cut2!(u32))
+}
+
+macro_rules! impl_lesqlite_len {
+    ($fn_name:ident, $int_ty:ident) => (
+        #[inline]
+        pub fn $fn_name(value: $int_ty) -> usize {
+            const CUT1: $int_ty = 185;
+            const CUT2: $int_ty = lesqlite_cut2!($int_ty);
+
+            if value < CUT1 {
+                1
+            } else if value <= (CUT1 + 255 + 256 * (CUT2 - 1 - CUT1)) {
+                2
+            } else {
+                let bits = mem::size_of::<$int_ty>() * 8 - value.leading_zeros() as usize;
+                bits.div_ceil(8) + 1
+            }
+        }
+    )
+}
+
+impl_lesqlite_len!(lesqlite_len_usize, usize);
+impl_lesqlite_len!(lesqlite_len_u32, u32);
+impl_lesqlite_len!(lesqlite_len_u128, u128);
+
+#[test]
+fn encoded_len_matches_writers() {
+    let mut values = vec![0u128, 1, 127, 128, 184, 185, 2287, 2288, 16383, 16384, 67823, 67824, u128::MAX];
+    for shift in 0 .. 128 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    macro_rules! check {
+        ($len:ident, $write:ident, $int_ty:ident, $value:expr) => ({
+            let value = $value as $int_ty;
+            let mut out = Vec::new();
+            assert_eq!($len(value), $write(&mut out, 0, value), "{}({:#x})", stringify!($len), value);
+        })
+    }
+
+    for &value in values.iter() {
+        check!(leb128_len_u16, write_leb128c_u16, u16, value);
+        check!(leb128_len_u32, write_leb128c_u32, u32, value);
+        check!(leb128_len_u64, write_leb128c_u64, u64, value);
+        check!(leb128_len_u128, write_leb128c_u128, u128, value);
+        check!(leb128_len_usize, write_leb128c_usize, usize, value);
+
+        check!(prefix_len_u32, impl_write_u32_prefix, u32, value);
+        check!(prefix_len_usize, impl_write_usize_prefix, usize, value);
+        check!(prefix_len_u128, impl_write_u128_prefix, u128, value);
+
+        check!(lesqlite_len_u32, impl_write_u32_lesqlite, u32, value);
+        check!(lesqlite_len_usize, impl_write_usize_lesqlite, usize, value);
+        check!(lesqlite_len_u128, impl_write_u128_lesqlite, u128, value);
+    }
+}
+
+macro_rules! impl_read_unsigned_lesqlite {
+    ($fn_name:ident, $int_ty:ident) => (
+        #[inline]
+        pub fn $fn_name(data: &[u8], start_position: usize) -> ($int_ty, usize) {
+            const CUT1: $int_ty = 185;
+            const CUT2: $int_ty = lesqlite_cut2!($int_ty);
+
+            let first = data[start_position] as $int_ty;
+
+            if first < CUT1 {
+                (first, 1)
+            } else if first < CUT2 {
+                let second = data[start_position + 1] as $int_ty;
+                (CUT1 + ((first - CUT1) << 8) + second, 2)
+            } else {
+                let bytes = (first - CUT2) as usize + 2;
+                let mut result: $int_ty = 0;
+
+                for i in 0 .. bytes {
+                    result |= (data[start_position + 1 + i] as $int_ty) << (i * 8);
+                }
+
+                (result, bytes + 1)
+            }
+        }
+    )
+}
+
+impl_read_unsigned_lesqlite!(read_lesqlite_usize, usize);
+impl_read_unsigned_lesqlite!(read_lesqlite_u128, u128);
+
+#[inline]
+pub fn read_lesqlite_u32(data: &[u8], start_position: usize) -> (u32, usize) {
+    read_lesqlite_tuned_u32(data, start_position, 185, lesqlite_cut2!(u32))
+}
+
+impl_varint_codec!(LesqliteUsize, impl_write_usize_lesqlite, read_lesqlite_usize, usize, lesqlite_len_usize);
+impl_varint_codec!(LesqliteU32, impl_write_u32_lesqlite, read_lesqlite_u32, u32, lesqlite_len_u32);
+impl_varint_codec!(LesqliteU128, impl_write_u128_lesqlite, read_lesqlite_u128, u128, lesqlite_len_u128);
+
+#[test]
+fn lesqlite_u32_round_trip() {
+    let mut values = vec![0, 184, 185, 16568, 16569, u32::MAX];
+    for shift in 0 .. 32 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        position += impl_write_u32_lesqlite(&mut encoded, position, val);
+    }
+
+    let mut position = 0;
+    for &val in values.iter() {
+        let (decoded, read) = read_lesqlite_u32(&encoded, position);
+        assert_eq!(decoded, val);
+        position += read;
+    }
+
+    assert_eq!(position, encoded.len());
+}
+
+#[test]
+fn lesqlite_u128_round_trip() {
+    let mut values = vec![0, u128::MAX, u64::MAX as u128, u64::MAX as u128 + 1];
+    for shift in 0 .. 128 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        position += impl_write_u128_lesqlite(&mut encoded, position, val);
+    }
+
+    let mut position = 0;
+    for &val in values.iter() {
+        let (decoded, read) = read_lesqlite_u128(&encoded, position);
+        assert_eq!(decoded, val);
+        position += read;
+    }
+
+    assert_eq!(position, encoded.len());
+}
 
 
 #[cfg(target_pointer_width = "32")]
@@ -679,7 +1828,7 @@ const USIZE_TAG: u8 = 1;
 
 //         let value = value.to_le();
 //         let value = unsafe {
-//              ::std::slice::from_raw_parts(&value as *const _ as *const u8, mem::size_of::<usize>())
+//              ::core::slice::from_raw_parts(&value as *const _ as *const u8, mem::size_of::<usize>())
 //         };
 
 //         write_slice_to_vec_skewed(out, start_position + 1, value);
@@ -702,9 +1851,9 @@ pub fn write_special_usize(out: &mut Vec<u8>, start_position: usize, value: usiz
     // assert!(initial_len + 4 <= out.capacity());
 
     unsafe {
-        let ptr = out.as_mut_ptr().offset(start_position as isize);
+        let ptr = out.as_mut_ptr().add(start_position);
 
-        #[repr(packed)] struct Unaligned<T>(T);
+        #[repr(C, packed)] struct Unaligned<T>(T);
 
         let bytes_written = if value < (1 << 15) {
             *(ptr as *mut Unaligned<u16>) = Unaligned((value as u16).to_le());
@@ -714,20 +1863,7 @@ pub fn write_special_usize(out: &mut Vec<u8>, start_position: usize, value: usiz
             4
         };
 
-        if start_position == initial_len {
-            unsafe {
-                out.set_len(initial_len + bytes_written);
-            }
-        } else {
-            let bytes_overwritten = initial_len - start_position;
-            let additional_bytes = bytes_written.saturating_sub(bytes_overwritten);
-
-            if additional_bytes > 0 {
-                unsafe {
-                    out.set_len(initial_len + additional_bytes);
-                }
-            }
-        }
+        commit_written(out, start_position, bytes_written);
 
         bytes_written
     }
@@ -747,249 +1883,617 @@ pub fn write_special_u32(out: &mut Vec<u8>, start_position: usize, value: u32) -
     // assert!(initial_len + 4 <= out.capacity());
 
     unsafe {
-        let ptr = out.as_mut_ptr().offset(start_position as isize);
+        let ptr = out.as_mut_ptr().add(start_position);
 
-        #[repr(packed)] struct Unaligned<T>(T);
+        #[repr(C, packed)] struct Unaligned<T>(T);
 
         let bytes_written = if value < (1 << 15) {
             *(ptr as *mut Unaligned<u16>) = Unaligned((value as u16).to_le());
             2
         } else {
-            *(ptr as *mut Unaligned<u32>) = Unaligned((value as u32 | (1u32 << 31)).to_le());
+            *(ptr as *mut Unaligned<u32>) = Unaligned((value | (1u32 << 31)).to_le());
             4
         };
 
-        if start_position == initial_len {
-            unsafe {
-                out.set_len(initial_len + bytes_written);
-            }
-        } else {
-            let bytes_overwritten = initial_len - start_position;
-            let additional_bytes = bytes_written.saturating_sub(bytes_overwritten);
-
-            if additional_bytes > 0 {
-                unsafe {
-                    out.set_len(initial_len + additional_bytes);
-                }
-            }
-        }
+        commit_written(out, start_position, bytes_written);
 
         bytes_written
     }
 }
 
+impl_varint_encoder!(SpecialUsize, write_special_usize, usize);
+impl_varint_encoder!(SpecialU32, write_special_u32, u32);
+
 
 // Benchmarks ------------------------------------------------------------------
 
-macro_rules! impl_bench {
-    ($bench_name:ident, $variant:ident, $fun:ident, $data:ident) => (
-        #[bench]
-        fn $bench_name(b: &mut test::Bencher) {
+// libtest only reports MB/s for `b.bytes`; this prints the rate for both
+// the input integers and the encoded bytes, measured over the same iterations.
+#[cfg(feature = "std")]
+fn print_rates(input_bytes: usize, encoded_bytes: usize, iterations: u64, elapsed: Duration) {
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
 
-            let test_data = load_test_data($data);
-            let test_data: Vec<_> = test_data.iter().filter_map(|entry| {
+    if seconds > 0.0 {
+        let mb_per_s = |bytes: usize| (bytes as f64 * iterations as f64) / seconds / 1_000_000.0;
+        print!("input: {:.0} MB/s, encoded: {:.0} MB/s, ", mb_per_s(input_bytes), mb_per_s(encoded_bytes));
+    }
+}
+
+// Maps a `Value` variant to the integer type it holds.
+macro_rules! variant_ty {
+    (U8) => (u8);
+    (U16) => (u16);
+    (U32) => (u32);
+    (U64) => (u64);
+    (U128) => (u128);
+    (Usize) => (usize);
+    (I8) => (i8);
+    (I16) => (i16);
+    (I32) => (i32);
+    (I64) => (i64);
+    (I128) => (i128);
+    (Isize) => (isize);
+}
+
+// The inputs every bench starts from: the values of one variant in a dataset,
+// their size before encoding (what `b.bytes` is set to), and the values
+// encoded back to back with `$encoder`, leb128 unless given. Expands to items
+// for the module a bench macro generates, so that its `#[bench]` functions and
+// its `run_once` test share the same setup. Macros that generate several
+// benches name the module after the first one.
+macro_rules! impl_bench_data {
+    (@values $variant:ident, $data:ident) => (
+        pub fn test_data() -> Vec<variant_ty!($variant)> {
+            load_test_data($data).iter().filter_map(|entry| {
                 match *entry {
                     Value::$variant(val) => Some(val),
                     _ => None,
                 }
-            }).collect();
+            }).collect()
+        }
+
+        pub fn input_bytes(test_data: &[variant_ty!($variant)]) -> u64 {
+            (test_data.len() * mem::size_of::<variant_ty!($variant)>()) as u64
+        }
+    );
+    ($variant:ident, $data:ident) => (
+        impl_bench_data!($variant, $data, Leb128C);
+    );
+    ($variant:ident, $data:ident, $encoder:ty) => (
+        impl_bench_data!(@values $variant, $data);
+
+        pub fn encode(test_data: &[variant_ty!($variant)]) -> Vec<u8> {
+            let mut encoded = Vec::new();
 
-            if let Some(&x) = test_data.get(0) {
-                b.bytes =  (test_data.len() * ::std::mem::size_of_val(&x)) as u64;
+            for &val in test_data.iter() {
+                let pos = encoded.len();
+                <$encoder as VarintEncoder>::encode(&mut encoded, pos, val as u128);
             }
 
-            let mut size = 0;
-            let capacity = ((b.bytes * 135) / 100) as usize;
+            encoded
+        }
+    );
+}
 
-            b.iter(|| {
+// Each bench gets a module of the same name holding its encode loop, so that
+// the loop also runs once as a regular `#[test]` on stable.
+//
+// With `encoded` as the last argument, `b.bytes` is set to the encoded size
+// instead of the size of the input integers, and the throughput is printed
+// relative to both.
+macro_rules! impl_bench {
+    // Encodes leb128 u64 through `Writer` instead of an encoder. Has to come
+    // first, `$encoder:ty` would take the dataset and `$data` the `writer`.
+    ($bench_name:ident, $variant:ident, $data:ident, writer) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[inline]
+            pub fn encode_all(test_data: &[variant_ty!($variant)], capacity: usize) -> Vec<u8> {
+                let mut writer = Writer::with_capacity(capacity);
+                for &val in test_data.iter() {
+                    writer.push_u64(val as u64);
+                }
+                writer.finish()
+            }
+
+            pub fn capacity(test_data: &[variant_ty!($variant)]) -> usize {
+                test_data.iter().map(|&val| leb128_len_u64(val as u64)).sum()
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let capacity = capacity(&test_data);
+                let encoded = encode_all(&test_data, capacity);
+
+                assert_eq!(encoded.len(), capacity);
+                assert_eq!(encoded, encode(&test_data));
+            }
+        }
+
+        impl_bench!(@bench $bench_name, false);
+    );
+    ($bench_name:ident, $variant:ident, $encoder:ty, $data:ident) => (
+        impl_bench!(@module $bench_name, $variant, $encoder, $data);
+        impl_bench!(@bench $bench_name, false);
+    );
+    ($bench_name:ident, $variant:ident, $encoder:ty, $data:ident, encoded) => (
+        impl_bench!(@module $bench_name, $variant, $encoder, $data);
+        impl_bench!(@bench $bench_name, true);
+    );
+    (@module $bench_name:ident, $variant:ident, $encoder:ty, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!(@values $variant, $data);
+
+            #[inline]
+            pub fn encode_all(test_data: &[variant_ty!($variant)], capacity: usize) -> Vec<u8> {
                 let mut output = Vec::with_capacity(capacity);
+                encode_into(test_data, &mut output);
+                output
+            }
+
+            // Replaces whatever `output` held before.
+            #[inline]
+            pub fn encode_into(test_data: &[variant_ty!($variant)], output: &mut Vec<u8>) {
+                output.clear();
                 let mut position = 0;
 
                 for &val in test_data.iter() {
-                    position += $fun(&mut output, position, val);
+                    position += <$encoder as VarintEncoder>::encode(output, position, val as u128);
                 }
 
-                size = position;
+                debug_assert_eq!(position, output.len());
+            }
+
+            // Exact if the encoder can tell its lengths up front, a guess
+            // otherwise.
+            pub fn capacity(test_data: &[variant_ty!($variant)]) -> usize {
+                let mut total = 0;
+
+                for &val in test_data.iter() {
+                    match <$encoder as VarintEncoder>::encoded_len(val as u128) {
+                        Some(len) => total += len,
+                        None => return (test_data.len() * mem::size_of::<variant_ty!($variant)>() * 135) / 100,
+                    }
+                }
+
+                total
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let capacity = capacity(&test_data);
+                let encoded = encode_all(&test_data, capacity);
+
+                if <$encoder as VarintEncoder>::encoded_len(0).is_some() {
+                    assert_eq!(encoded.len(), capacity);
+                }
+
+                let mut reused = vec![0xFF; 3];
+                encode_into(&test_data, &mut reused);
+                assert_eq!(reused, encoded);
+            }
+        }
+    );
+    (@bench $bench_name:ident, $encoded:expr) => (
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let mut size = 0;
+            let capacity = $bench_name::capacity(&test_data);
+            let input_bytes = b.bytes as usize;
+
+            if $encoded {
+                b.bytes = $bench_name::encode_all(&test_data, capacity).len() as u64;
+            }
+
+            let mut elapsed = Duration::new(0, 0);
+            let mut iterations = 0;
+
+            b.iter(|| {
+                let start = if $encoded { Some(Instant::now()) } else { None };
+
+                size = $bench_name::encode_all(&test_data, capacity).len();
+
+                if let Some(start) = start {
+                    elapsed += start.elapsed();
+                    iterations += 1;
+                }
             });
 
-            if b.bytes > 0 {
-                print!("size: {}%, ", (100 * size) / (b.bytes as usize));
+            if input_bytes > 0 {
+                print!("size: {}%, ", (100 * size) / input_bytes);
+            }
+
+            if $encoded {
+                print_rates(input_bytes, size, iterations, elapsed);
             }
         }
     )
 }
 
-// impl_bench!(write_raw_u8_solo_query_cache, U8, write_raw_u8_solo, QUERY_CACHE);
-// // impl_bench!(write_raw_u8_slice_query_cache, U8, write_raw_u8_slice, QUERY_CACHE);
-// // impl_bench!(write_raw_u8_skewed_query_cache, U8, write_raw_u8_skewed, QUERY_CACHE);
-// impl_bench!(write_raw_u16_solo_query_cache, U16, write_raw_u16_solo, QUERY_CACHE);
-// // impl_bench!(write_raw_u16_slice_query_cache, U16, write_raw_u16_slice, QUERY_CACHE);
-// // impl_bench!(write_raw_u16_skewed_query_cache, U16, write_raw_u16_skewed, QUERY_CACHE);
+// Same as `impl_bench!` but encodes into one buffer that is allocated before
+// timing starts and only cleared between iterations. The difference to the
+// matching `impl_bench!` number is what allocating the output costs.
+macro_rules! impl_reuse_bench {
+    ($bench_name:ident, $variant:ident, $encoder:ty, $data:ident) => (
+        impl_bench!(@module $bench_name, $variant, $encoder, $data);
 
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
 
-// impl_bench!(write_raw_u32_solo_metadata, U32, write_raw_u32_solo, METADATA);
-// impl_bench!(write_raw_u32_solo_dep_graph, U32, write_raw_u32_solo, DEP_GRAPH);
-// impl_bench!(write_raw_u32_solo_query_cache, U32, write_raw_u32_solo, QUERY_CACHE);
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
 
-// // impl_bench!(write_raw_u32_slice_metadata, U32, write_raw_u32_slice, METADATA);
-// // impl_bench!(write_raw_u32_slice_dep_graph, U32, write_raw_u32_slice, DEP_GRAPH);
-// // impl_bench!(write_raw_u32_slice_query_cache, U32, write_raw_u32_slice, QUERY_CACHE);
+            let mut output = Vec::new();
 
-// // impl_bench!(write_raw_u32_skewed_metadata, U32, write_raw_u32_skewed, METADATA);
-// // impl_bench!(write_raw_u32_skewed_dep_graph, U32, write_raw_u32_skewed, DEP_GRAPH);
-// // impl_bench!(write_raw_u32_skewed_query_cache, U32, write_raw_u32_skewed, QUERY_CACHE);
+            b.iter(|| {
+                $bench_name::encode_into(&test_data, &mut output);
+                test::black_box(&output);
+            });
+        }
+    )
+}
 
-// impl_bench!(write_raw_u64_solo_metadata, U64, write_raw_u64_solo, METADATA);
-// impl_bench!(write_raw_u64_solo_dep_graph, U64, write_raw_u64_solo, DEP_GRAPH);
-// impl_bench!(write_raw_u64_solo_query_cache, U64, write_raw_u64_solo, QUERY_CACHE);
+// The benchmarks below are grouped into one module per family, so that e.g.
+// `cargo bench leb128d_benches` runs just that group.
 
-// // impl_bench!(write_raw_u64_slice_metadata, U64, write_raw_u64_slice, METADATA);
-// // impl_bench!(write_raw_u64_slice_dep_graph, U64, write_raw_u64_slice, DEP_GRAPH);
-// // impl_bench!(write_raw_u64_slice_query_cache, U64, write_raw_u64_slice, QUERY_CACHE);
+#[cfg(test)]
+mod write_raw_benches {
+    use super::*;
 
-// // impl_bench!(write_raw_u64_skewed_metadata, U64, write_raw_u64_skewed, METADATA);
-// // impl_bench!(write_raw_u64_skewed_dep_graph, U64, write_raw_u64_skewed, DEP_GRAPH);
-// // impl_bench!(write_raw_u64_skewed_query_cache, U64, write_raw_u64_skewed, QUERY_CACHE);
+    // impl_bench!(write_raw_u8_solo_query_cache, U8, RawU8Solo, QUERY_CACHE);
+    // impl_bench!(write_raw_u8_slice_query_cache, U8, RawU8Slice, QUERY_CACHE);
+    // impl_bench!(write_raw_u8_skewed_query_cache, U8, RawU8Skewed, QUERY_CACHE);
+    // impl_bench!(write_raw_u16_solo_query_cache, U16, RawU16Solo, QUERY_CACHE);
+    // impl_bench!(write_raw_u16_slice_query_cache, U16, RawU16Slice, QUERY_CACHE);
+    // impl_bench!(write_raw_u16_skewed_query_cache, U16, RawU16Skewed, QUERY_CACHE);
 
-// impl_bench!(write_raw_usize_solo_metadata, Usize, write_raw_usize_solo, METADATA);
-// impl_bench!(write_raw_usize_solo_dep_graph, Usize, write_raw_usize_solo, DEP_GRAPH);
-// impl_bench!(write_raw_usize_solo_query_cache, Usize, write_raw_usize_solo, QUERY_CACHE);
 
-// // impl_bench!(write_raw_usize_slice_metadata, Usize, write_raw_usize_slice, METADATA);
-// // impl_bench!(write_raw_usize_slice_dep_graph, Usize, write_raw_usize_slice, DEP_GRAPH);
-// // impl_bench!(write_raw_usize_slice_query_cache, Usize, write_raw_usize_slice, QUERY_CACHE);
+    // impl_bench!(write_raw_u32_solo_metadata, U32, RawU32Solo, METADATA);
+    // impl_bench!(write_raw_u32_solo_dep_graph, U32, RawU32Solo, DEP_GRAPH);
+    // impl_bench!(write_raw_u32_solo_query_cache, U32, RawU32Solo, QUERY_CACHE);
 
-// // impl_bench!(write_raw_usize_skewed_metadata, Usize, write_raw_usize_skewed, METADATA);
-// // impl_bench!(write_raw_usize_skewed_dep_graph, Usize, write_raw_usize_skewed, DEP_GRAPH);
-// // impl_bench!(write_raw_usize_skewed_query_cache, Usize, write_raw_usize_skewed, QUERY_CACHE);
+    impl_bench!(write_raw_u32_slice_metadata, U32, RawU32Slice, METADATA);
+    impl_bench!(write_raw_u32_slice_dep_graph, U32, RawU32Slice, DEP_GRAPH);
+    impl_bench!(write_raw_u32_slice_query_cache, U32, RawU32Slice, QUERY_CACHE);
 
+    // impl_bench!(write_raw_u32_skewed_metadata, U32, RawU32Skewed, METADATA);
+    // impl_bench!(write_raw_u32_skewed_dep_graph, U32, RawU32Skewed, DEP_GRAPH);
+    // impl_bench!(write_raw_u32_skewed_query_cache, U32, RawU32Skewed, QUERY_CACHE);
 
+    impl_bench!(write_raw_u32_safe_metadata, U32, RawU32Safe, METADATA);
+    impl_bench!(write_raw_u32_safe_dep_graph, U32, RawU32Safe, DEP_GRAPH);
+    impl_bench!(write_raw_u32_safe_query_cache, U32, RawU32Safe, QUERY_CACHE);
 
-// impl_bench!(write_shift_u8_query_cache, U8, write_shift_u8, QUERY_CACHE);
-// impl_bench!(write_shift_u16_query_cache, U16, write_shift_u16, QUERY_CACHE);
+    impl_reuse_bench!(write_raw_u32_slice_reuse_metadata, U32, RawU32Slice, METADATA);
+    impl_reuse_bench!(write_raw_u32_slice_reuse_dep_graph, U32, RawU32Slice, DEP_GRAPH);
+    impl_reuse_bench!(write_raw_u32_slice_reuse_query_cache, U32, RawU32Slice, QUERY_CACHE);
 
-// impl_bench!(write_shift_u32_metadata, U32, write_shift_u32, METADATA);
-// impl_bench!(write_shift_u32_dep_graph, U32, write_shift_u32, DEP_GRAPH);
-// impl_bench!(write_shift_u32_query_cache, U32, write_shift_u32, QUERY_CACHE);
+    impl_bench!(write_raw_u64_solo_metadata, U64, RawU64Solo, METADATA);
+    impl_bench!(write_raw_u64_solo_dep_graph, U64, RawU64Solo, DEP_GRAPH);
+    impl_bench!(write_raw_u64_solo_query_cache, U64, RawU64Solo, QUERY_CACHE);
 
-// impl_bench!(write_shift_u64_metadata, U64, write_shift_u64, METADATA);
-// impl_bench!(write_shift_u64_dep_graph, U64, write_shift_u64, DEP_GRAPH);
-// impl_bench!(write_shift_u64_query_cache, U64, write_shift_u64, QUERY_CACHE);
+    impl_bench!(write_raw_u64_slice_metadata, U64, RawU64Slice, METADATA);
+    impl_bench!(write_raw_u64_slice_dep_graph, U64, RawU64Slice, DEP_GRAPH);
+    impl_bench!(write_raw_u64_slice_query_cache, U64, RawU64Slice, QUERY_CACHE);
 
-// impl_bench!(write_shift_usize_metadata, Usize, write_shift_usize, METADATA);
-// impl_bench!(write_shift_usize_dep_graph, Usize, write_shift_usize, DEP_GRAPH);
-// impl_bench!(write_shift_usize_query_cache, Usize, write_shift_usize, QUERY_CACHE);
+    impl_bench!(write_raw_u64_skewed_metadata, U64, RawU64Skewed, METADATA);
+    impl_bench!(write_raw_u64_skewed_dep_graph, U64, RawU64Skewed, DEP_GRAPH);
+    impl_bench!(write_raw_u64_skewed_query_cache, U64, RawU64Skewed, QUERY_CACHE);
 
+    impl_bench!(write_raw_u64_manual_metadata, U64, RawU64Manual, METADATA);
+    impl_bench!(write_raw_u64_manual_dep_graph, U64, RawU64Manual, DEP_GRAPH);
+    impl_bench!(write_raw_u64_manual_query_cache, U64, RawU64Manual, QUERY_CACHE);
 
+    impl_bench!(write_raw_u64_safe_metadata, U64, RawU64Safe, METADATA);
+    impl_bench!(write_raw_u64_safe_dep_graph, U64, RawU64Safe, DEP_GRAPH);
+    impl_bench!(write_raw_u64_safe_query_cache, U64, RawU64Safe, QUERY_CACHE);
 
+    impl_bench!(write_raw_u64_extend_metadata, U64, RawU64Extend, METADATA);
+    impl_bench!(write_raw_u64_extend_dep_graph, U64, RawU64Extend, DEP_GRAPH);
+    impl_bench!(write_raw_u64_extend_query_cache, U64, RawU64Extend, QUERY_CACHE);
 
-// impl_bench!(write_leb128b_u16_solo_query_cache, U16, write_leb128b_u16_solo, QUERY_CACHE);
+    // impl_bench!(write_raw_usize_solo_metadata, Usize, RawUsizeSolo, METADATA);
+    // impl_bench!(write_raw_usize_solo_dep_graph, Usize, RawUsizeSolo, DEP_GRAPH);
+    // impl_bench!(write_raw_usize_solo_query_cache, Usize, RawUsizeSolo, QUERY_CACHE);
 
-// impl_bench!(write_leb128b_u32_solo_metadata, U32, write_leb128b_u32_solo, METADATA);
-// impl_bench!(write_leb128b_u32_solo_dep_graph, U32, write_leb128b_u32_solo, DEP_GRAPH);
-// impl_bench!(write_leb128b_u32_solo_query_cache, U32, write_leb128b_u32_solo, QUERY_CACHE);
+    // impl_bench!(write_raw_usize_slice_metadata, Usize, RawUsizeSlice, METADATA);
+    // impl_bench!(write_raw_usize_slice_dep_graph, Usize, RawUsizeSlice, DEP_GRAPH);
+    // impl_bench!(write_raw_usize_slice_query_cache, Usize, RawUsizeSlice, QUERY_CACHE);
 
-// impl_bench!(write_leb128b_u64_solo_metadata, U64, write_leb128b_u64_solo, METADATA);
-// impl_bench!(write_leb128b_u64_solo_dep_graph, U64, write_leb128b_u64_solo, DEP_GRAPH);
-// impl_bench!(write_leb128b_u64_solo_query_cache, U64, write_leb128b_u64_solo, QUERY_CACHE);
+    // impl_bench!(write_raw_usize_skewed_metadata, Usize, RawUsizeSkewed, METADATA);
+    // impl_bench!(write_raw_usize_skewed_dep_graph, Usize, RawUsizeSkewed, DEP_GRAPH);
+    // impl_bench!(write_raw_usize_skewed_query_cache, Usize, RawUsizeSkewed, QUERY_CACHE);
+}
 
-// impl_bench!(write_leb128b_usize_solo_metadata, Usize, write_leb128b_usize_solo, METADATA);
-// impl_bench!(write_leb128b_usize_solo_dep_graph, Usize, write_leb128b_usize_solo, DEP_GRAPH);
-// impl_bench!(write_leb128b_usize_solo_query_cache, Usize, write_leb128b_usize_solo, QUERY_CACHE);
 
 
+#[cfg(test)]
+mod shift_benches {
+    use super::*;
 
-// impl_bench!(write_leb128a_u16_query_cache, U16, write_leb128a_u16, QUERY_CACHE);
+    // impl_bench!(write_shift_u8_query_cache, U8, ShiftU8, QUERY_CACHE);
+    // impl_bench!(write_shift_u16_query_cache, U16, ShiftU16, QUERY_CACHE);
 
-// impl_bench!(write_leb128a_u32_metadata, U32, write_leb128a_u32, METADATA);
-// impl_bench!(write_leb128a_u32_dep_graph, U32, write_leb128a_u32, DEP_GRAPH);
-// impl_bench!(write_leb128a_u32_query_cache, U32, write_leb128a_u32, QUERY_CACHE);
+    // impl_bench!(write_shift_u32_metadata, U32, ShiftU32, METADATA);
+    // impl_bench!(write_shift_u32_dep_graph, U32, ShiftU32, DEP_GRAPH);
+    // impl_bench!(write_shift_u32_query_cache, U32, ShiftU32, QUERY_CACHE);
 
-// impl_bench!(write_leb128a_u64_metadata, U64, write_leb128a_u64, METADATA);
-// impl_bench!(write_leb128a_u64_dep_graph, U64, write_leb128a_u64, DEP_GRAPH);
-// impl_bench!(write_leb128a_u64_query_cache, U64, write_leb128a_u64, QUERY_CACHE);
+    impl_bench!(write_shift_u64_metadata, U64, ShiftU64, METADATA);
+    impl_bench!(write_shift_u64_dep_graph, U64, ShiftU64, DEP_GRAPH);
+    impl_bench!(write_shift_u64_query_cache, U64, ShiftU64, QUERY_CACHE);
 
-// impl_bench!(write_leb128a_usize_metadata, Usize, write_leb128a_usize, METADATA);
-// impl_bench!(write_leb128a_usize_dep_graph, Usize, write_leb128a_usize, DEP_GRAPH);
-// impl_bench!(write_leb128a_usize_query_cache, Usize, write_leb128a_usize, QUERY_CACHE);
+    impl_bench!(write_shift_be_u64_metadata, U64, ShiftBeU64, METADATA);
+    impl_bench!(write_shift_be_u64_dep_graph, U64, ShiftBeU64, DEP_GRAPH);
+    impl_bench!(write_shift_be_u64_query_cache, U64, ShiftBeU64, QUERY_CACHE);
 
+    // impl_bench!(write_shift_usize_metadata, Usize, ShiftUsize, METADATA);
+    // impl_bench!(write_shift_usize_dep_graph, Usize, ShiftUsize, DEP_GRAPH);
+    // impl_bench!(write_shift_usize_query_cache, Usize, ShiftUsize, QUERY_CACHE);
+}
 
-// impl_bench!(write_leb128c_u16_query_cache, U16, write_leb128c_u16, QUERY_CACHE);
 
-// impl_bench!(write_leb128c_u32_metadata, U32, write_leb128c_u32, METADATA);
-// impl_bench!(write_leb128c_u32_dep_graph, U32, write_leb128c_u32, DEP_GRAPH);
-// impl_bench!(write_leb128c_u32_query_cache, U32, write_leb128c_u32, QUERY_CACHE);
 
-// impl_bench!(write_leb128c_u64_metadata, U64, write_leb128c_u64, METADATA);
-// impl_bench!(write_leb128c_u64_dep_graph, U64, write_leb128c_u64, DEP_GRAPH);
-// impl_bench!(write_leb128c_u64_query_cache, U64, write_leb128c_u64, QUERY_CACHE);
 
-// impl_bench!(write_leb128c_usize_metadata, Usize, write_leb128c_usize, METADATA);
-// impl_bench!(write_leb128c_usize_dep_graph, Usize, write_leb128c_usize, DEP_GRAPH);
-// impl_bench!(write_leb128c_usize_query_cache, Usize, write_leb128c_usize, QUERY_CACHE);
+#[cfg(test)]
+mod leb128b_benches {
+    use super::*;
 
+    // impl_bench!(write_leb128b_u16_solo_query_cache, U16, Leb128BU16, QUERY_CACHE);
 
-// impl_bench!(write_leb128d_u16_query_cache, U16, write_leb128d_u16, QUERY_CACHE);
+    // impl_bench!(write_leb128b_u32_solo_metadata, U32, Leb128BU32, METADATA);
+    // impl_bench!(write_leb128b_u32_solo_dep_graph, U32, Leb128BU32, DEP_GRAPH);
+    // impl_bench!(write_leb128b_u32_solo_query_cache, U32, Leb128BU32, QUERY_CACHE);
 
-// impl_bench!(write_leb128d_u32_metadata, U32, write_leb128d_u32, METADATA);
-// impl_bench!(write_leb128d_u32_dep_graph, U32, write_leb128d_u32, DEP_GRAPH);
-// impl_bench!(write_leb128d_u32_query_cache, U32, write_leb128d_u32, QUERY_CACHE);
+    // impl_bench!(write_leb128b_u64_solo_metadata, U64, Leb128BU64, METADATA);
+    // impl_bench!(write_leb128b_u64_solo_dep_graph, U64, Leb128BU64, DEP_GRAPH);
+    // impl_bench!(write_leb128b_u64_solo_query_cache, U64, Leb128BU64, QUERY_CACHE);
 
-// impl_bench!(write_leb128d_u64_metadata, U64, write_leb128d_u64, METADATA);
-// impl_bench!(write_leb128d_u64_dep_graph, U64, write_leb128d_u64, DEP_GRAPH);
-// impl_bench!(write_leb128d_u64_query_cache, U64, write_leb128d_u64, QUERY_CACHE);
+    // impl_bench!(write_leb128b_usize_solo_metadata, Usize, Leb128BUsize, METADATA);
+    // impl_bench!(write_leb128b_usize_solo_dep_graph, Usize, Leb128BUsize, DEP_GRAPH);
+    // impl_bench!(write_leb128b_usize_solo_query_cache, Usize, Leb128BUsize, QUERY_CACHE);
+}
 
-// impl_bench!(write_leb128d_usize_metadata, Usize, write_leb128d_usize, METADATA);
-// impl_bench!(write_leb128d_usize_dep_graph, Usize, write_leb128d_usize, DEP_GRAPH);
-// impl_bench!(write_leb128d_usize_query_cache, Usize, write_leb128d_usize, QUERY_CACHE);
 
 
-// impl_bench!(write_leb128f_u16_query_cache, U16, write_leb128f_u16, QUERY_CACHE);
+#[cfg(test)]
+mod leb128a_benches {
+    use super::*;
 
-// impl_bench!(write_leb128f_u32_metadata, U32, write_leb128f_u32, METADATA);
-// impl_bench!(write_leb128f_u32_dep_graph, U32, write_leb128f_u32, DEP_GRAPH);
-// impl_bench!(write_leb128f_u32_query_cache, U32, write_leb128f_u32, QUERY_CACHE);
+    // impl_bench!(write_leb128a_u16_query_cache, U16, Leb128AU16, QUERY_CACHE);
 
-// impl_bench!(write_leb128f_u64_metadata, U64, write_leb128f_u64, METADATA);
-// impl_bench!(write_leb128f_u64_dep_graph, U64, write_leb128f_u64, DEP_GRAPH);
-// impl_bench!(write_leb128f_u64_query_cache, U64, write_leb128f_u64, QUERY_CACHE);
+    // impl_bench!(write_leb128a_u32_metadata, U32, Leb128AU32, METADATA);
+    // impl_bench!(write_leb128a_u32_dep_graph, U32, Leb128AU32, DEP_GRAPH);
+    // impl_bench!(write_leb128a_u32_query_cache, U32, Leb128AU32, QUERY_CACHE);
 
-// impl_bench!(write_leb128f_usize_metadata, Usize, write_leb128f_usize, METADATA);
-// impl_bench!(write_leb128f_usize_dep_graph, Usize, write_leb128f_usize, DEP_GRAPH);
-// impl_bench!(write_leb128f_usize_query_cache, Usize, write_leb128f_usize, QUERY_CACHE);
+    // impl_bench!(write_leb128a_u64_metadata, U64, Leb128AU64, METADATA);
+    // impl_bench!(write_leb128a_u64_dep_graph, U64, Leb128AU64, DEP_GRAPH);
+    // impl_bench!(write_leb128a_u64_query_cache, U64, Leb128AU64, QUERY_CACHE);
 
-// impl_bench!(write_lesqlite_usize_metadata, Usize, impl_write_usize_lesqlite, METADATA);
-// impl_bench!(write_lesqlite_usize_dep_graph, Usize, impl_write_usize_lesqlite, DEP_GRAPH);
-// impl_bench!(write_lesqlite_usize_query_cache, Usize, impl_write_usize_lesqlite, QUERY_CACHE);
+    // impl_bench!(write_leb128a_usize_metadata, Usize, Leb128AUsize, METADATA);
+    // impl_bench!(write_leb128a_usize_dep_graph, Usize, Leb128AUsize, DEP_GRAPH);
+    // impl_bench!(write_leb128a_usize_query_cache, Usize, Leb128AUsize, QUERY_CACHE);
+}
 
-// impl_bench!(write_lesqlite_u32_metadata, U32, impl_write_u32_lesqlite, METADATA);
-// impl_bench!(write_lesqlite_u32_dep_graph, U32, impl_write_u32_lesqlite, DEP_GRAPH);
-// impl_bench!(write_lesqlite_u32_query_cache, U32, impl_write_u32_lesqlite, QUERY_CACHE);
 
-// impl_bench!(write_aspecial_usize_metadata, Usize, write_special_usize, METADATA);
-// impl_bench!(write_aspecial_usize_dep_graph, Usize, write_special_usize, DEP_GRAPH);
-// impl_bench!(write_aspecial_usize_query_cache, Usize, write_special_usize, QUERY_CACHE);
+#[cfg(test)]
+mod leb128c_benches {
+    use super::*;
 
-// impl_bench!(write_aspecial_u32_metadata, U32, write_special_u32, METADATA);
-// impl_bench!(write_aspecial_u32_dep_graph, U32, write_special_u32, DEP_GRAPH);
-// impl_bench!(write_aspecial_u32_query_cache, U32, write_special_u32, QUERY_CACHE);
+    // impl_bench!(write_leb128c_u16_query_cache, U16, Leb128CU16, QUERY_CACHE);
 
+    // impl_bench!(write_leb128c_u32_metadata, U32, Leb128CU32, METADATA);
+    // impl_bench!(write_leb128c_u32_dep_graph, U32, Leb128CU32, DEP_GRAPH);
+    // impl_bench!(write_leb128c_u32_query_cache, U32, Leb128CU32, QUERY_CACHE);
 
+    impl_bench!(write_leb128c_u64_metadata, U64, Leb128CU64, METADATA);
+    impl_bench!(write_leb128c_u64_dep_graph, U64, Leb128CU64, DEP_GRAPH);
+    impl_bench!(write_leb128c_u64_query_cache, U64, Leb128CU64, QUERY_CACHE);
 
+    impl_bench!(write_writer_u64_metadata, U64, METADATA, writer);
+    impl_bench!(write_writer_u64_dep_graph, U64, DEP_GRAPH, writer);
+    impl_bench!(write_writer_u64_query_cache, U64, QUERY_CACHE, writer);
 
+    impl_reuse_bench!(write_leb128c_u64_reuse_metadata, U64, Leb128CU64, METADATA);
+    impl_reuse_bench!(write_leb128c_u64_reuse_dep_graph, U64, Leb128CU64, DEP_GRAPH);
+    impl_reuse_bench!(write_leb128c_u64_reuse_query_cache, U64, Leb128CU64, QUERY_CACHE);
 
+    impl_bench!(write_leb128_append_only_u64_metadata, U64, Leb128AppendOnlyU64, METADATA);
+    impl_bench!(write_leb128_append_only_u64_dep_graph, U64, Leb128AppendOnlyU64, DEP_GRAPH);
+    impl_bench!(write_leb128_append_only_u64_query_cache, U64, Leb128AppendOnlyU64, QUERY_CACHE);
 
+    impl_bench!(write_leb128c_u64_encoded_query_cache, U64, Leb128CU64, QUERY_CACHE, encoded);
+    impl_bench!(write_raw_u64_slice_encoded_query_cache, U64, RawU64Slice, QUERY_CACHE, encoded);
 
+    // impl_bench!(write_leb128c_usize_metadata, Usize, Leb128CUsize, METADATA);
+    // impl_bench!(write_leb128c_usize_dep_graph, Usize, Leb128CUsize, DEP_GRAPH);
+    // impl_bench!(write_leb128c_usize_query_cache, Usize, Leb128CUsize, QUERY_CACHE);
+}
 
+// A dataset can filter down to no values of a type at all, so the generated
+// bench loops have to cope with empty and tiny inputs.
+#[cfg(feature = "std")]
+#[test]
+fn bench_loops_empty_and_single() {
+    use leb128c_benches::write_leb128c_u64_metadata as write;
+    use read_benches::read_leb128_ref_u64_metadata as read;
+
+    assert_eq!(write::capacity(&[]), 0);
+    assert!(write::encode_all(&[], 0).is_empty());
+    assert!(read::encode(&[]).is_empty());
+    read::decode_all(&[], &[], true);
+
+    let single = [300u64];
+    assert_eq!(write::capacity(&single), 2);
+    let encoded = write::encode_all(&single, 0);
+    assert_eq!(encoded, [0xAC, 0x02]);
+    assert_eq!(read::encode(&single), encoded);
+    read::decode_all(&encoded, &single, true);
+}
 
 
+#[cfg(test)]
+mod leb128d_benches {
+    use super::*;
 
+    // impl_bench!(write_leb128d_u16_query_cache, U16, Leb128DU16, QUERY_CACHE);
 
+    // impl_bench!(write_leb128d_u32_metadata, U32, Leb128DU32, METADATA);
+    // impl_bench!(write_leb128d_u32_dep_graph, U32, Leb128DU32, DEP_GRAPH);
+    // impl_bench!(write_leb128d_u32_query_cache, U32, Leb128DU32, QUERY_CACHE);
 
+    impl_bench!(write_leb128d_u64_metadata, U64, Leb128DU64, METADATA);
+    impl_bench!(write_leb128d_u64_dep_graph, U64, Leb128DU64, DEP_GRAPH);
+    impl_bench!(write_leb128d_u64_query_cache, U64, Leb128DU64, QUERY_CACHE);
 
-#[inline]
-fn read_unsigned_leb128_ref(data: &[u8], start_position: usize) -> (u128, usize) {
-    let mut result = 0;
+    // impl_bench!(write_leb128d_usize_metadata, Usize, Leb128DUsize, METADATA);
+    // impl_bench!(write_leb128d_usize_dep_graph, Usize, Leb128DUsize, DEP_GRAPH);
+    // impl_bench!(write_leb128d_usize_query_cache, Usize, Leb128DUsize, QUERY_CACHE);
+}
+
+
+#[cfg(test)]
+mod leb128f_benches {
+    use super::*;
+
+    // impl_bench!(write_leb128f_u16_query_cache, U16, Leb128FU16, QUERY_CACHE);
+
+    // impl_bench!(write_leb128f_u32_metadata, U32, Leb128FU32, METADATA);
+    // impl_bench!(write_leb128f_u32_dep_graph, U32, Leb128FU32, DEP_GRAPH);
+    // impl_bench!(write_leb128f_u32_query_cache, U32, Leb128FU32, QUERY_CACHE);
+
+    // impl_bench!(write_leb128f_u64_metadata, U64, Leb128FU64, METADATA);
+    // impl_bench!(write_leb128f_u64_dep_graph, U64, Leb128FU64, DEP_GRAPH);
+    // impl_bench!(write_leb128f_u64_query_cache, U64, Leb128FU64, QUERY_CACHE);
+
+    // impl_bench!(write_leb128f_usize_metadata, Usize, Leb128FUsize, METADATA);
+    // impl_bench!(write_leb128f_usize_dep_graph, Usize, Leb128FUsize, DEP_GRAPH);
+    // impl_bench!(write_leb128f_usize_query_cache, Usize, Leb128FUsize, QUERY_CACHE);
+}
+
+#[cfg(test)]
+mod lesqlite_benches {
+    use super::*;
+
+    // impl_bench!(write_lesqlite_usize_metadata, Usize, LesqliteUsize, METADATA);
+    // impl_bench!(write_lesqlite_usize_dep_graph, Usize, LesqliteUsize, DEP_GRAPH);
+    // impl_bench!(write_lesqlite_usize_query_cache, Usize, LesqliteUsize, QUERY_CACHE);
+
+    // impl_bench!(write_lesqlite_u32_metadata, U32, LesqliteU32, METADATA);
+    // impl_bench!(write_lesqlite_u32_dep_graph, U32, LesqliteU32, DEP_GRAPH);
+    // impl_bench!(write_lesqlite_u32_query_cache, U32, LesqliteU32, QUERY_CACHE);
+
+    impl_bench!(write_lesqlite_u128_metadata, U128, LesqliteU128, METADATA);
+    impl_bench!(write_lesqlite_u128_query_cache, U128, LesqliteU128, QUERY_CACHE);
+}
+
+#[cfg(test)]
+mod special_benches {
+    use super::*;
+
+    // impl_bench!(write_aspecial_usize_metadata, Usize, SpecialUsize, METADATA);
+    // impl_bench!(write_aspecial_usize_dep_graph, Usize, SpecialUsize, DEP_GRAPH);
+    // impl_bench!(write_aspecial_usize_query_cache, Usize, SpecialUsize, QUERY_CACHE);
+
+    // impl_bench!(write_aspecial_u32_metadata, U32, SpecialU32, METADATA);
+    // impl_bench!(write_aspecial_u32_dep_graph, U32, SpecialU32, DEP_GRAPH);
+    // impl_bench!(write_aspecial_u32_query_cache, U32, SpecialU32, QUERY_CACHE);
+}
+
+
+// Per-call latency instead of mean throughput. Every single encode call is
+// timed individually, so the numbers include the overhead of `Instant::now()`,
+// but the tail is what we are interested in here.
+
+const LATENCY_ROUNDS: usize = 10;
+
+macro_rules! impl_latency_bench {
+    ($bench_name:ident, $variant:ident, $encoder:ty, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!(@values $variant, $data);
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+
+            let capacity = test_data.len() * 2 * ::core::mem::size_of::<u128>();
+            let mut latencies = Vec::with_capacity(test_data.len() * LATENCY_ROUNDS);
+
+            for _ in 0 .. LATENCY_ROUNDS {
+                let mut output = Vec::with_capacity(capacity);
+                let mut position = 0;
+
+                for &val in test_data.iter() {
+                    let start = Instant::now();
+                    position += <$encoder as VarintEncoder>::encode(&mut output, position, val as u128);
+                    latencies.push(start.elapsed());
+                }
+
+                test::black_box(&output);
+            }
+
+            latencies.sort();
+
+            if !latencies.is_empty() {
+                let percentile = |p: usize| latencies[((latencies.len() - 1) * p) / 100].as_nanos();
+
+                print!("p50: {}ns, p99: {}ns, max: {}ns, ",
+                       percentile(50),
+                       percentile(99),
+                       percentile(100));
+            }
+        }
+    )
+}
+
+#[cfg(test)]
+mod latency_benches {
+    use super::*;
+
+    impl_latency_bench!(latency_raw_u32_slice_query_cache, U32, RawU32Slice, QUERY_CACHE);
+    impl_latency_bench!(latency_raw_u32_skewed_query_cache, U32, RawU32Skewed, QUERY_CACHE);
+    impl_latency_bench!(latency_raw_u64_slice_query_cache, U64, RawU64Slice, QUERY_CACHE);
+    impl_latency_bench!(latency_raw_u64_skewed_query_cache, U64, RawU64Skewed, QUERY_CACHE);
+    impl_latency_bench!(latency_lesqlite_u32_query_cache, U32, LesqliteU32, QUERY_CACHE);
+    impl_latency_bench!(latency_leb128c_u32_query_cache, U32, Leb128CU32, QUERY_CACHE);
+}
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+#[inline]
+fn read_unsigned_leb128_ref(data: &[u8], start_position: usize) -> (u128, usize) {
+    let mut result = 0;
     let mut shift = 0;
     let mut position = start_position;
     loop {
@@ -1021,6 +2525,255 @@ impl_read_unsigned_leb128_ref!(read_leb128_ref_u64, u64);
 impl_read_unsigned_leb128_ref!(read_leb128_ref_u128, u128);
 impl_read_unsigned_leb128_ref!(read_leb128_ref_usize, usize);
 
+// For fixed-width record fields: the value is followed by zero bytes up to
+// `field_width`, which are skipped (and checked) so that the position always
+// advances by exactly one field.
+pub fn read_leb128_u64_padded(data: &[u8], start_position: usize, field_width: usize) -> (u64, usize) {
+    let (value, read) = read_leb128_ref_u64(data, start_position);
+    assert!(read <= field_width, "leb128 value does not fit into its field");
+
+    let padding = &data[start_position + read .. start_position + field_width];
+    assert!(padding.iter().all(|&byte| byte == 0), "non-zero padding byte");
+
+    (value, field_width)
+}
+
+#[test]
+fn read_leb128_u64_padded_skips_field() {
+    let mut field = Vec::new();
+    let len = write_leb128c_u64(&mut field, 0, 1 << 20);
+    assert_eq!(len, 3);
+    field.resize(8, 0);
+    field.push(0x2A);
+
+    assert_eq!(read_leb128_u64_padded(&field, 0, 8), (1 << 20, 8));
+    assert_eq!(read_leb128_ref_u64(&field, 8), (42, 1));
+}
+
+#[test]
+#[should_panic(expected = "non-zero padding byte")]
+fn read_leb128_u64_padded_rejects_filler() {
+    let mut field = Vec::new();
+    write_leb128c_u64(&mut field, 0, 1 << 20);
+    field.resize(8, 0);
+    field[6] = 0xFF;
+
+    read_leb128_u64_padded(&field, 0, 8);
+}
+
+// Longest leb128 encoding that can still fit into the u128 accumulator.
+const LEB128_REF_MAX_BYTES: usize = mem::size_of::<u128>() * 8 / 7 + 1;
+
+// Same as `read_unsigned_leb128_ref` but stops after `LEB128_REF_MAX_BYTES`
+// instead of shifting past the end of the accumulator, so it can be pointed
+// at untrusted input.
+pub fn read_leb128_ref_checked(data: &[u8], start_position: usize) -> Result<(u128, usize), DecodeError> {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut position = start_position;
+
+    for _ in 0 .. LEB128_REF_MAX_BYTES {
+        let byte = *data.get(position).ok_or(DecodeError::UnexpectedEof)?;
+        position += 1;
+        result |= ((byte & 0x7F) as u128) << shift;
+        if (byte & 0x80) == 0 {
+            return Ok((result, position - start_position));
+        }
+        shift += 7;
+    }
+
+    Err(DecodeError::Overlong)
+}
+
+#[test]
+fn read_leb128_ref_checked_is_bounded() {
+    let all_continuation = [0x80; 20];
+    assert_eq!(read_leb128_ref_checked(&all_continuation, 0), Err(DecodeError::Overlong));
+
+    let mut out = Vec::new();
+    let len = write_leb128c_u128(&mut out, 0, u128::MAX);
+    assert_eq!(read_leb128_ref_checked(&out, 0), Ok((u128::MAX, len)));
+    assert_eq!(read_leb128_ref_checked(&out[.. len - 1], 0), Err(DecodeError::UnexpectedEof));
+}
+
+// Like `read_leb128_ref_usize` but refuses values that don't fit into a usize
+// of the current target instead of silently truncating them.
+pub fn read_leb128_usize_strict(data: &[u8], start_position: usize) -> Result<(usize, usize), DecodeError> {
+    let (value, read) = read_leb128_ref_checked(data, start_position)?;
+
+    if value > usize::MAX as u128 {
+        return Err(DecodeError::Overflow);
+    }
+
+    Ok((value as usize, read))
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn read_leb128_usize_strict_rejects_overflow() {
+    let mut out = Vec::new();
+    write_leb128c_u128(&mut out, 0, u64::MAX as u128 + 1);
+    assert_eq!(read_leb128_usize_strict(&out, 0), Err(DecodeError::Overflow));
+
+    let mut out = Vec::new();
+    let len = write_leb128c_u128(&mut out, 0, u64::MAX as u128);
+    assert_eq!(read_leb128_usize_strict(&out, 0), Ok((usize::MAX, len)));
+}
+
+// Never reads more bytes than a usize of the current target can need.
+pub fn read_leb128_usize_checked(data: &[u8], start_position: usize) -> Result<(usize, usize), DecodeError> {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut position = start_position;
+
+    for i in 0 .. USIZE_LEB128_SIZE {
+        let byte = *data.get(position).ok_or(DecodeError::UnexpectedEof)?;
+        position += 1;
+
+        // The last group only has room for the bits left over in a usize.
+        if i == USIZE_LEB128_SIZE - 1 && ((byte & 0x7F) as u32) >> (usize::BITS - shift) != 0 {
+            return Err(DecodeError::Overflow);
+        }
+
+        result |= ((byte & 0x7F) as usize) << shift;
+        if (byte & 0x80) == 0 {
+            return Ok((result, position - start_position));
+        }
+        shift += 7;
+    }
+
+    Err(DecodeError::Overlong)
+}
+
+#[test]
+#[cfg(target_pointer_width = "32")]
+fn read_leb128_usize_checked_boundary() {
+    let max = [0xFF, 0xFF, 0xFF, 0xFF, 0x0F];
+    assert_eq!(read_leb128_usize_checked(&max, 0), Ok((usize::MAX, 5)));
+
+    let overflow = [0xFF, 0xFF, 0xFF, 0xFF, 0x7F];
+    assert_eq!(read_leb128_usize_checked(&overflow, 0), Err(DecodeError::Overflow));
+
+    // Already overflows at the fifth byte.
+    let overflow_64 = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F];
+    assert_eq!(read_leb128_usize_checked(&overflow_64, 0), Err(DecodeError::Overflow));
+
+    let too_long = [0x80, 0x80, 0x80, 0x80, 0x80, 0x00];
+    assert_eq!(read_leb128_usize_checked(&too_long, 0), Err(DecodeError::Overlong));
+
+    let truncated = [0x80, 0x80];
+    assert_eq!(read_leb128_usize_checked(&truncated, 0), Err(DecodeError::UnexpectedEof));
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn read_leb128_usize_checked_boundary() {
+    let max = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+    assert_eq!(read_leb128_usize_checked(&max, 0), Ok((usize::MAX, 10)));
+
+    let overflow = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F];
+    assert_eq!(read_leb128_usize_checked(&overflow, 0), Err(DecodeError::Overflow));
+
+    // The 32-bit overflow case is an ordinary 35-bit value here.
+    let overflow_32 = [0xFF, 0xFF, 0xFF, 0xFF, 0x7F];
+    assert_eq!(read_leb128_usize_checked(&overflow_32, 0), Ok(((1 << 35) - 1, 5)));
+
+    let too_long = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00];
+    assert_eq!(read_leb128_usize_checked(&too_long, 0), Err(DecodeError::Overlong));
+
+    let truncated = [0x80, 0x80];
+    assert_eq!(read_leb128_usize_checked(&truncated, 0), Err(DecodeError::UnexpectedEof));
+}
+
+// Only accepts the minimal encoding: the final byte must carry a non-zero
+// group (unless it is the only byte) and the tenth byte may only hold bit 63.
+pub fn read_leb128_canonical_u64(data: &[u8], start_position: usize) -> Result<(u64, usize), DecodeError> {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut position = start_position;
+
+    for i in 0 .. leb128_size!(u64) {
+        let byte = *data.get(position).ok_or(DecodeError::UnexpectedEof)?;
+        position += 1;
+
+        if i == leb128_size!(u64) - 1 && (byte & 0x7E) != 0 {
+            return Err(DecodeError::NonCanonical);
+        }
+
+        result |= ((byte & 0x7F) as u64) << shift;
+
+        if (byte & 0x80) == 0 {
+            if byte == 0 && i > 0 {
+                return Err(DecodeError::NonCanonical);
+            }
+            return Ok((result, position - start_position));
+        }
+        shift += 7;
+    }
+
+    Err(DecodeError::Overlong)
+}
+
+#[test]
+fn read_leb128_canonical_u64_rejects_early_terminator() {
+    assert_eq!(read_leb128_canonical_u64(&[0x00], 0), Ok((0, 1)));
+    assert_eq!(read_leb128_canonical_u64(&[0xAC, 0x02], 0), Ok((300, 2)));
+
+    // The second byte clears the continuation bit with an empty group, so
+    // the trailing 0x02 that should have belonged to the value is left over.
+    assert_eq!(read_leb128_canonical_u64(&[0xAC, 0x00, 0x02], 0), Err(DecodeError::NonCanonical));
+    assert_eq!(read_leb128_canonical_u64(&[0x80, 0x00], 0), Err(DecodeError::NonCanonical));
+
+    let mut out = Vec::new();
+    let len = write_leb128c_u64(&mut out, 0, u64::MAX);
+    assert_eq!(read_leb128_canonical_u64(&out, 0), Ok((u64::MAX, len)));
+
+    let overflow = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02];
+    assert_eq!(read_leb128_canonical_u64(&overflow, 0), Err(DecodeError::NonCanonical));
+
+    let too_long = [0x80; 11];
+    assert_eq!(read_leb128_canonical_u64(&too_long, 0), Err(DecodeError::Overlong));
+
+    assert_eq!(read_leb128_canonical_u64(&[0xAC], 0), Err(DecodeError::UnexpectedEof));
+}
+
+// Also reports whether the value was encoded with more bytes than needed,
+// i.e. whether the final group is an empty padding byte.
+pub fn read_leb128_u64_with_flags(data: &[u8], start_position: usize) -> Result<(u64, usize, bool), DecodeError> {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut position = start_position;
+
+    for _ in 0 .. leb128_size!(u64) {
+        let byte = *data.get(position).ok_or(DecodeError::UnexpectedEof)?;
+        position += 1;
+        result |= ((byte & 0x7F) as u64) << shift;
+        if (byte & 0x80) == 0 {
+            let len = position - start_position;
+            return Ok((result, len, byte == 0 && len > 1));
+        }
+        shift += 7;
+    }
+
+    Err(DecodeError::Overlong)
+}
+
+#[test]
+fn read_leb128_u64_with_flags_detects_padding() {
+    assert_eq!(read_leb128_u64_with_flags(&[0x00], 0), Ok((0, 1, false)));
+    assert_eq!(read_leb128_u64_with_flags(&[0x80, 0x00], 0), Ok((0, 2, true)));
+    assert_eq!(read_leb128_u64_with_flags(&[0xAC, 0x02], 0), Ok((300, 2, false)));
+    assert_eq!(read_leb128_u64_with_flags(&[0xAC, 0x82, 0x00], 0), Ok((300, 3, true)));
+
+    // Padding can't go on forever: past ten bytes the value is rejected.
+    let too_long = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00];
+    assert_eq!(read_leb128_u64_with_flags(&too_long, 0), Err(DecodeError::Overlong));
+
+    assert_eq!(read_leb128_u64_with_flags(&[0xAC], 0), Err(DecodeError::UnexpectedEof));
+}
+
+impl_varint_decoder!(Leb128Ref, read_unsigned_leb128_ref);
+
 
 macro_rules! impl_read_unsigned_leb128_fixed {
     ($fn_name:ident, $int_ty:ident) => (
@@ -1051,6 +2804,12 @@ impl_read_unsigned_leb128_fixed!(read_leb128_fixed_u64, u64);
 impl_read_unsigned_leb128_fixed!(read_leb128_fixed_u128, u128);
 impl_read_unsigned_leb128_fixed!(read_leb128_fixed_usize, usize);
 
+impl_varint_decoder!(Leb128FixedU16, read_leb128_fixed_u16);
+impl_varint_decoder!(Leb128FixedU32, read_leb128_fixed_u32);
+impl_varint_decoder!(Leb128FixedU64, read_leb128_fixed_u64);
+impl_varint_decoder!(Leb128FixedU128, read_leb128_fixed_u128);
+impl_varint_decoder!(Leb128FixedUsize, read_leb128_fixed_usize);
+
 
 macro_rules! impl_read_unsigned_leb128_fixed2 {
     ($fn_name:ident, $int_ty:ident) => (
@@ -1060,7 +2819,12 @@ macro_rules! impl_read_unsigned_leb128_fixed2 {
             let mut shift = 0;
             let mut position = start_position;
 
+            // The loop stops after `leb128_size!` bytes, so the last group is
+            // shifted by at most `7 * (leb128_size! - 1)`, which is still
+            // below the bit width of every type. Bits of that group that
+            // don't fit are shifted out instead of overflowing the shift.
             for _ in 0 .. leb128_size!($int_ty) {
+                debug_assert!(shift < mem::size_of::<$int_ty>() * 8);
                 let byte = data[position];
                 position += 1;
                 result |= ((byte & 0x7F) as $int_ty) << shift;
@@ -1081,7 +2845,28 @@ impl_read_unsigned_leb128_fixed2!(read_leb128_fixed2_u64, u64);
 impl_read_unsigned_leb128_fixed2!(read_leb128_fixed2_u128, u128);
 impl_read_unsigned_leb128_fixed2!(read_leb128_fixed2_usize, usize);
 
+impl_varint_decoder!(Leb128Fixed2U16, read_leb128_fixed2_u16);
+impl_varint_decoder!(Leb128Fixed2U32, read_leb128_fixed2_u32);
+impl_varint_decoder!(Leb128Fixed2U64, read_leb128_fixed2_u64);
+impl_varint_decoder!(Leb128Fixed2U128, read_leb128_fixed2_u128);
+impl_varint_decoder!(Leb128Fixed2Usize, read_leb128_fixed2_usize);
+
+#[test]
+fn read_leb128_fixed2_u16_masks_last_group() {
+    assert_eq!(read_leb128_fixed2_u16(&[0xFF, 0xFF, 0x03], 0), (0xFFFF, 3));
+
+    // The top bit of the last group doesn't fit into a u16 and is dropped.
+    assert_eq!(read_leb128_fixed2_u16(&[0x80, 0x80, 0x07], 0), (0xC000, 3));
+
+    // A malformed value that keeps going stops after three bytes.
+    assert_eq!(read_leb128_fixed2_u16(&[0xFF, 0xFF, 0xFF, 0x01], 0), (0xFFFF, 3));
+}
+
 
+// Reads without bounds checks. Callers must guarantee that the value's
+// terminating byte lies within `data`; the assert at the end only catches a
+// violation after the out-of-bounds read has happened. Debug builds check
+// every read up front instead.
 macro_rules! impl_read_unsigned_leb128_unsafe {
     ($fn_name:ident, $int_ty:ident) => (
         #[inline]
@@ -1092,6 +2877,7 @@ macro_rules! impl_read_unsigned_leb128_unsafe {
                 let mut position = start_position;
 
                 for _ in 0 .. leb128_size!($int_ty) {
+                    debug_assert!(position < data.len(), "leb128 value runs past the end of the buffer");
                     let byte = *data.get_unchecked(position);
                     position += 1;
                     result |= ((byte & 0x7F) as $int_ty) << shift;
@@ -1115,11 +2901,38 @@ impl_read_unsigned_leb128_unsafe!(read_leb128_unsafe_u64, u64);
 impl_read_unsigned_leb128_unsafe!(read_leb128_unsafe_u128, u128);
 impl_read_unsigned_leb128_unsafe!(read_leb128_unsafe_usize, usize);
 
+impl_varint_decoder!(Leb128UnsafeU16, read_leb128_unsafe_u16);
+impl_varint_decoder!(Leb128UnsafeU32, read_leb128_unsafe_u32);
+impl_varint_decoder!(Leb128UnsafeU64, read_leb128_unsafe_u64);
+impl_varint_decoder!(Leb128UnsafeU128, read_leb128_unsafe_u128);
+impl_varint_decoder!(Leb128UnsafeUsize, read_leb128_unsafe_usize);
+
+#[test]
+fn read_leb128_unsafe_u64_exact_buffer() {
+    // Each value gets a buffer that ends right at its terminating byte, so
+    // any read past it shows up under Miri.
+    for &value in &[0, 127, 128, 1 << 35, u64::MAX] {
+        let mut out = Vec::new();
+        let len = write_leb128c_u64(&mut out, 0, value);
+        let exact = out[.. len].to_vec().into_boxed_slice();
+        assert_eq!(read_leb128_unsafe_u64(&exact, 0), (value, len));
+    }
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "leb128 value runs past the end of the buffer")]
+fn read_leb128_unsafe_u64_truncated() {
+    // Breaks the caller contract; only well-defined because debug builds
+    // check each read before it happens.
+    read_leb128_unsafe_u64(&[0x80, 0x80], 0);
+}
+
 macro_rules! impl_read_unsigned_leb128_unsafe2 {
     ($fn_name:ident, $int_ty:ident) => (
         #[inline]
         pub fn $fn_name(data: &[u8], start_position: usize) -> ($int_ty, usize) {
-            #[repr(packed)] struct Unaligned<T>(T);
+            #[repr(C, packed)] struct Unaligned<T>(T);
 
             unsafe {
                 let ptr = data.as_ptr().offset(start_position as isize);
@@ -1135,7 +2948,7 @@ macro_rules! impl_read_unsigned_leb128_unsafe2 {
                         assert!(start_position + bytes_read <= data.len());
                         return (result, bytes_read)
                     }
-                    full_int = full_int >> 8;
+                    full_int >>= 8;
                 }
 
                 unreachable!()
@@ -1150,6 +2963,11 @@ impl_read_unsigned_leb128_unsafe2!(read_leb128_unsafe2_u64, u64);
 // impl_read_unsigned_leb128_unsafe2!(read_leb128_unsafe2_u128, u128);
 impl_read_unsigned_leb128_unsafe2!(read_leb128_unsafe2_usize, usize);
 
+impl_varint_decoder!(Leb128Unsafe2U16, read_leb128_unsafe2_u16);
+impl_varint_decoder!(Leb128Unsafe2U32, read_leb128_unsafe2_u32);
+impl_varint_decoder!(Leb128Unsafe2U64, read_leb128_unsafe2_u64);
+impl_varint_decoder!(Leb128Unsafe2Usize, read_leb128_unsafe2_usize);
+
 
 
 macro_rules! impl_read_unsigned_leb128_weird {
@@ -1185,263 +3003,5763 @@ impl_read_unsigned_leb128_weird!(read_leb128_weird_u64, u64);
 impl_read_unsigned_leb128_weird!(read_leb128_weird_u128, u128);
 impl_read_unsigned_leb128_weird!(read_leb128_weird_usize, usize);
 
+impl_varint_decoder!(Leb128WeirdU16, read_leb128_weird_u16);
+impl_varint_decoder!(Leb128WeirdU32, read_leb128_weird_u32);
+impl_varint_decoder!(Leb128WeirdU64, read_leb128_weird_u64);
+impl_varint_decoder!(Leb128WeirdU128, read_leb128_weird_u128);
+impl_varint_decoder!(Leb128WeirdUsize, read_leb128_weird_usize);
+
+#[cfg(feature = "std")]
+#[test]
+fn weird_reader_matches_ref() {
+    let mut values: Vec<u128> = vec![0, u64::MAX as u128];
+
+    for &name in &[METADATA, DEP_GRAPH, QUERY_CACHE] {
+        values.extend(load_test_data(name).iter().filter_map(|entry| {
+            match *entry {
+                Value::U8(v) => Some(v as u128),
+                Value::U16(v) => Some(v as u128),
+                Value::U32(v) => Some(v as u128),
+                Value::U64(v) => Some(v as u128),
+                Value::U128(v) if v <= u64::MAX as u128 => Some(v),
+                Value::Usize(v) => Some(v as u128),
+                _ => None,
+            }
+        }));
+    }
+
+    values.push(1 << 63);
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        position += write_leb128c_u128(&mut encoded, position, val);
+    }
+
+    // 1 << 63 needs all ten bytes, the last one being a bare terminator.
+    assert_eq!(&encoded[encoded.len() - 10 ..], &[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01][..]);
+
+    let mut position = 0;
+    for &val in values.iter() {
+        let weird = read_leb128_weird_u64(&encoded, position);
+        assert_eq!(weird, read_leb128_ref_u64(&encoded, position));
+        assert_eq!(weird.0 as u128, val);
+        position += weird.1;
+    }
+
+    assert_eq!(position, encoded.len());
+}
+
 
+// Takes the same optional `encoded` argument as `impl_bench!`. The `run_once`
+// test also checks that every value decodes to the original (truncated to the
+// reader's integer type).
 macro_rules! impl_read_bench {
+    // `$fun` is an `unsafe fn` whose contract is met by reading a buffer of
+    // complete values front to back.
+    ($bench_name:ident, $variant:ident, $fun:ident, $data:ident, unsafe) => (
+        impl_read_bench!(@impl $bench_name, $variant, $fun, $data, Leb128C, false, unsafe, []);
+    );
+    // Same, for a `#[target_feature]` reader. Skipped on CPUs without it.
+    ($bench_name:ident, $variant:ident, $fun:ident, $data:ident, unsafe, $feature:tt) => (
+        impl_read_bench!(@impl $bench_name, $variant, $fun, $data, Leb128C, false, unsafe, [$feature]);
+    );
+    ($bench_name:ident, $variant:ident, $fun:ident, $data:ident, encoded) => (
+        impl_read_bench!(@impl $bench_name, $variant, $fun, $data, Leb128C, true, safe, []);
+    );
+    ($bench_name:ident, $variant:ident, $fun:ident, $data:ident, $encoder:ty, encoded) => (
+        impl_read_bench!(@impl $bench_name, $variant, $fun, $data, $encoder, true, safe, []);
+    );
+    // `$fun` is a `RecordReader` method instead of a free function.
+    ($bench_name:ident, $variant:ident, $fun:ident, $data:ident, reader) => (
+        impl_read_bench!(@reader $bench_name, $variant, $fun, $data, Leb128C, false);
+    );
     ($bench_name:ident, $variant:ident, $fun:ident, $data:ident) => (
-        #[bench]
-        fn $bench_name(b: &mut test::Bencher) {
+        impl_read_bench!(@impl $bench_name, $variant, $fun, $data, Leb128C, false, safe, []);
+    );
+    ($bench_name:ident, $variant:ident, $fun:ident, $data:ident, $encoder:ty) => (
+        impl_read_bench!(@impl $bench_name, $variant, $fun, $data, $encoder, false, safe, []);
+    );
+    (@call safe, $fun:ident, $encoded:expr, $position:expr) => (
+        $fun($encoded, $position)
+    );
+    (@call unsafe, $fun:ident, $encoded:expr, $position:expr) => (
+        unsafe { $fun($encoded, $position) }
+    );
+    (@skip_unsupported) => ();
+    (@skip_unsupported $feature:tt) => (
+        if !is_x86_feature_detected!($feature) {
+            return;
+        }
+    );
+    (@impl $bench_name:ident, $variant:ident, $fun:ident, $data:ident, $encoder:ty, $encoded:expr, $call:tt, [$($feature:tt)*]) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
 
-            let test_data = load_test_data($data);
-            let test_data: Vec<_> = test_data.iter().filter_map(|entry| {
-                match *entry {
-                    Value::$variant(val) => Some(val),
-                    _ => None,
+            impl_read_bench!(@common $variant, $data, $encoder, [$($feature)*]);
+
+            #[inline]
+            pub fn decode_all(encoded: &[u8], test_data: &[variant_ty!($variant)], verify: bool) {
+                let mut position = 0;
+                for i in 0 .. test_data.len() {
+                    let (val, count) = impl_read_bench!(@call $call, $fun, encoded, position);
+                    ::core::hint::black_box(val);
+                    position += count;
+                    debug_assert!(count > 0);
+
+                    if verify {
+                        let bits = mem::size_of_val(&val) * 8;
+                        let mask = u128::MAX >> (128 - bits);
+                        assert_eq!(val as u128, test_data[i] as u128 & mask, "value #{}", i);
+                    }
                 }
-            }).collect();
 
+                if verify {
+                    assert_eq!(position, encoded.len());
+                }
+            }
+        }
+
+        impl_read_bench!(@bench $bench_name, $encoded, [$($feature)*]);
+    );
+    (@reader $bench_name:ident, $variant:ident, $fun:ident, $data:ident, $encoder:ty, $encoded:expr) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_read_bench!(@common $variant, $data, $encoder, []);
+
+            #[inline]
+            pub fn decode_all(encoded: &[u8], test_data: &[variant_ty!($variant)], verify: bool) {
+                let mut reader = RecordReader::new(encoded, 0);
+                for i in 0 .. test_data.len() {
+                    let val = reader.$fun().unwrap();
+                    ::core::hint::black_box(val);
+
+                    if verify {
+                        let bits = mem::size_of_val(&val) * 8;
+                        let mask = u128::MAX >> (128 - bits);
+                        assert_eq!(val as u128, test_data[i] as u128 & mask, "value #{}", i);
+                    }
+                }
 
-            if let Some(&x) = test_data.get(0) {
-                b.bytes =  (test_data.len() * ::std::mem::size_of_val(&x)) as u64;
+                if verify {
+                    assert_eq!(reader.remaining(), 0);
+                }
             }
+        }
 
-            let mut encoded = Vec::new();
+        impl_read_bench!(@bench $bench_name, $encoded, []);
+    );
+    (@common $variant:ident, $data:ident, $encoder:ty, [$($feature:tt)*]) => (
+        impl_bench_data!($variant, $data, $encoder);
 
-            for &val in test_data.iter() {
-                let pos = encoded.len();
-                write_leb128c_u128(&mut encoded, pos, val as u128);
+        #[test]
+        fn run_once() {
+            impl_read_bench!(@skip_unsupported $($feature)*);
+
+            let test_data = test_data();
+            let encoded = encode(&test_data);
+            decode_all(&encoded, &test_data, true);
+        }
+    );
+    (@bench $bench_name:ident, $encoded:expr, [$($feature:tt)*]) => (
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+            impl_read_bench!(@skip_unsupported $($feature)*);
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let encoded = $bench_name::encode(&test_data);
+
+            let input_bytes = b.bytes as usize;
+
+            if $encoded {
+                b.bytes = encoded.len() as u64;
             }
 
+            let mut elapsed = Duration::new(0, 0);
+            let mut iterations = 0;
+
             b.iter(|| {
-                let mut position = 0;
-                for _ in 0 .. test_data.len() {
-                    let (val, count) = unsafe { $fun(&mut encoded, position) };
-                    test::black_box(val);
-                    position += count;
-                    debug_assert!(count > 0);
+                let start = if $encoded { Some(Instant::now()) } else { None };
+
+                $bench_name::decode_all(&encoded, &test_data, false);
+
+                if let Some(start) = start {
+                    elapsed += start.elapsed();
+                    iterations += 1;
                 }
             });
+
+            if $encoded {
+                print_rates(input_bytes, encoded.len(), iterations, elapsed);
+            }
         }
     )
 }
 
-impl_read_bench!(read_leb128_ref_u16_dep_graph, Usize, read_leb128_ref_u16, DEP_GRAPH);
-impl_read_bench!(read_leb128_ref_u32_dep_graph, Usize, read_leb128_ref_u32, DEP_GRAPH);
-impl_read_bench!(read_leb128_ref_u64_dep_graph, Usize, read_leb128_ref_u64, DEP_GRAPH);
-impl_read_bench!(read_leb128_ref_u128_dep_graph, Usize, read_leb128_ref_u128, DEP_GRAPH);
-impl_read_bench!(read_leb128_ref_usize_dep_graph, Usize, read_leb128_ref_usize, DEP_GRAPH);
+#[cfg(test)]
+mod read_benches {
+    use super::*;
 
-impl_read_bench!(read_leb128_ref_u16_metadata, U16, read_leb128_ref_u16, METADATA);
-impl_read_bench!(read_leb128_ref_u32_metadata, U32, read_leb128_ref_u32, METADATA);
-impl_read_bench!(read_leb128_ref_u64_metadata, U64, read_leb128_ref_u64, METADATA);
-impl_read_bench!(read_leb128_ref_u128_metadata, U128, read_leb128_ref_u128, METADATA);
-impl_read_bench!(read_leb128_ref_usize_metadata, Usize, read_leb128_ref_usize, METADATA);
+    impl_read_bench!(read_leb128_ref_u16_dep_graph, Usize, read_leb128_ref_u16, DEP_GRAPH);
+    impl_read_bench!(read_leb128_ref_u32_dep_graph, Usize, read_leb128_ref_u32, DEP_GRAPH);
+    impl_read_bench!(read_leb128_ref_u64_dep_graph, Usize, read_leb128_ref_u64, DEP_GRAPH);
+    impl_read_bench!(read_leb128_ref_u128_dep_graph, Usize, read_leb128_ref_u128, DEP_GRAPH);
+    impl_read_bench!(read_leb128_ref_usize_dep_graph, Usize, read_leb128_ref_usize, DEP_GRAPH);
 
-impl_read_bench!(read_leb128_ref_u16_query_cache, U16, read_leb128_ref_u16, QUERY_CACHE);
-impl_read_bench!(read_leb128_ref_u32_query_cache, U32, read_leb128_ref_u32, QUERY_CACHE);
-impl_read_bench!(read_leb128_ref_u64_query_cache, U64, read_leb128_ref_u64, QUERY_CACHE);
-impl_read_bench!(read_leb128_ref_u128_query_cache, U128, read_leb128_ref_u128, QUERY_CACHE);
-impl_read_bench!(read_leb128_ref_usize_query_cache, Usize, read_leb128_ref_usize, QUERY_CACHE);
+    impl_read_bench!(read_leb128_ref_u16_metadata, U16, read_leb128_ref_u16, METADATA);
+    impl_read_bench!(read_leb128_ref_u32_metadata, U32, read_leb128_ref_u32, METADATA);
+    impl_read_bench!(read_leb128_ref_u64_metadata, U64, read_leb128_ref_u64, METADATA);
+    impl_read_bench!(read_leb128_ref_u128_metadata, U128, read_leb128_ref_u128, METADATA);
+    impl_read_bench!(read_leb128_ref_usize_metadata, Usize, read_leb128_ref_usize, METADATA);
 
+    impl_read_bench!(read_leb128_ref_u16_query_cache, U16, read_leb128_ref_u16, QUERY_CACHE);
+    impl_read_bench!(read_leb128_ref_u32_query_cache, U32, read_leb128_ref_u32, QUERY_CACHE);
+    impl_read_bench!(read_leb128_ref_u64_query_cache, U64, read_leb128_ref_u64, QUERY_CACHE);
+    impl_read_bench!(read_leb128_ref_u64_encoded_query_cache, U64, read_leb128_ref_u64, QUERY_CACHE, encoded);
+    impl_read_bench!(read_leb128_ref_u128_query_cache, U128, read_leb128_ref_u128, QUERY_CACHE);
+    impl_read_bench!(read_leb128_ref_usize_query_cache, Usize, read_leb128_ref_usize, QUERY_CACHE);
 
+    impl_read_bench!(read_shift_be_u64_metadata, U64, read_shift_be_u64, METADATA, ShiftBeU64);
+    impl_read_bench!(read_shift_be_u64_dep_graph, U64, read_shift_be_u64, DEP_GRAPH, ShiftBeU64);
+    impl_read_bench!(read_shift_be_u64_query_cache, U64, read_shift_be_u64, QUERY_CACHE, ShiftBeU64);
 
 
-// impl_read_bench!(read_leb128_fixed_u16_dep_graph, Usize, read_leb128_fixed_u16, DEP_GRAPH);
-// impl_read_bench!(read_leb128_fixed_u32_dep_graph, Usize, read_leb128_fixed_u32, DEP_GRAPH);
-// impl_read_bench!(read_leb128_fixed_u64_dep_graph, Usize, read_leb128_fixed_u64, DEP_GRAPH);
-// impl_read_bench!(read_leb128_fixed_u128_dep_graph, Usize, read_leb128_fixed_u128, DEP_GRAPH);
-// impl_read_bench!(read_leb128_fixed_usize_dep_graph, Usize, read_leb128_fixed_usize, DEP_GRAPH);
 
-// impl_read_bench!(read_leb128_fixed_u16_metadata, U16, read_leb128_fixed_u16, METADATA);
-// impl_read_bench!(read_leb128_fixed_u32_metadata, U32, read_leb128_fixed_u32, METADATA);
-// impl_read_bench!(read_leb128_fixed_u64_metadata, U64, read_leb128_fixed_u64, METADATA);
-// impl_read_bench!(read_leb128_fixed_u128_metadata, U128, read_leb128_fixed_u128, METADATA);
-// impl_read_bench!(read_leb128_fixed_usize_metadata, Usize, read_leb128_fixed_usize, METADATA);
 
-// impl_read_bench!(read_leb128_fixed_u16_query_cache, U16, read_leb128_fixed_u16, QUERY_CACHE);
-// impl_read_bench!(read_leb128_fixed_u32_query_cache, U32, read_leb128_fixed_u32, QUERY_CACHE);
-// impl_read_bench!(read_leb128_fixed_u64_query_cache, U64, read_leb128_fixed_u64, QUERY_CACHE);
-// impl_read_bench!(read_leb128_fixed_u128_query_cache, U128, read_leb128_fixed_u128, QUERY_CACHE);
-// impl_read_bench!(read_leb128_fixed_usize_query_cache, Usize, read_leb128_fixed_usize, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_fixed_u16_dep_graph, Usize, read_leb128_fixed_u16, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_fixed_u32_dep_graph, Usize, read_leb128_fixed_u32, DEP_GRAPH);
+    impl_read_bench!(read_leb128_fixed_u64_dep_graph, Usize, read_leb128_fixed_u64, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_fixed_u128_dep_graph, Usize, read_leb128_fixed_u128, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_fixed_usize_dep_graph, Usize, read_leb128_fixed_usize, DEP_GRAPH);
 
+    // impl_read_bench!(read_leb128_fixed_u16_metadata, U16, read_leb128_fixed_u16, METADATA);
+    // impl_read_bench!(read_leb128_fixed_u32_metadata, U32, read_leb128_fixed_u32, METADATA);
+    impl_read_bench!(read_leb128_fixed_u64_metadata, U64, read_leb128_fixed_u64, METADATA);
+    // impl_read_bench!(read_leb128_fixed_u128_metadata, U128, read_leb128_fixed_u128, METADATA);
+    // impl_read_bench!(read_leb128_fixed_usize_metadata, Usize, read_leb128_fixed_usize, METADATA);
 
+    // impl_read_bench!(read_leb128_fixed_u16_query_cache, U16, read_leb128_fixed_u16, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_fixed_u32_query_cache, U32, read_leb128_fixed_u32, QUERY_CACHE);
+    impl_read_bench!(read_leb128_fixed_u64_query_cache, U64, read_leb128_fixed_u64, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_fixed_u128_query_cache, U128, read_leb128_fixed_u128, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_fixed_usize_query_cache, Usize, read_leb128_fixed_usize, QUERY_CACHE);
 
 
 
-impl_read_bench!(read_leb128_fixed2_u16_dep_graph, Usize, read_leb128_fixed2_u16, DEP_GRAPH);
-impl_read_bench!(read_leb128_fixed2_u32_dep_graph, Usize, read_leb128_fixed2_u32, DEP_GRAPH);
-impl_read_bench!(read_leb128_fixed2_u64_dep_graph, Usize, read_leb128_fixed2_u64, DEP_GRAPH);
-impl_read_bench!(read_leb128_fixed2_u128_dep_graph, Usize, read_leb128_fixed2_u128, DEP_GRAPH);
-impl_read_bench!(read_leb128_fixed2_usize_dep_graph, Usize, read_leb128_fixed2_usize, DEP_GRAPH);
 
-impl_read_bench!(read_leb128_fixed2_u16_metadata, U16, read_leb128_fixed2_u16, METADATA);
-impl_read_bench!(read_leb128_fixed2_u32_metadata, U32, read_leb128_fixed2_u32, METADATA);
-impl_read_bench!(read_leb128_fixed2_u64_metadata, U64, read_leb128_fixed2_u64, METADATA);
-impl_read_bench!(read_leb128_fixed2_u128_metadata, U128, read_leb128_fixed2_u128, METADATA);
-impl_read_bench!(read_leb128_fixed2_usize_metadata, Usize, read_leb128_fixed2_usize, METADATA);
 
-impl_read_bench!(read_leb128_fixed2_u16_query_cache, U16, read_leb128_fixed2_u16, QUERY_CACHE);
-impl_read_bench!(read_leb128_fixed2_u32_query_cache, U32, read_leb128_fixed2_u32, QUERY_CACHE);
-impl_read_bench!(read_leb128_fixed2_u64_query_cache, U64, read_leb128_fixed2_u64, QUERY_CACHE);
-impl_read_bench!(read_leb128_fixed2_u128_query_cache, U128, read_leb128_fixed2_u128, QUERY_CACHE);
-impl_read_bench!(read_leb128_fixed2_usize_query_cache, Usize, read_leb128_fixed2_usize, QUERY_CACHE);
+    impl_read_bench!(read_leb128_fixed2_u16_dep_graph, Usize, read_leb128_fixed2_u16, DEP_GRAPH);
+    impl_read_bench!(read_leb128_fixed2_u32_dep_graph, Usize, read_leb128_fixed2_u32, DEP_GRAPH);
+    impl_read_bench!(read_leb128_fixed2_u64_dep_graph, Usize, read_leb128_fixed2_u64, DEP_GRAPH);
+    impl_read_bench!(read_leb128_fixed2_u128_dep_graph, Usize, read_leb128_fixed2_u128, DEP_GRAPH);
+    impl_read_bench!(read_leb128_fixed2_usize_dep_graph, Usize, read_leb128_fixed2_usize, DEP_GRAPH);
 
+    impl_read_bench!(read_leb128_fixed2_u16_metadata, U16, read_leb128_fixed2_u16, METADATA);
+    impl_read_bench!(read_leb128_fixed2_u32_metadata, U32, read_leb128_fixed2_u32, METADATA);
+    impl_read_bench!(read_leb128_fixed2_u64_metadata, U64, read_leb128_fixed2_u64, METADATA);
+    impl_read_bench!(read_leb128_fixed2_u128_metadata, U128, read_leb128_fixed2_u128, METADATA);
+    impl_read_bench!(read_leb128_fixed2_usize_metadata, Usize, read_leb128_fixed2_usize, METADATA);
 
+    impl_read_bench!(read_leb128_fixed2_u16_query_cache, U16, read_leb128_fixed2_u16, QUERY_CACHE);
+    impl_read_bench!(read_leb128_fixed2_u32_query_cache, U32, read_leb128_fixed2_u32, QUERY_CACHE);
+    impl_read_bench!(read_leb128_fixed2_u64_query_cache, U64, read_leb128_fixed2_u64, QUERY_CACHE);
+    impl_read_bench!(read_leb128_fixed2_u128_query_cache, U128, read_leb128_fixed2_u128, QUERY_CACHE);
+    impl_read_bench!(read_leb128_fixed2_usize_query_cache, Usize, read_leb128_fixed2_usize, QUERY_CACHE);
 
-// impl_read_bench!(read_leb128_unsafe_u16_dep_graph, Usize, read_leb128_unsafe_u16, DEP_GRAPH);
-// impl_read_bench!(read_leb128_unsafe_u32_dep_graph, Usize, read_leb128_unsafe_u32, DEP_GRAPH);
-// impl_read_bench!(read_leb128_unsafe_u64_dep_graph, Usize, read_leb128_unsafe_u64, DEP_GRAPH);
-// impl_read_bench!(read_leb128_unsafe_u128_dep_graph, Usize, read_leb128_unsafe_u128, DEP_GRAPH);
-// impl_read_bench!(read_leb128_unsafe_usize_dep_graph, Usize, read_leb128_unsafe_usize, DEP_GRAPH);
 
-// impl_read_bench!(read_leb128_unsafe_u16_metadata, U16, read_leb128_unsafe_u16, METADATA);
-// impl_read_bench!(read_leb128_unsafe_u32_metadata, U32, read_leb128_unsafe_u32, METADATA);
-// impl_read_bench!(read_leb128_unsafe_u64_metadata, U64, read_leb128_unsafe_u64, METADATA);
-// impl_read_bench!(read_leb128_unsafe_u128_metadata, U128, read_leb128_unsafe_u128, METADATA);
-// impl_read_bench!(read_leb128_unsafe_usize_metadata, Usize, read_leb128_unsafe_usize, METADATA);
 
-// impl_read_bench!(read_leb128_unsafe_u16_query_cache, U16, read_leb128_unsafe_u16, QUERY_CACHE);
-// impl_read_bench!(read_leb128_unsafe_u32_query_cache, U32, read_leb128_unsafe_u32, QUERY_CACHE);
-// impl_read_bench!(read_leb128_unsafe_u64_query_cache, U64, read_leb128_unsafe_u64, QUERY_CACHE);
-// impl_read_bench!(read_leb128_unsafe_u128_query_cache, U128, read_leb128_unsafe_u128, QUERY_CACHE);
-// impl_read_bench!(read_leb128_unsafe_usize_query_cache, Usize, read_leb128_unsafe_usize, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_unsafe_u16_dep_graph, Usize, read_leb128_unsafe_u16, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_unsafe_u32_dep_graph, Usize, read_leb128_unsafe_u32, DEP_GRAPH);
+    impl_read_bench!(read_leb128_unsafe_u64_dep_graph, Usize, read_leb128_unsafe_u64, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_unsafe_u128_dep_graph, Usize, read_leb128_unsafe_u128, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_unsafe_usize_dep_graph, Usize, read_leb128_unsafe_usize, DEP_GRAPH);
 
+    // impl_read_bench!(read_leb128_unsafe_u16_metadata, U16, read_leb128_unsafe_u16, METADATA);
+    // impl_read_bench!(read_leb128_unsafe_u32_metadata, U32, read_leb128_unsafe_u32, METADATA);
+    impl_read_bench!(read_leb128_unsafe_u64_metadata, U64, read_leb128_unsafe_u64, METADATA);
+    // impl_read_bench!(read_leb128_unsafe_u128_metadata, U128, read_leb128_unsafe_u128, METADATA);
+    // impl_read_bench!(read_leb128_unsafe_usize_metadata, Usize, read_leb128_unsafe_usize, METADATA);
 
+    // impl_read_bench!(read_leb128_unsafe_u16_query_cache, U16, read_leb128_unsafe_u16, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_unsafe_u32_query_cache, U32, read_leb128_unsafe_u32, QUERY_CACHE);
+    impl_read_bench!(read_leb128_unsafe_u64_query_cache, U64, read_leb128_unsafe_u64, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_unsafe_u128_query_cache, U128, read_leb128_unsafe_u128, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_unsafe_usize_query_cache, Usize, read_leb128_unsafe_usize, QUERY_CACHE);
 
 
 
-// impl_read_bench!(read_leb128_unsafe2_u16_dep_graph, Usize, read_leb128_unsafe2_u16, DEP_GRAPH);
-// impl_read_bench!(read_leb128_unsafe2_u32_dep_graph, Usize, read_leb128_unsafe2_u32, DEP_GRAPH);
-// impl_read_bench!(read_leb128_unsafe2_u64_dep_graph, Usize, read_leb128_unsafe2_u64, DEP_GRAPH);
-// // impl_read_bench!(read_leb128_unsafe2_u128_dep_graph, Usize, read_leb128_unsafe2_u128, DEP_GRAPH);
-// impl_read_bench!(read_leb128_unsafe2_usize_dep_graph, Usize, read_leb128_unsafe2_usize, DEP_GRAPH);
 
-// impl_read_bench!(read_leb128_unsafe2_u16_metadata, U16, read_leb128_unsafe2_u16, METADATA);
-// impl_read_bench!(read_leb128_unsafe2_u32_metadata, U32, read_leb128_unsafe2_u32, METADATA);
-// impl_read_bench!(read_leb128_unsafe2_u64_metadata, U64, read_leb128_unsafe2_u64, METADATA);
-// // impl_read_bench!(read_leb128_unsafe2_u128_metadata, U128, read_leb128_unsafe2_u128, METADATA);
-// impl_read_bench!(read_leb128_unsafe2_usize_metadata, Usize, read_leb128_unsafe2_usize, METADATA);
 
-// impl_read_bench!(read_leb128_unsafe2_u16_query_cache, U16, read_leb128_unsafe2_u16, QUERY_CACHE);
-// impl_read_bench!(read_leb128_unsafe2_u32_query_cache, U32, read_leb128_unsafe2_u32, QUERY_CACHE);
-// impl_read_bench!(read_leb128_unsafe2_u64_query_cache, U64, read_leb128_unsafe2_u64, QUERY_CACHE);
-// // impl_read_bench!(read_leb128_unsafe2_u128_query_cache, U128, read_leb128_unsafe2_u128, QUERY_CACHE);
-// impl_read_bench!(read_leb128_unsafe2_usize_query_cache, Usize, read_leb128_unsafe2_usize, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_unsafe2_u16_dep_graph, Usize, read_leb128_unsafe2_u16, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_unsafe2_u32_dep_graph, Usize, read_leb128_unsafe2_u32, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_unsafe2_u64_dep_graph, Usize, read_leb128_unsafe2_u64, DEP_GRAPH);
+    // // impl_read_bench!(read_leb128_unsafe2_u128_dep_graph, Usize, read_leb128_unsafe2_u128, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_unsafe2_usize_dep_graph, Usize, read_leb128_unsafe2_usize, DEP_GRAPH);
 
+    // impl_read_bench!(read_leb128_unsafe2_u16_metadata, U16, read_leb128_unsafe2_u16, METADATA);
+    // impl_read_bench!(read_leb128_unsafe2_u32_metadata, U32, read_leb128_unsafe2_u32, METADATA);
+    // impl_read_bench!(read_leb128_unsafe2_u64_metadata, U64, read_leb128_unsafe2_u64, METADATA);
+    // // impl_read_bench!(read_leb128_unsafe2_u128_metadata, U128, read_leb128_unsafe2_u128, METADATA);
+    // impl_read_bench!(read_leb128_unsafe2_usize_metadata, Usize, read_leb128_unsafe2_usize, METADATA);
 
+    // impl_read_bench!(read_leb128_unsafe2_u16_query_cache, U16, read_leb128_unsafe2_u16, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_unsafe2_u32_query_cache, U32, read_leb128_unsafe2_u32, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_unsafe2_u64_query_cache, U64, read_leb128_unsafe2_u64, QUERY_CACHE);
+    // // impl_read_bench!(read_leb128_unsafe2_u128_query_cache, U128, read_leb128_unsafe2_u128, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_unsafe2_usize_query_cache, Usize, read_leb128_unsafe2_usize, QUERY_CACHE);
 
 
 
-// impl_read_bench!(read_leb128_weird_u16_dep_graph, Usize, read_leb128_weird_u16, DEP_GRAPH);
-// impl_read_bench!(read_leb128_weird_u32_dep_graph, Usize, read_leb128_weird_u32, DEP_GRAPH);
-// impl_read_bench!(read_leb128_weird_u64_dep_graph, Usize, read_leb128_weird_u64, DEP_GRAPH);
-// impl_read_bench!(read_leb128_weird_u128_dep_graph, Usize, read_leb128_weird_u128, DEP_GRAPH);
-// impl_read_bench!(read_leb128_weird_usize_dep_graph, Usize, read_leb128_weird_usize, DEP_GRAPH);
 
-// impl_read_bench!(read_leb128_weird_u16_metadata, U16, read_leb128_weird_u16, METADATA);
-// impl_read_bench!(read_leb128_weird_u32_metadata, U32, read_leb128_weird_u32, METADATA);
-// impl_read_bench!(read_leb128_weird_u64_metadata, U64, read_leb128_weird_u64, METADATA);
-// impl_read_bench!(read_leb128_weird_u128_metadata, U128, read_leb128_weird_u128, METADATA);
-// impl_read_bench!(read_leb128_weird_usize_metadata, Usize, read_leb128_weird_usize, METADATA);
 
-// impl_read_bench!(read_leb128_weird_u16_query_cache, U16, read_leb128_weird_u16, QUERY_CACHE);
-// impl_read_bench!(read_leb128_weird_u32_query_cache, U32, read_leb128_weird_u32, QUERY_CACHE);
-// impl_read_bench!(read_leb128_weird_u64_query_cache, U64, read_leb128_weird_u64, QUERY_CACHE);
-// impl_read_bench!(read_leb128_weird_u128_query_cache, U128, read_leb128_weird_u128, QUERY_CACHE);
-// impl_read_bench!(read_leb128_weird_usize_query_cache, Usize, read_leb128_weird_usize, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_weird_u16_dep_graph, Usize, read_leb128_weird_u16, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_weird_u32_dep_graph, Usize, read_leb128_weird_u32, DEP_GRAPH);
+    impl_read_bench!(read_leb128_weird_u64_dep_graph, Usize, read_leb128_weird_u64, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_weird_u128_dep_graph, Usize, read_leb128_weird_u128, DEP_GRAPH);
+    // impl_read_bench!(read_leb128_weird_usize_dep_graph, Usize, read_leb128_weird_usize, DEP_GRAPH);
 
+    // impl_read_bench!(read_leb128_weird_u16_metadata, U16, read_leb128_weird_u16, METADATA);
+    // impl_read_bench!(read_leb128_weird_u32_metadata, U32, read_leb128_weird_u32, METADATA);
+    impl_read_bench!(read_leb128_weird_u64_metadata, U64, read_leb128_weird_u64, METADATA);
+    // impl_read_bench!(read_leb128_weird_u128_metadata, U128, read_leb128_weird_u128, METADATA);
+    // impl_read_bench!(read_leb128_weird_usize_metadata, Usize, read_leb128_weird_usize, METADATA);
+
+    // impl_read_bench!(read_leb128_weird_u16_query_cache, U16, read_leb128_weird_u16, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_weird_u32_query_cache, U32, read_leb128_weird_u32, QUERY_CACHE);
+    impl_read_bench!(read_leb128_weird_u64_query_cache, U64, read_leb128_weird_u64, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_weird_u128_query_cache, U128, read_leb128_weird_u128, QUERY_CACHE);
+    // impl_read_bench!(read_leb128_weird_usize_query_cache, Usize, read_leb128_weird_usize, QUERY_CACHE);
+}
 
 
 
+
+#[cfg(target_arch = "x86_64")]
 #[inline]
 #[target_feature(enable = "bmi2")]
 unsafe fn read_unsigned_leb128_simd_32(data: &[u8], start_position: usize) -> (u32, usize) {
-    use std::arch::x86_64::*;
+    use core::arch::x86_64::*;
 
     const PEXT_MASK: &[u64; 5] = &[
         0b0111_1111,
-        0b0111_11110111_1111,
-        0b0111_11110111_11110111_1111,
-        0b0111_11110111_11110111_11110111_1111,
-        0b0111_11110111_11110111_11110111_11110111_1111,
+        0b0111_1111_0111_1111,
+        0b0111_1111_0111_1111_0111_1111,
+        0b0111_1111_0111_1111_0111_1111_0111_1111,
+        0b0111_1111_0111_1111_0111_1111_0111_1111_0111_1111,
     ];
 
+    // The load below always takes 16 bytes, so the last values of a buffer
+    // are read the ordinary way.
+    if start_position + 16 > data.len() {
+        return read_leb128_ref_u32(data, start_position);
+    }
+
     unsafe {
-        let cmp = _mm_set1_epi8(128u8 as i8);
-        let data_simd = _mm_loadu_si128(data.as_ptr().offset(start_position as isize) as *const _);
-        let smaller = _mm_cmpgt_epi8(data_simd, cmp);
-        let mask = _mm_movemask_epi8(smaller);
-        let bytes = mask.trailing_zeros() as usize;
+        let data_simd = _mm_loadu_si128(data.as_ptr().add(start_position) as *const _);
+        // One bit per byte, set if the continuation bit is set.
+        let mask = _mm_movemask_epi8(data_simd);
+        let bytes = (!mask).trailing_zeros() as usize;
         let data0 = _mm_cvtsi128_si64(data_simd) as u64;
-        debug_assert!(bytes <= 5);
+        debug_assert!(bytes < PEXT_MASK.len());
         (_pext_u64(data0, *PEXT_MASK.get_unchecked(bytes)) as u32, bytes + 1)
     }
 }
 
-impl_read_bench!(read_leb128_simd_u32_dep_graph, U32, read_unsigned_leb128_simd_32, DEP_GRAPH);
-impl_read_bench!(read_leb128_simd_u32_query_cache, U32, read_unsigned_leb128_simd_32, QUERY_CACHE);
-impl_read_bench!(read_leb128_simd_u32_metadata, U32, read_unsigned_leb128_simd_32, METADATA);
+#[cfg(target_arch = "x86_64")]
+impl_read_bench!(read_leb128_simd_u32_dep_graph, U32, read_unsigned_leb128_simd_32, DEP_GRAPH, unsafe, "bmi2");
+#[cfg(target_arch = "x86_64")]
+impl_read_bench!(read_leb128_simd_u32_query_cache, U32, read_unsigned_leb128_simd_32, QUERY_CACHE, unsafe, "bmi2");
+#[cfg(target_arch = "x86_64")]
+impl_read_bench!(read_leb128_simd_u32_metadata, U32, read_unsigned_leb128_simd_32, METADATA, unsafe, "bmi2");
 
+#[cfg(target_arch = "x86_64")]
 #[inline]
 #[target_feature(enable = "bmi2")]
 unsafe fn read_unsigned_leb128_simd_usize(data: &[u8], start_position: usize) -> (usize, usize) {
-    use std::arch::x86_64::*;
+    use core::arch::x86_64::*;
 
     const PEXT_MASK: &[u64; 5] = &[
         0b0111_1111,
-        0b0111_11110111_1111,
-        0b0111_11110111_11110111_1111,
-        0b0111_11110111_11110111_11110111_1111,
-        0b0111_11110111_11110111_11110111_11110111_1111,
+        0b0111_1111_0111_1111,
+        0b0111_1111_0111_1111_0111_1111,
+        0b0111_1111_0111_1111_0111_1111_0111_1111,
+        0b0111_1111_0111_1111_0111_1111_0111_1111_0111_1111,
     ];
 
+    // The load below always takes 16 bytes, so the last values of a buffer
+    // are read the ordinary way.
+    if start_position + 16 > data.len() {
+        return read_leb128_ref_usize(data, start_position);
+    }
+
     unsafe {
-        let cmp = _mm_set1_epi8(128u8 as i8);
-        let data_simd = _mm_loadu_si128(data.as_ptr().offset(start_position as isize) as *const _);
-        let smaller = _mm_cmpgt_epi8(data_simd, cmp);
-        let mask = _mm_movemask_epi8(smaller);
-        let bytes = mask.trailing_zeros() as usize;
+        let data_simd = _mm_loadu_si128(data.as_ptr().add(start_position) as *const _);
+        // One bit per byte, set if the continuation bit is set.
+        let mask = _mm_movemask_epi8(data_simd);
+        let bytes = (!mask).trailing_zeros() as usize;
         let data0 = _mm_cvtsi128_si64(data_simd) as u64;
-        debug_assert!(bytes <= 5);
+        debug_assert!(bytes < PEXT_MASK.len());
         (_pext_u64(data0, *PEXT_MASK.get_unchecked(bytes)) as usize, bytes + 1)
     }
 }
 
 
-impl_read_bench!(read_leb128_simd_usize_dep_graph, Usize, read_unsigned_leb128_simd_usize, DEP_GRAPH);
-impl_read_bench!(read_leb128_simd_usize_query_cache, Usize, read_unsigned_leb128_simd_usize, QUERY_CACHE);
-impl_read_bench!(read_leb128_simd_usize_metadata, Usize, read_unsigned_leb128_simd_usize, METADATA);
-
+#[cfg(target_arch = "x86_64")]
+impl_read_bench!(read_leb128_simd_usize_dep_graph, Usize, read_unsigned_leb128_simd_usize, DEP_GRAPH, unsafe, "bmi2");
+#[cfg(target_arch = "x86_64")]
+impl_read_bench!(read_leb128_simd_usize_query_cache, Usize, read_unsigned_leb128_simd_usize, QUERY_CACHE, unsafe, "bmi2");
+#[cfg(target_arch = "x86_64")]
+impl_read_bench!(read_leb128_simd_usize_metadata, Usize, read_unsigned_leb128_simd_usize, METADATA, unsafe, "bmi2");
 
-#[inline]
-#[target_feature(enable = "bmi2")]
-unsafe fn read_unsigned_leb128_mmx_32(data: &[u8], start_position: usize) -> (u32, usize) {
-    use std::arch::x86_64::*;
 
-    const PEXT_MASK: &[u64; 5] = &[
-        0b0111_1111,
-        0b0111_11110111_1111,
-        0b0111_11110111_11110111_1111,
-        0b0111_11110111_11110111_11110111_1111,
-        0b0111_11110111_11110111_11110111_11110111_1111,
-    ];
 
-    unsafe {
-        let cmp = _mm_set1_pi8(128u8 as i8);
 
-        #[repr(packed)] struct Unaligned(u64);
+// VLQ, most significant group first -------------------------------------------
 
-        let word = (*(data.as_ptr().offset(start_position as isize) as *const Unaligned)).0;
+#[inline]
+pub fn write_vlq_u64(out: &mut Vec<u8>, start_position: usize, value: u64) -> usize {
+    let bits = 64 - (value | 1).leading_zeros() as usize;
+    let groups = bits.div_ceil(7);
 
-        let data_simd = mem::transmute(word);
-        let smaller = _mm_cmpgt_pi8(data_simd, cmp);
-        let mask = _mm_movemask_pi8(smaller);
-        let bytes = mask.trailing_zeros() as usize;
-        let data0: u64 = mem::transmute(data_simd);
-        debug_assert!(bytes <= 5);
-        (_pext_u64(data0, *PEXT_MASK.get_unchecked(bytes)) as u32, bytes + 1)
+    let mut position = start_position;
+    for i in (1 .. groups).rev() {
+        write_to_vec(out, position, ((value >> (i * 7)) as u8 & 0x7F) | 0x80);
+        position += 1;
     }
+
+    write_to_vec(out, position, value as u8 & 0x7F);
+
+    groups
 }
 
-impl_read_bench!(read_leb128_mmx_u32_dep_graph, U32, read_unsigned_leb128_mmx_32, DEP_GRAPH);
-impl_read_bench!(read_leb128_mmx_u32_query_cache, U32, read_unsigned_leb128_mmx_32, QUERY_CACHE);
-impl_read_bench!(read_leb128_mmx_u32_metadata, U32, read_unsigned_leb128_mmx_32, METADATA);
\ No newline at end of file
+#[inline]
+pub fn read_vlq_u64(data: &[u8], start_position: usize) -> (u64, usize) {
+    let mut result = 0;
+    let mut position = start_position;
+
+    loop {
+        let byte = data[position];
+        position += 1;
+        result = (result << 7) | (byte & 0x7F) as u64;
+        if (byte & 0x80) == 0 {
+            break;
+        }
+    }
+
+    (result, position - start_position)
+}
+
+impl_varint_codec!(VlqU64, write_vlq_u64, read_vlq_u64, u64);
+
+impl_bench!(write_vlq_u64_metadata, U64, VlqU64, METADATA);
+impl_bench!(write_vlq_u64_dep_graph, U64, VlqU64, DEP_GRAPH);
+impl_bench!(write_vlq_u64_query_cache, U64, VlqU64, QUERY_CACHE);
+
+impl_read_bench!(read_vlq_u64_metadata, U64, read_vlq_u64, METADATA, VlqU64);
+impl_read_bench!(read_vlq_u64_dep_graph, U64, read_vlq_u64, DEP_GRAPH, VlqU64);
+impl_read_bench!(read_vlq_u64_query_cache, U64, read_vlq_u64, QUERY_CACHE, VlqU64);
+
+#[test]
+fn vlq_round_trip() {
+    let mut values = vec![0, u64::MAX];
+    for shift in 0 .. 64 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        position += write_vlq_u64(&mut encoded, position, val);
+    }
+
+    let mut position = 0;
+    for &val in values.iter() {
+        let (decoded, read) = read_vlq_u64(&encoded, position);
+        assert_eq!(decoded, val);
+        position += read;
+    }
+
+    assert_eq!(position, encoded.len());
+}
+
+#[test]
+fn vlq_known_encodings() {
+    let mut out = Vec::new();
+    assert_eq!(write_vlq_u64(&mut out, 0, 0), 1);
+    assert_eq!(out, [0x00]);
+
+    let mut out = Vec::new();
+    assert_eq!(write_vlq_u64(&mut out, 0, 0x80), 2);
+    assert_eq!(out, [0x81, 0x00]);
+}
+
+
+// SQLite4 varint --------------------------------------------------------------
+//
+// See https://sqlite.org/src4/doc/trunk/www/varint.wiki. Values up to 240 fit
+// into the first byte, up to 67823 into three bytes, and everything larger is
+// stored as a big-endian integer of 3 to 8 bytes after a length byte.
+
+#[inline]
+pub fn write_sqlite4_varint_u64(out: &mut Vec<u8>, start_position: usize, value: u64) -> usize {
+    if value <= 240 {
+        write_to_vec(out, start_position, value as u8);
+        1
+    } else if value <= 2287 {
+        write_to_vec(out, start_position, ((value - 240) / 256 + 241) as u8);
+        write_to_vec(out, start_position + 1, ((value - 240) % 256) as u8);
+        2
+    } else if value <= 67823 {
+        write_to_vec(out, start_position, 249);
+        write_to_vec(out, start_position + 1, ((value - 2288) / 256) as u8);
+        write_to_vec(out, start_position + 2, ((value - 2288) % 256) as u8);
+        3
+    } else {
+        let bits = 64 - value.leading_zeros() as usize;
+        let bytes = bits.div_ceil(8);
+
+        write_to_vec(out, start_position, 247 + bytes as u8);
+
+        let value = value.to_be_bytes();
+        write_slice_to_vec_skewed(out, start_position + 1, &value[8 - bytes ..]);
+        bytes + 1
+    }
+}
+
+#[inline]
+pub fn read_sqlite4_varint_u64(data: &[u8], start_position: usize) -> (u64, usize) {
+    let a0 = data[start_position] as u64;
+
+    if a0 <= 240 {
+        (a0, 1)
+    } else if a0 <= 248 {
+        ((a0 - 241) * 256 + data[start_position + 1] as u64 + 240, 2)
+    } else if a0 == 249 {
+        (2288 + 256 * data[start_position + 1] as u64 + data[start_position + 2] as u64, 3)
+    } else {
+        let bytes = (a0 - 247) as usize;
+        let mut result = 0;
+
+        for &byte in &data[start_position + 1 .. start_position + 1 + bytes] {
+            result = (result << 8) | byte as u64;
+        }
+
+        (result, bytes + 1)
+    }
+}
+
+impl_varint_codec!(Sqlite4U64, write_sqlite4_varint_u64, read_sqlite4_varint_u64, u64);
+
+impl_bench!(write_sqlite4_u64_metadata, U64, Sqlite4U64, METADATA);
+impl_bench!(write_sqlite4_u64_dep_graph, U64, Sqlite4U64, DEP_GRAPH);
+impl_bench!(write_sqlite4_u64_query_cache, U64, Sqlite4U64, QUERY_CACHE);
+
+impl_read_bench!(read_sqlite4_u64_metadata, U64, read_sqlite4_varint_u64, METADATA, Sqlite4U64);
+impl_read_bench!(read_sqlite4_u64_dep_graph, U64, read_sqlite4_varint_u64, DEP_GRAPH, Sqlite4U64);
+impl_read_bench!(read_sqlite4_u64_query_cache, U64, read_sqlite4_varint_u64, QUERY_CACHE, Sqlite4U64);
+
+#[test]
+fn sqlite4_varint_documented_examples() {
+    let cases: &[(u64, &[u8])] = &[
+        (0, &[0]),
+        (240, &[240]),
+        (241, &[241, 1]),
+        (2287, &[248, 255]),
+        (2288, &[249, 0, 0]),
+        (67823, &[249, 255, 255]),
+        (67824, &[250, 0x01, 0x08, 0xF0]),
+        (16777215, &[250, 0xFF, 0xFF, 0xFF]),
+        (16777216, &[251, 0x01, 0x00, 0x00, 0x00]),
+        (u64::MAX, &[255, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+    ];
+
+    for &(value, expected) in cases {
+        let mut out = Vec::new();
+        assert_eq!(write_sqlite4_varint_u64(&mut out, 0, value), expected.len());
+        assert_eq!(&out[..], expected);
+        assert_eq!(read_sqlite4_varint_u64(&out, 0), (value, expected.len()));
+    }
+}
+
+#[test]
+fn sqlite4_varint_round_trip() {
+    let mut values = vec![0, u64::MAX];
+    for shift in 0 .. 64 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        position += write_sqlite4_varint_u64(&mut encoded, position, val);
+    }
+
+    let mut position = 0;
+    for &val in values.iter() {
+        let (decoded, read) = read_sqlite4_varint_u64(&encoded, position);
+        assert_eq!(decoded, val);
+        position += read;
+    }
+
+    assert_eq!(position, encoded.len());
+}
+
+
+// Fixed width without continuation bits ---------------------------------------
+//
+// Every value takes exactly `width_bytes` bytes, little-endian. The width is
+// chosen once per dataset, so decoding needs no branches at all.
+
+#[inline]
+pub fn write_packed_u64(out: &mut Vec<u8>, start_position: usize, value: u64, width_bytes: usize) -> usize {
+    debug_assert!((1 ..= 8).contains(&width_bytes));
+    debug_assert!(width_bytes == 8 || (value >> (width_bytes * 8)) == 0);
+
+    let value = value.to_le_bytes();
+    write_slice_to_vec_skewed(out, start_position, &value[.. width_bytes]);
+    width_bytes
+}
+
+#[inline]
+pub fn read_packed_u64(data: &[u8], start_position: usize, width_bytes: usize) -> (u64, usize) {
+    let mut bytes = [0u8; 8];
+    bytes[.. width_bytes].copy_from_slice(&data[start_position .. start_position + width_bytes]);
+    (u64::from_le_bytes(bytes), width_bytes)
+}
+
+pub fn min_width_for(values: &[u64]) -> usize {
+    let max = values.iter().cloned().max().unwrap_or(0);
+    let bits = 64 - (max | 1).leading_zeros() as usize;
+    bits.div_ceil(8)
+}
+
+macro_rules! impl_packed_bench {
+    ($write_bench:ident, $read_bench:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $write_bench {
+            use super::*;
+
+            impl_bench_data!(@values $variant, $data);
+
+            pub fn widen(test_data: &[variant_ty!($variant)]) -> Vec<u64> {
+                test_data.iter().map(|&val| val as u64).collect()
+            }
+
+            #[inline]
+            pub fn encode_all(test_data: &[u64], width: usize) -> Vec<u8> {
+                let mut output = Vec::with_capacity(test_data.len() * width);
+                let mut position = 0;
+
+                for &val in test_data.iter() {
+                    position += write_packed_u64(&mut output, position, val, width);
+                }
+
+                output
+            }
+
+            #[inline]
+            pub fn decode_all(encoded: &[u8], test_data: &[u64], width: usize, verify: bool) {
+                let mut position = 0;
+                for i in 0 .. test_data.len() {
+                    let (val, count) = read_packed_u64(encoded, position, width);
+                    ::core::hint::black_box(val);
+                    position += count;
+
+                    if verify {
+                        assert_eq!(val, test_data[i], "value #{}", i);
+                    }
+                }
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = widen(&test_data());
+                let width = min_width_for(&test_data);
+                let encoded = encode_all(&test_data, width);
+                assert_eq!(encoded.len(), test_data.len() * width);
+                decode_all(&encoded, &test_data, width, true);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $write_bench(b: &mut test::Bencher) {
+
+            let test_data = $write_bench::test_data();
+            b.bytes = $write_bench::input_bytes(&test_data);
+
+            let test_data = $write_bench::widen(&test_data);
+            let width = min_width_for(&test_data);
+
+            let mut size = 0;
+
+            b.iter(|| {
+                size = $write_bench::encode_all(&test_data, width).len();
+            });
+
+            if b.bytes > 0 {
+                print!("width: {}, size: {}%, ", width, (100 * size) / (b.bytes as usize));
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $read_bench(b: &mut test::Bencher) {
+
+            let test_data = $write_bench::test_data();
+            b.bytes = $write_bench::input_bytes(&test_data);
+
+            let test_data = $write_bench::widen(&test_data);
+            let width = min_width_for(&test_data);
+            let encoded = $write_bench::encode_all(&test_data, width);
+
+            b.iter(|| {
+                $write_bench::decode_all(&encoded, &test_data, width, false);
+            });
+        }
+    )
+}
+
+impl_packed_bench!(write_packed_u32_metadata, read_packed_u32_metadata, U32, METADATA);
+impl_packed_bench!(write_packed_u32_dep_graph, read_packed_u32_dep_graph, U32, DEP_GRAPH);
+impl_packed_bench!(write_packed_u32_query_cache, read_packed_u32_query_cache, U32, QUERY_CACHE);
+
+impl_packed_bench!(write_packed_u64_metadata, read_packed_u64_metadata, U64, METADATA);
+impl_packed_bench!(write_packed_u64_dep_graph, read_packed_u64_dep_graph, U64, DEP_GRAPH);
+impl_packed_bench!(write_packed_u64_query_cache, read_packed_u64_query_cache, U64, QUERY_CACHE);
+
+#[test]
+fn packed_min_width() {
+    assert_eq!(min_width_for(&[]), 1);
+    assert_eq!(min_width_for(&[0, 0xFF]), 1);
+    assert_eq!(min_width_for(&[0x100, 3]), 2);
+    assert_eq!(min_width_for(&[0xFF_FFFF]), 3);
+    assert_eq!(min_width_for(&[1, u64::MAX]), 8);
+}
+
+#[test]
+fn packed_round_trip() {
+    let values = [0, 1, 0x1234, 0xFF_FFFF, 0x80_0000];
+    let width = min_width_for(&values);
+    assert_eq!(width, 3);
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        position += write_packed_u64(&mut encoded, position, val, width);
+    }
+
+    assert_eq!(encoded.len(), values.len() * width);
+
+    let mut position = 0;
+    for &val in values.iter() {
+        let (decoded, read) = read_packed_u64(&encoded, position, width);
+        assert_eq!(decoded, val);
+        position += read;
+    }
+}
+
+
+// Encode followed by decode ---------------------------------------------------
+
+macro_rules! impl_roundtrip_bench {
+    ($bench_name:ident, $variant:ident, $write:ident, $read:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!(@values $variant, $data);
+
+            #[inline]
+            pub fn roundtrip(test_data: &[variant_ty!($variant)], output: &mut Vec<u8>, verify: bool) {
+                output.clear();
+
+                let mut position = 0;
+                for &val in test_data.iter() {
+                    position += $write(output, position, val);
+                }
+
+                let mut position = 0;
+                for i in 0 .. test_data.len() {
+                    let (val, count) = $read(output, position);
+                    ::core::hint::black_box(val);
+                    position += count;
+
+                    if verify {
+                        assert_eq!(val, test_data[i], "value #{}", i);
+                    }
+                }
+
+                ::core::hint::black_box(position);
+            }
+
+            #[test]
+            fn run_once() {
+                roundtrip(&test_data(), &mut Vec::new(), true);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let capacity = ((b.bytes * 135) / 100) as usize;
+            let mut output = Vec::with_capacity(capacity);
+
+            b.iter(|| {
+                $bench_name::roundtrip(&test_data, &mut output, false);
+            });
+        }
+    )
+}
+
+impl_roundtrip_bench!(roundtrip_leb128d_unsafe_u64_metadata, U64, write_leb128d_u64, read_leb128_unsafe_u64, METADATA);
+impl_roundtrip_bench!(roundtrip_leb128d_unsafe_u64_dep_graph, U64, write_leb128d_u64, read_leb128_unsafe_u64, DEP_GRAPH);
+impl_roundtrip_bench!(roundtrip_leb128d_unsafe_u64_query_cache, U64, write_leb128d_u64, read_leb128_unsafe_u64, QUERY_CACHE);
+
+impl_roundtrip_bench!(roundtrip_leb128c_ref_u64_metadata, U64, write_leb128c_u64, read_leb128_ref_u64, METADATA);
+impl_roundtrip_bench!(roundtrip_leb128c_ref_u64_dep_graph, U64, write_leb128c_u64, read_leb128_ref_u64, DEP_GRAPH);
+impl_roundtrip_bench!(roundtrip_leb128c_ref_u64_query_cache, U64, write_leb128c_u64, read_leb128_ref_u64, QUERY_CACHE);
+
+// Unlike the roundtrip benches above, every value is read back right after it
+// has been written, so each decode has to wait for the bytes of the store
+// just before it instead of running over a finished buffer.
+macro_rules! impl_interleaved_bench {
+    ($bench_name:ident, $variant:ident, $write:ident, $read:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!(@values $variant, $data);
+
+            #[inline]
+            pub fn interleaved(test_data: &[variant_ty!($variant)], output: &mut Vec<u8>) {
+                output.clear();
+
+                let mut position = 0;
+                for &val in test_data.iter() {
+                    let written = $write(output, position, val);
+                    let (decoded, read) = $read(output, position);
+                    debug_assert_eq!((decoded, read), (val, written));
+                    ::core::hint::black_box(decoded);
+                    position += read;
+                }
+
+                ::core::hint::black_box(position);
+            }
+
+            // Relies on the `debug_assert_eq!` in the loop.
+            #[test]
+            fn run_once() {
+                interleaved(&test_data(), &mut Vec::new());
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let capacity = ((b.bytes * 135) / 100) as usize;
+            let mut output = Vec::with_capacity(capacity);
+
+            b.iter(|| {
+                $bench_name::interleaved(&test_data, &mut output);
+            });
+        }
+    )
+}
+
+impl_interleaved_bench!(interleaved_leb128d_unsafe_u64_metadata, U64, write_leb128d_u64, read_leb128_unsafe_u64, METADATA);
+impl_interleaved_bench!(interleaved_leb128d_unsafe_u64_dep_graph, U64, write_leb128d_u64, read_leb128_unsafe_u64, DEP_GRAPH);
+impl_interleaved_bench!(interleaved_leb128d_unsafe_u64_query_cache, U64, write_leb128d_u64, read_leb128_unsafe_u64, QUERY_CACHE);
+
+
+// Reading from a stream -------------------------------------------------------
+
+#[cfg(feature = "std")]
+pub fn read_leb128_u64_io<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut byte = [0u8; 1];
+
+    for i in 0 .. leb128_size!(u64) {
+        r.read_exact(&mut byte)?;
+
+        // The tenth group only has room for the single top bit of a u64.
+        if i == leb128_size!(u64) - 1 && byte[0] > 0x01 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "leb128 value overflows u64"));
+        }
+
+        result |= ((byte[0] & 0x7F) as u64) << shift;
+        if (byte[0] & 0x80) == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+    }
+
+    Err(io::Error::new(io::ErrorKind::InvalidData, "leb128 value longer than 10 bytes"))
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn read_leb128_io_cursor() {
+    let data = [0x00, 0x7F, 0x80, 0x01, 0xE5, 0x8E, 0x26];
+    let mut cursor = io::Cursor::new(&data[..]);
+
+    assert_eq!(read_leb128_u64_io(&mut cursor).unwrap(), 0);
+    assert_eq!(read_leb128_u64_io(&mut cursor).unwrap(), 127);
+    assert_eq!(read_leb128_u64_io(&mut cursor).unwrap(), 128);
+    assert_eq!(read_leb128_u64_io(&mut cursor).unwrap(), 624485);
+    assert_eq!(cursor.position(), data.len() as u64);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn read_leb128_io_truncated() {
+    let data = [0xE5, 0x8E];
+    let mut cursor = io::Cursor::new(&data[..]);
+
+    let err = read_leb128_u64_io(&mut cursor).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn read_leb128_io_overflow() {
+    let data = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02];
+    let mut cursor = io::Cursor::new(&data[..]);
+
+    let err = read_leb128_u64_io(&mut cursor).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    let data = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+    let mut cursor = io::Cursor::new(&data[..]);
+
+    assert_eq!(read_leb128_u64_io(&mut cursor).unwrap(), u64::MAX);
+}
+
+
+// Overwriting instead of appending --------------------------------------------
+//
+// The output is pre-filled with the encoded size of the whole dataset, so every
+// write starts before `output.len()` and `write_slice_to_vec_skewed` always
+// takes its cold path.
+
+macro_rules! impl_overwrite_bench {
+    ($bench_name:ident, $variant:ident, $encoder:ty, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data, $encoder);
+
+            #[inline]
+            pub fn overwrite(test_data: &[variant_ty!($variant)], output: &mut Vec<u8>) -> usize {
+                let mut position = 0;
+
+                for &val in test_data.iter() {
+                    position += <$encoder as VarintEncoder>::encode(output, position, val as u128);
+                }
+
+                position
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode(&test_data);
+                let mut output = encoded.clone();
+
+                assert_eq!(overwrite(&test_data, &mut output), encoded.len());
+                assert_eq!(output, encoded);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let mut output = $bench_name::encode(&test_data);
+            let size = output.len();
+
+            b.iter(|| {
+                test::black_box($bench_name::overwrite(&test_data, &mut output));
+            });
+
+            assert_eq!(output.len(), size);
+        }
+    )
+}
+
+impl_overwrite_bench!(overwrite_raw_u32_slice_metadata, U32, RawU32Slice, METADATA);
+impl_overwrite_bench!(overwrite_raw_u32_slice_dep_graph, U32, RawU32Slice, DEP_GRAPH);
+impl_overwrite_bench!(overwrite_raw_u32_slice_query_cache, U32, RawU32Slice, QUERY_CACHE);
+
+impl_overwrite_bench!(overwrite_raw_u32_skewed_metadata, U32, RawU32Skewed, METADATA);
+impl_overwrite_bench!(overwrite_raw_u32_skewed_dep_graph, U32, RawU32Skewed, DEP_GRAPH);
+impl_overwrite_bench!(overwrite_raw_u32_skewed_query_cache, U32, RawU32Skewed, QUERY_CACHE);
+
+impl_overwrite_bench!(overwrite_raw_u64_slice_metadata, U64, RawU64Slice, METADATA);
+impl_overwrite_bench!(overwrite_raw_u64_slice_dep_graph, U64, RawU64Slice, DEP_GRAPH);
+impl_overwrite_bench!(overwrite_raw_u64_slice_query_cache, U64, RawU64Slice, QUERY_CACHE);
+
+impl_overwrite_bench!(overwrite_raw_u64_skewed_metadata, U64, RawU64Skewed, METADATA);
+impl_overwrite_bench!(overwrite_raw_u64_skewed_dep_graph, U64, RawU64Skewed, DEP_GRAPH);
+impl_overwrite_bench!(overwrite_raw_u64_skewed_query_cache, U64, RawU64Skewed, QUERY_CACHE);
+
+// Worst case for the `start_position == output.len()` branch in
+// `write_slice_to_vec_skewed`: every value is appended and then immediately
+// written again into the same slot, so the branch flips on every call.
+macro_rules! impl_alternating_bench {
+    ($bench_name:ident, $variant:ident, $encoder:ty, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data, $encoder);
+
+            #[inline]
+            pub fn alternating(test_data: &[variant_ty!($variant)]) -> Vec<u8> {
+                let mut output = Vec::with_capacity(test_data.len() * mem::size_of::<variant_ty!($variant)>());
+                let mut position = 0;
+
+                for &val in test_data.iter() {
+                    <$encoder as VarintEncoder>::encode(&mut output, position, val as u128);
+                    position += <$encoder as VarintEncoder>::encode(&mut output, position, val as u128);
+                }
+
+                output
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                assert_eq!(alternating(&test_data), encode(&test_data));
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            b.iter(|| {
+                test::black_box($bench_name::alternating(&test_data));
+            });
+        }
+    )
+}
+
+impl_alternating_bench!(alternating_raw_u64_slice_metadata, U64, RawU64Slice, METADATA);
+impl_alternating_bench!(alternating_raw_u64_slice_dep_graph, U64, RawU64Slice, DEP_GRAPH);
+impl_alternating_bench!(alternating_raw_u64_slice_query_cache, U64, RawU64Slice, QUERY_CACHE);
+
+impl_alternating_bench!(alternating_raw_u64_skewed_metadata, U64, RawU64Skewed, METADATA);
+impl_alternating_bench!(alternating_raw_u64_skewed_dep_graph, U64, RawU64Skewed, DEP_GRAPH);
+impl_alternating_bench!(alternating_raw_u64_skewed_query_cache, U64, RawU64Skewed, QUERY_CACHE);
+
+
+// Heterogeneous streams -------------------------------------------------------
+//
+// Values stay in the order they appear in the dataset. Each one is prefixed
+// with a tag byte saying which type follows, so decoding has to dispatch on
+// data it has just read. Single bytes are stored as is, everything else as
+// leb128 of the same width. Signed values are simply reinterpreted as
+// unsigned here; `write_value` below zigzag encodes them instead.
+
+fn mixed_tag(value: &Value) -> u8 {
+    match *value {
+        Value::U8(_) => 0,
+        Value::U16(_) => 1,
+        Value::U32(_) => 2,
+        Value::U64(_) => 3,
+        Value::U128(_) => 4,
+        Value::Usize(_) => 5,
+        Value::I8(_) => 6,
+        Value::I16(_) => 7,
+        Value::I32(_) => 8,
+        Value::I64(_) => 9,
+        Value::I128(_) => 10,
+        Value::Isize(_) => 11,
+    }
+}
+
+fn value_size(value: &Value) -> usize {
+    match *value {
+        Value::U8(_) | Value::I8(_) => 1,
+        Value::U16(_) | Value::I16(_) => 2,
+        Value::U32(_) | Value::I32(_) => 4,
+        Value::U64(_) | Value::I64(_) => 8,
+        Value::U128(_) | Value::I128(_) => 16,
+        Value::Usize(_) | Value::Isize(_) => mem::size_of::<usize>(),
+    }
+}
+
+// Signed values are either reinterpreted as unsigned or zigzag encoded,
+// depending on `zigzag`. Callers pass a constant, so the check folds away.
+#[inline]
+fn write_tagged(out: &mut Vec<u8>, start_position: usize, value: &Value, zigzag: bool) -> usize {
+    macro_rules! signed {
+        ($v:expr, $encode:ident, $uint_ty:ident) => (
+            if zigzag { $encode($v) } else { $v as $uint_ty }
+        )
+    }
+
+    write_to_vec(out, start_position, mixed_tag(value));
+
+    let position = start_position + 1;
+
+    1 + match *value {
+        Value::U8(v) => { write_to_vec(out, position, v); 1 }
+        Value::U16(v) => write_leb128c_u16(out, position, v),
+        Value::U32(v) => write_leb128c_u32(out, position, v),
+        Value::U64(v) => write_leb128c_u64(out, position, v),
+        Value::U128(v) => write_leb128c_u128(out, position, v),
+        Value::Usize(v) => write_leb128c_usize(out, position, v),
+        Value::I8(v) => { write_to_vec(out, position, signed!(v, zigzag_encode_i8, u8)); 1 }
+        Value::I16(v) => write_leb128c_u16(out, position, signed!(v, zigzag_encode_i16, u16)),
+        Value::I32(v) => write_leb128c_u32(out, position, signed!(v, zigzag_encode_i32, u32)),
+        Value::I64(v) => write_leb128c_u64(out, position, signed!(v, zigzag_encode_i64, u64)),
+        Value::I128(v) => write_leb128c_u128(out, position, signed!(v, zigzag_encode_i128, u128)),
+        Value::Isize(v) => write_leb128c_usize(out, position, signed!(v, zigzag_encode_isize, usize)),
+    }
+}
+
+#[inline]
+fn read_tagged(data: &[u8], start_position: usize, zigzag: bool) -> (Value, usize) {
+    macro_rules! signed {
+        ($v:expr, $decode:ident, $int_ty:ident) => (
+            if zigzag { $decode($v) } else { $v as $int_ty }
+        )
+    }
+
+    let position = start_position + 1;
+
+    let (value, read) = match data[start_position] {
+        0 => (Value::U8(data[position]), 1),
+        1 => { let (v, n) = read_leb128_ref_u16(data, position); (Value::U16(v), n) }
+        2 => { let (v, n) = read_leb128_ref_u32(data, position); (Value::U32(v), n) }
+        3 => { let (v, n) = read_leb128_ref_u64(data, position); (Value::U64(v), n) }
+        4 => { let (v, n) = read_leb128_ref_u128(data, position); (Value::U128(v), n) }
+        5 => { let (v, n) = read_leb128_ref_usize(data, position); (Value::Usize(v), n) }
+        6 => (Value::I8(signed!(data[position], zigzag_decode_i8, i8)), 1),
+        7 => { let (v, n) = read_leb128_ref_u16(data, position); (Value::I16(signed!(v, zigzag_decode_i16, i16)), n) }
+        8 => { let (v, n) = read_leb128_ref_u32(data, position); (Value::I32(signed!(v, zigzag_decode_i32, i32)), n) }
+        9 => { let (v, n) = read_leb128_ref_u64(data, position); (Value::I64(signed!(v, zigzag_decode_i64, i64)), n) }
+        10 => { let (v, n) = read_leb128_ref_u128(data, position); (Value::I128(signed!(v, zigzag_decode_i128, i128)), n) }
+        11 => { let (v, n) = read_leb128_ref_usize(data, position); (Value::Isize(signed!(v, zigzag_decode_isize, isize)), n) }
+        tag => panic!("unknown tag {}", tag),
+    };
+
+    (value, read + 1)
+}
+
+#[inline]
+fn write_mixed_leb128(out: &mut Vec<u8>, start_position: usize, value: &Value) -> usize {
+    write_tagged(out, start_position, value, false)
+}
+
+#[inline]
+fn read_mixed_leb128(data: &[u8], start_position: usize) -> (Value, usize) {
+    read_tagged(data, start_position, false)
+}
+
+macro_rules! impl_mixed_bench {
+    ($write_bench:ident, $read_bench:ident, $write:ident, $read:ident, $data:ident) => (
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $write_bench(b: &mut test::Bencher) {
+            let test_data = load_test_data($data);
+
+            b.bytes = test_data.iter().map(value_size).sum::<usize>() as u64;
+
+            let mut size = 0;
+            let capacity = ((b.bytes * 135) / 100) as usize;
+
+            b.iter(|| {
+                let mut output = Vec::with_capacity(capacity);
+                let mut position = 0;
+
+                for val in test_data.iter() {
+                    position += $write(&mut output, position, val);
+                }
+
+                size = position;
+            });
+
+            if b.bytes > 0 {
+                print!("size: {}%, ", (100 * size) / (b.bytes as usize));
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $read_bench(b: &mut test::Bencher) {
+            let test_data = load_test_data($data);
+
+            b.bytes = test_data.iter().map(value_size).sum::<usize>() as u64;
+
+            let mut encoded = Vec::new();
+
+            for val in test_data.iter() {
+                let pos = encoded.len();
+                $write(&mut encoded, pos, val);
+            }
+
+            b.iter(|| {
+                let mut position = 0;
+                for _ in 0 .. test_data.len() {
+                    let (val, count) = $read(&encoded, position);
+                    test::black_box(val);
+                    position += count;
+                }
+            });
+        }
+    )
+}
+
+impl_mixed_bench!(write_mixed_leb128_metadata, read_mixed_leb128_metadata,
+                  write_mixed_leb128, read_mixed_leb128, METADATA);
+impl_mixed_bench!(write_mixed_leb128_dep_graph, read_mixed_leb128_dep_graph,
+                  write_mixed_leb128, read_mixed_leb128, DEP_GRAPH);
+impl_mixed_bench!(write_mixed_leb128_query_cache, read_mixed_leb128_query_cache,
+                  write_mixed_leb128, read_mixed_leb128, QUERY_CACHE);
+
+// Same layout and tags, but signed values are zigzag encoded so that small
+// negative numbers stay short instead of taking the maximum length. Zigzag maps
+// 0, -1, 1, -2, ... to 0, 1, 2, 3, ... in the unsigned type of the same width.
+
+macro_rules! impl_zigzag {
+    ($encode:ident, $decode:ident, $int_ty:ident, $uint_ty:ident) => (
+        #[inline]
+        pub fn $encode(value: $int_ty) -> $uint_ty {
+            ((value << 1) ^ (value >> (mem::size_of::<$int_ty>() * 8 - 1))) as $uint_ty
+        }
+
+        #[inline]
+        pub fn $decode(value: $uint_ty) -> $int_ty {
+            ((value >> 1) as $int_ty) ^ -((value & 1) as $int_ty)
+        }
+    )
+}
+
+impl_zigzag!(zigzag_encode_i8, zigzag_decode_i8, i8, u8);
+impl_zigzag!(zigzag_encode_i16, zigzag_decode_i16, i16, u16);
+impl_zigzag!(zigzag_encode_i32, zigzag_decode_i32, i32, u32);
+impl_zigzag!(zigzag_encode_i64, zigzag_decode_i64, i64, u64);
+impl_zigzag!(zigzag_encode_i128, zigzag_decode_i128, i128, u128);
+impl_zigzag!(zigzag_encode_isize, zigzag_decode_isize, isize, usize);
+
+#[test]
+fn zigzag_extremes() {
+    assert_eq!(zigzag_encode_i8(i8::MIN), u8::MAX);
+    assert_eq!(zigzag_encode_i8(i8::MAX), u8::MAX - 1);
+    assert_eq!(zigzag_encode_i16(-1), 1);
+    assert_eq!(zigzag_encode_i32(i32::MIN), u32::MAX);
+    assert_eq!(zigzag_encode_i128(i128::MIN), u128::MAX);
+    assert_eq!(zigzag_encode_isize(isize::MIN), usize::MAX);
+
+    assert_eq!(zigzag_decode_i8(u8::MAX), i8::MIN);
+    assert_eq!(zigzag_decode_i16(1), -1);
+    assert_eq!(zigzag_decode_i32(u32::MAX - 1), i32::MAX);
+    assert_eq!(zigzag_decode_i128(u128::MAX), i128::MIN);
+    assert_eq!(zigzag_decode_isize(usize::MAX), isize::MIN);
+}
+
+#[inline]
+pub fn write_value(out: &mut Vec<u8>, pos: usize, v: &Value) -> usize {
+    write_tagged(out, pos, v, true)
+}
+
+#[inline]
+pub fn read_value(data: &[u8], pos: usize) -> (Value, usize) {
+    read_tagged(data, pos, true)
+}
+
+impl_mixed_bench!(write_value_metadata, read_value_metadata, write_value, read_value, METADATA);
+impl_mixed_bench!(write_value_dep_graph, read_value_dep_graph, write_value, read_value, DEP_GRAPH);
+impl_mixed_bench!(write_value_query_cache, read_value_query_cache, write_value, read_value, QUERY_CACHE);
+
+#[test]
+fn value_round_trip() {
+    let values = [
+        Value::U8(0xFF),
+        Value::U16(0x1234),
+        Value::U32(u32::MAX),
+        Value::U64(300),
+        Value::U128(u128::MAX),
+        Value::Usize(usize::MAX),
+        Value::I8(i8::MIN),
+        Value::I16(-1),
+        Value::I32(i32::MAX),
+        Value::I64(i64::MIN),
+        Value::I128(-300),
+        Value::Isize(isize::MIN),
+    ];
+
+    // `write_value`/`read_value` and `write_mixed_leb128`/`read_mixed_leb128`.
+    for &zigzag in &[true, false] {
+        let mut encoded = Vec::new();
+        for value in values.iter() {
+            let pos = encoded.len();
+            write_tagged(&mut encoded, pos, value, zigzag);
+        }
+
+        let mut position = 0;
+        for value in values.iter() {
+            let (decoded, len) = read_tagged(&encoded, position, zigzag);
+            assert_eq!(decoded, *value);
+            position += len;
+        }
+        assert_eq!(position, encoded.len());
+    }
+
+    // A small negative number takes one payload byte, not the full width.
+    let mut out = Vec::new();
+    assert_eq!(write_value(&mut out, 0, &Value::I64(-1)), 2);
+    assert_eq!(out, [9, 0x01]);
+    assert_eq!(write_mixed_leb128(&mut out, 0, &Value::I64(-1)), 11);
+}
+
+
+// leb128 with the one and two byte cases taken out of the loop ----------------
+
+#[inline]
+pub fn write_leb128_fastpath_u32(out: &mut Vec<u8>, start_position: usize, value: u32) -> usize {
+    if value < 0x80 {
+        write_to_vec(out, start_position, value as u8);
+        1
+    } else if value < 0x4000 {
+        write_to_vec(out, start_position, (value as u8) | 0x80);
+        write_to_vec(out, start_position + 1, (value >> 7) as u8);
+        2
+    } else {
+        write_leb128c_u32(out, start_position, value)
+    }
+}
+
+impl_varint_encoder!(Leb128FastpathU32, write_leb128_fastpath_u32, u32);
+
+impl_bench!(write_leb128_fastpath_u32_metadata, U32, Leb128FastpathU32, METADATA);
+impl_bench!(write_leb128_fastpath_u32_dep_graph, U32, Leb128FastpathU32, DEP_GRAPH);
+impl_bench!(write_leb128_fastpath_u32_query_cache, U32, Leb128FastpathU32, QUERY_CACHE);
+
+#[test]
+fn leb128_fastpath_matches_leb128c() {
+    let mut values: Vec<u32> = (0 .. 0x10000).collect();
+    values.extend_from_slice(&[0x1F_FFFF, 0x20_0000, 0xFFF_FFFF, 0x1000_0000, u32::MAX]);
+
+    let mut expected = Vec::new();
+    let mut actual = Vec::new();
+
+    for &value in values.iter() {
+        let pos = expected.len();
+        let expected_len = write_leb128c_u32(&mut expected, pos, value);
+        let actual_len = write_leb128_fastpath_u32(&mut actual, pos, value);
+        assert_eq!(actual_len, expected_len, "value: {}", value);
+    }
+
+    assert_eq!(actual, expected);
+}
+
+
+#[inline]
+pub fn read_leb128_u64_fast(data: &[u8], start_position: usize) -> (u64, usize) {
+    #[inline(never)]
+    #[cold]
+    fn slow(data: &[u8], start_position: usize) -> (u64, usize) {
+        read_leb128_ref_u64(data, start_position)
+    }
+
+    let first = data[start_position];
+
+    if first < 0x80 {
+        (first as u64, 1)
+    } else {
+        slow(data, start_position)
+    }
+}
+
+impl_varint_decoder!(Leb128FastU64, read_leb128_u64_fast);
+
+impl_read_bench!(read_leb128_fast_u64_query_cache, U64, read_leb128_u64_fast, QUERY_CACHE);
+impl_read_bench!(read_leb128_fast_usize_query_cache, Usize, read_leb128_u64_fast, QUERY_CACHE);
+
+#[cfg(feature = "std")]
+#[test]
+fn read_leb128_u64_fast_matches_ref() {
+    let mut encoded = Vec::new();
+
+    for &name in &[METADATA, DEP_GRAPH, QUERY_CACHE] {
+        for entry in load_test_data(name).iter() {
+            let value = match *entry {
+                Value::U8(v) => v as u64,
+                Value::U16(v) => v as u64,
+                Value::U32(v) => v as u64,
+                Value::U64(v) => v,
+                Value::Usize(v) => v as u64,
+                _ => continue,
+            };
+
+            let pos = encoded.len();
+            write_leb128c_u64(&mut encoded, pos, value);
+        }
+    }
+
+    let mut position = 0;
+    while position < encoded.len() {
+        let expected = read_leb128_ref_u64(&encoded, position);
+        assert_eq!(read_leb128_u64_fast(&encoded, position), expected);
+        position += expected.1;
+    }
+}
+
+// Shape of the test data ------------------------------------------------------
+//
+// Run with `cargo test print_dataset_histogram -- --nocapture` to see how many
+// values of each type need how many bytes in leb128.
+
+#[cfg(feature = "std")]
+#[test]
+fn print_dataset_histogram() {
+    for &name in &[METADATA, DEP_GRAPH, QUERY_CACHE] {
+        let test_data = load_test_data(name);
+
+        for &ty in &["u32", "u64", "usize"] {
+            let mut buckets = [0usize; 10];
+
+            for entry in test_data.iter() {
+                let value = match (ty, entry) {
+                    ("u32", &Value::U32(v)) => v as u64,
+                    ("u64", &Value::U64(v)) => v,
+                    ("usize", &Value::Usize(v)) => v as u64,
+                    _ => continue,
+                };
+
+                let bits = 64 - value.leading_zeros() as usize;
+                let len = cmp::max(1, bits.div_ceil(7));
+                buckets[len - 1] += 1;
+            }
+
+            print!("{} {:>5}:", name, ty);
+            for (i, count) in buckets.iter().enumerate() {
+                print!(" {}: {:>7}", i + 1, count);
+            }
+            println!();
+        }
+    }
+}
+
+
+// Run with `cargo test encoding_size_comparison -- --nocapture` for the total
+// number of bytes each scheme needs per dataset and type, relative to raw.
+
+fn encoded_size<E: VarintEncoder>(values: &[u128]) -> usize {
+    let mut out = Vec::new();
+    let mut position = 0;
+    for &val in values {
+        position += E::encode(&mut out, position, val);
+    }
+    position
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn encoding_size_comparison() {
+    println!("dataset, type, scheme, total_bytes, pct_of_raw");
+
+    for &name in &[METADATA, DEP_GRAPH, QUERY_CACHE] {
+        let test_data = load_test_data(name);
+
+        for &ty in &["u16", "u32", "u64", "u128", "usize"] {
+            let values: Vec<u128> = test_data.iter().filter_map(|entry| {
+                match (ty, entry) {
+                    ("u16", &Value::U16(v)) => Some(v as u128),
+                    ("u32", &Value::U32(v)) => Some(v as u128),
+                    ("u64", &Value::U64(v)) => Some(v as u128),
+                    ("u128", &Value::U128(v)) => Some(v),
+                    ("usize", &Value::Usize(v)) => Some(v as u128),
+                    _ => None,
+                }
+            }).collect();
+
+            if values.is_empty() {
+                continue
+            }
+
+            // lesqlite and prefix only come in u32, usize and u128 flavors, so
+            // u64 uses the usize one wherever that is wide enough.
+            let (int_size, lesqlite, prefix) = match ty {
+                "u16" => (2, encoded_size::<LesqliteU32>(&values), encoded_size::<PrefixU32>(&values)),
+                "u32" => (4, encoded_size::<LesqliteU32>(&values), encoded_size::<PrefixU32>(&values)),
+                "u64" if mem::size_of::<usize>() == 8 => (8, encoded_size::<LesqliteUsize>(&values), encoded_size::<PrefixUsize>(&values)),
+                "u64" => (8, encoded_size::<LesqliteU128>(&values), encoded_size::<PrefixU128>(&values)),
+                "u128" => (16, encoded_size::<LesqliteU128>(&values), encoded_size::<PrefixU128>(&values)),
+                _ => (mem::size_of::<usize>(), encoded_size::<LesqliteUsize>(&values), encoded_size::<PrefixUsize>(&values)),
+            };
+
+            let raw = values.len() * int_size;
+            let mut schemes = vec![
+                ("raw", raw),
+                ("leb128", encoded_size::<Leb128C>(&values)),
+                ("lesqlite", lesqlite),
+                ("prefix", prefix),
+            ];
+
+            if ty != "u128" {
+                schemes.push(("adaptive", encoded_size::<AdaptiveU64>(&values)));
+            }
+
+            for &(scheme, total_bytes) in &schemes {
+                println!("{}, {}, {}, {}, {:.1}%",
+                         name,
+                         ty,
+                         scheme,
+                         total_bytes,
+                         total_bytes as f64 * 100.0 / raw as f64);
+            }
+        }
+    }
+}
+
+// leb128 decoding eight bytes at a time ---------------------------------------
+//
+// Loads a whole word, finds the terminating byte via the inverted continuation
+// bits and then squeezes out the continuation bits in three mask-and-shift
+// steps (a portable stand-in for `pext`). Values longer than eight bytes and
+// reads near the end of the buffer fall back to the byte-wise loop.
+
+#[inline]
+pub fn read_leb128_wordwise_u64(data: &[u8], start_position: usize) -> (u64, usize) {
+    if start_position + 8 <= data.len() {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&data[start_position .. start_position + 8]);
+        let word = u64::from_le_bytes(bytes);
+
+        let stops = !word & 0x8080_8080_8080_8080;
+
+        if stops != 0 {
+            let len = (stops.trailing_zeros() as usize + 1) / 8;
+
+            let w = word & (u64::MAX >> (64 - len * 8)) & 0x7F7F_7F7F_7F7F_7F7F;
+            let w = (w & 0x007F_007F_007F_007F) | ((w & 0x7F00_7F00_7F00_7F00) >> 1);
+            let w = (w & 0x0000_3FFF_0000_3FFF) | ((w & 0x3FFF_0000_3FFF_0000) >> 2);
+            let w = (w & 0x0000_0000_0FFF_FFFF) | ((w & 0x0FFF_FFFF_0000_0000) >> 4);
+
+            return (w, len)
+        }
+    }
+
+    read_leb128_ref_u64(data, start_position)
+}
+
+impl_read_bench!(read_leb128_wordwise_u64_metadata, U64, read_leb128_wordwise_u64, METADATA);
+impl_read_bench!(read_leb128_wordwise_u64_dep_graph, Usize, read_leb128_wordwise_u64, DEP_GRAPH);
+impl_read_bench!(read_leb128_wordwise_u64_query_cache, U64, read_leb128_wordwise_u64, QUERY_CACHE);
+
+#[cfg(feature = "std")]
+#[test]
+fn wordwise_reader_matches_ref() {
+    for &name in &[METADATA, DEP_GRAPH, QUERY_CACHE] {
+        let mut values: Vec<u64> = load_test_data(name).iter().filter_map(|entry| {
+            match *entry {
+                Value::U32(val) => Some(val as u64),
+                Value::U64(val) => Some(val),
+                Value::Usize(val) => Some(val as u64),
+                _ => None,
+            }
+        }).collect();
+
+        values.extend_from_slice(&[0, 0x7F, 0x80, 1 << 56, (1 << 56) - 1, u64::MAX]);
+
+        let mut encoded = Vec::new();
+        for &val in values.iter() {
+            let pos = encoded.len();
+            write_leb128c_u64(&mut encoded, pos, val);
+        }
+
+        let mut position = 0;
+        for &val in values.iter() {
+            let expected = read_leb128_ref_u64(&encoded, position);
+            let actual = read_leb128_wordwise_u64(&encoded, position);
+            assert_eq!(actual, expected);
+            assert_eq!(actual.0, val);
+            position += actual.1;
+        }
+
+        assert_eq!(position, encoded.len());
+    }
+}
+
+
+// Decoding a whole buffer -----------------------------------------------------
+
+pub fn decode_all_into_leb128_u64(data: &[u8], out: &mut Vec<u64>) {
+    out.clear();
+
+    let mut position = 0;
+    while position < data.len() {
+        let (val, read) = read_leb128_ref_u64(data, position);
+        out.push(val);
+        position += read;
+    }
+}
+
+pub fn decode_all_leb128_u64(data: &[u8]) -> Vec<u64> {
+    let mut out = Vec::new();
+    decode_all_into_leb128_u64(data, &mut out);
+    out
+}
+
+// For streams with a known number of values: writes straight into the
+// allocation instead of going through `push`. `count` has to be exactly the
+// number of values in `data`. With a larger count the reader runs off the end
+// of `data` and panics, with a smaller one the rest of `data` is ignored.
+pub fn decode_all_leb128_u64_fast(data: &[u8], count: usize) -> Vec<u64> {
+    let mut out: Vec<u64> = Vec::with_capacity(count);
+    let ptr = out.as_mut_ptr();
+
+    let mut position = 0;
+    for i in 0 .. count {
+        let (val, read) = read_leb128_ref_u64(data, position);
+        // `i < count <= capacity`, and nothing is read from the slot.
+        unsafe { ptr.add(i).write(val) };
+        position += read;
+    }
+
+    // Only now that every slot below `count` has been written.
+    unsafe { out.set_len(count) };
+    out
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn decode_all_leb128_u64_fast_matches_safe() {
+    for &name in &[METADATA, QUERY_CACHE] {
+        let mut encoded = Vec::new();
+        let mut count = 0;
+
+        for entry in load_test_data(name).iter() {
+            if let Value::U64(val) = *entry {
+                let pos = encoded.len();
+                write_leb128c_u64(&mut encoded, pos, val);
+                count += 1;
+            }
+        }
+
+        assert_eq!(decode_all_leb128_u64_fast(&encoded, count), decode_all_leb128_u64(&encoded));
+    }
+
+    assert!(decode_all_leb128_u64_fast(&[], 0).is_empty());
+}
+
+// Only looks at the first `max_bytes` bytes of `data`; a value that starts
+// within the budget but doesn't end there is an error. Returns the number of
+// bytes consumed. `out` is cleared first, so it can be reused across calls and
+// holds only this call's values, even on error (then the ones before it).
+pub fn decode_all_leb128_u64_bounded(data: &[u8], max_bytes: usize, out: &mut Vec<u64>) -> Result<usize, DecodeError> {
+    out.clear();
+
+    let data = &data[.. cmp::min(data.len(), max_bytes)];
+
+    let mut position = 0;
+    while position < data.len() {
+        let (val, read) = read_leb128_ref_checked(data, position)?;
+        if val > u64::MAX as u128 {
+            return Err(DecodeError::Overflow);
+        }
+        out.push(val as u64);
+        position += read;
+    }
+
+    Ok(position)
+}
+
+#[test]
+fn decode_all_leb128_u64_bounded_budget() {
+    let mut data = Vec::new();
+    for &val in &[1, 300, 1 << 20, 7] {
+        let pos = data.len();
+        write_leb128c_u64(&mut data, pos, val);
+    }
+    // 1 | AC 02 | 80 80 40 | 07
+
+    // Whatever was in `out` before is dropped.
+    let mut out = vec![42, 43, 44];
+    assert_eq!(decode_all_leb128_u64_bounded(&data, 3, &mut out), Ok(3));
+    assert_eq!(out, [1, 300]);
+
+    assert_eq!(decode_all_leb128_u64_bounded(&data, 5, &mut out), Err(DecodeError::UnexpectedEof));
+    assert_eq!(out, [1, 300]);
+
+    assert_eq!(decode_all_leb128_u64_bounded(&data, 100, &mut out), Ok(data.len()));
+    assert_eq!(out, [1, 300, 1 << 20, 7]);
+}
+
+// For blocks that are supposed to hold exactly `count` values and nothing
+// else. Anything left over afterwards means the block is corrupt.
+pub fn decode_exact_leb128_u64(data: &[u8], count: usize) -> Result<Vec<u64>, DecodeError> {
+    // Every value takes at least one byte.
+    let mut out = Vec::with_capacity(cmp::min(count, data.len()));
+
+    let mut position = 0;
+    for _ in 0 .. count {
+        let (val, read) = read_leb128_ref_checked(data, position)?;
+        if val > u64::MAX as u128 {
+            return Err(DecodeError::Overflow);
+        }
+        out.push(val as u64);
+        position += read;
+    }
+
+    if position != data.len() {
+        return Err(DecodeError::TrailingBytes);
+    }
+
+    Ok(out)
+}
+
+#[test]
+fn decode_exact_leb128_u64_outcomes() {
+    let mut data = Vec::new();
+    for &val in &[1, 300, u64::MAX] {
+        let pos = data.len();
+        write_leb128c_u64(&mut data, pos, val);
+    }
+
+    assert_eq!(decode_exact_leb128_u64(&data, 3), Ok(vec![1, 300, u64::MAX]));
+    assert_eq!(decode_exact_leb128_u64(&[], 0), Ok(vec![]));
+
+    assert_eq!(decode_exact_leb128_u64(&data, 2), Err(DecodeError::TrailingBytes));
+    assert_eq!(decode_exact_leb128_u64(&data, 4), Err(DecodeError::UnexpectedEof));
+    assert_eq!(decode_exact_leb128_u64(&data[.. data.len() - 1], 3), Err(DecodeError::UnexpectedEof));
+}
+
+macro_rules! impl_decode_all_bench {
+    ($reuse_bench:ident, $fresh_bench:ident, $fast_bench:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $reuse_bench {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode(&test_data);
+                let expected: Vec<u64> = test_data.iter().map(|&val| val as u64).collect();
+
+                let mut decoded = vec![1, 2, 3];
+                decode_all_into_leb128_u64(&encoded, &mut decoded);
+                assert_eq!(decoded, expected);
+
+                assert_eq!(decode_all_leb128_u64(&encoded), expected);
+                assert_eq!(decode_all_leb128_u64_fast(&encoded, test_data.len()), expected);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $reuse_bench(b: &mut test::Bencher) {
+
+            let test_data = $reuse_bench::test_data();
+            b.bytes = $reuse_bench::input_bytes(&test_data);
+
+            let encoded = $reuse_bench::encode(&test_data);
+            let mut decoded = Vec::new();
+
+            b.iter(|| {
+                decode_all_into_leb128_u64(&encoded, &mut decoded);
+                test::black_box(&decoded);
+            });
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $fresh_bench(b: &mut test::Bencher) {
+
+            let test_data = $reuse_bench::test_data();
+            b.bytes = $reuse_bench::input_bytes(&test_data);
+
+            let encoded = $reuse_bench::encode(&test_data);
+
+            b.iter(|| {
+                test::black_box(decode_all_leb128_u64(&encoded));
+            });
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $fast_bench(b: &mut test::Bencher) {
+
+            let test_data = $reuse_bench::test_data();
+            b.bytes = $reuse_bench::input_bytes(&test_data);
+
+            let encoded = $reuse_bench::encode(&test_data);
+
+            b.iter(|| {
+                test::black_box(decode_all_leb128_u64_fast(&encoded, test_data.len()));
+            });
+        }
+    )
+}
+
+impl_decode_all_bench!(decode_all_reuse_u64_metadata, decode_all_fresh_u64_metadata, decode_all_fast_u64_metadata, U64, METADATA);
+impl_decode_all_bench!(decode_all_reuse_u64_dep_graph, decode_all_fresh_u64_dep_graph, decode_all_fast_u64_dep_graph, U64, DEP_GRAPH);
+impl_decode_all_bench!(decode_all_reuse_u64_query_cache, decode_all_fresh_u64_query_cache, decode_all_fast_u64_query_cache, U64, QUERY_CACHE);
+
+#[test]
+fn decode_all_into_reuses_buffer() {
+    let values = [0, 1, 127, 128, 624485, u64::MAX];
+
+    let mut encoded = Vec::new();
+    for &val in values.iter() {
+        let pos = encoded.len();
+        write_leb128c_u64(&mut encoded, pos, val);
+    }
+
+    let mut decoded = vec![42; 100];
+    decode_all_into_leb128_u64(&encoded, &mut decoded);
+    assert_eq!(decoded, values);
+    assert!(decoded.capacity() >= 100);
+
+    assert_eq!(decode_all_leb128_u64(&encoded), values);
+    assert_eq!(decode_all_leb128_u64(&[]), []);
+}
+
+
+// Iterating over a buffer -----------------------------------------------------
+
+pub struct Leb128Iter<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Leb128Iter<'a> {
+    pub fn new(data: &'a [u8]) -> Leb128Iter<'a> {
+        Leb128Iter { data, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for Leb128Iter<'a> {
+    type Item = u64;
+
+    #[inline]
+    fn next(&mut self) -> Option<u64> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+
+        let (val, read) = read_leb128_ref_u64(self.data, self.pos);
+        self.pos += read;
+        Some(val)
+    }
+}
+
+macro_rules! impl_iter_bench {
+    ($iter_bench:ident, $loop_bench:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $iter_bench {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[inline]
+            pub fn sum_iter(encoded: &[u8]) -> u64 {
+                Leb128Iter::new(encoded).fold(0u64, |sum, val| sum.wrapping_add(val))
+            }
+
+            #[inline]
+            pub fn sum_loop(encoded: &[u8]) -> u64 {
+                let mut sum = 0u64;
+                let mut position = 0;
+                while position < encoded.len() {
+                    let (val, count) = read_leb128_ref_u64(encoded, position);
+                    sum = sum.wrapping_add(val);
+                    position += count;
+                }
+                sum
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode(&test_data);
+                let expected = test_data.iter().fold(0u64, |sum, &val| sum.wrapping_add(val as u64));
+
+                assert_eq!(sum_iter(&encoded), expected);
+                assert_eq!(sum_loop(&encoded), expected);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $iter_bench(b: &mut test::Bencher) {
+
+            let test_data = $iter_bench::test_data();
+            b.bytes = $iter_bench::input_bytes(&test_data);
+
+            let encoded = $iter_bench::encode(&test_data);
+
+            b.iter(|| $iter_bench::sum_iter(&encoded));
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $loop_bench(b: &mut test::Bencher) {
+
+            let test_data = $iter_bench::test_data();
+            b.bytes = $iter_bench::input_bytes(&test_data);
+
+            let encoded = $iter_bench::encode(&test_data);
+
+            b.iter(|| $iter_bench::sum_loop(&encoded));
+        }
+    )
+}
+
+impl_iter_bench!(sum_leb128_iter_u64_metadata, sum_leb128_loop_u64_metadata, U64, METADATA);
+impl_iter_bench!(sum_leb128_iter_u64_dep_graph, sum_leb128_loop_u64_dep_graph, U64, DEP_GRAPH);
+impl_iter_bench!(sum_leb128_iter_u64_query_cache, sum_leb128_loop_u64_query_cache, U64, QUERY_CACHE);
+
+#[cfg(feature = "std")]
+#[test]
+fn leb128_iter_reproduces_values() {
+    let values: Vec<u64> = load_test_data(QUERY_CACHE).iter().filter_map(|entry| {
+        match *entry {
+            Value::U64(val) => Some(val),
+            _ => None,
+        }
+    }).collect();
+
+    let mut encoded = Vec::new();
+    for &val in values.iter() {
+        let pos = encoded.len();
+        write_leb128c_u64(&mut encoded, pos, val);
+    }
+
+    assert_eq!(Leb128Iter::new(&encoded).collect::<Vec<_>>(), values);
+    assert_eq!(Leb128Iter::new(&[]).next(), None);
+}
+
+
+// lesqlite with configurable cut points ---------------------------------------
+//
+// Same layout as the other lesqlite writers, but with CUT1/CUT2 as parameters.
+// `impl_write_u32_lesqlite` and `read_lesqlite_u32` are these with the default
+// cut points. The slow path needs tags for 2 to 4 bytes, so `cut2` must not
+// exceed 253.
+
+#[inline]
+pub fn write_lesqlite_tuned_u32(out: &mut Vec<u8>, start_position: usize, mut value: u32, cut1: u8, cut2: u8) -> usize {
+    debug_assert!(cut1 < cut2 && cut2 <= 253);
+
+    let cut1 = cut1 as u32;
+    let cut2 = cut2 as u32;
+
+    if value < cut1 {
+        write_to_vec(out, start_position, value as u8);
+        1
+    } else if value <= (cut1 + 255 + 256 * (cut2 - 1 - cut1)) {
+        value -= cut1;
+        write_to_vec(out, start_position, cut1 as u8 + ((value >> 8) as u8));
+        write_to_vec(out, start_position + 1, value as u8);
+        2
+    } else {
+        let bits = 32 - value.leading_zeros() as usize;
+        let bytes = bits.div_ceil(8);
+
+        write_to_vec(out, start_position, cut2 as u8 + (bytes - 2) as u8);
+
+        let value = value.to_le_bytes();
+        write_slice_to_vec_skewed(out, start_position + 1, &value[.. bytes]);
+        bytes + 1
+    }
+}
+
+#[inline]
+pub fn read_lesqlite_tuned_u32(data: &[u8], start_position: usize, cut1: u8, cut2: u8) -> (u32, usize) {
+    let first = data[start_position];
+
+    if first < cut1 {
+        (first as u32, 1)
+    } else if first < cut2 {
+        let second = data[start_position + 1] as u32;
+        (cut1 as u32 + (((first - cut1) as u32) << 8) + second, 2)
+    } else {
+        let bytes = (first - cut2) as usize + 2;
+        let mut result = [0u8; 4];
+        result[.. bytes].copy_from_slice(&data[start_position + 1 .. start_position + 1 + bytes]);
+        (u32::from_le_bytes(result), bytes + 1)
+    }
+}
+
+macro_rules! impl_lesqlite_tuned_bench {
+    ($bench_name:ident, $variant:ident, $cut1:expr, $cut2:expr, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!(@values $variant, $data);
+
+            #[inline]
+            pub fn encode_all(test_data: &[variant_ty!($variant)], capacity: usize) -> Vec<u8> {
+                let mut output = Vec::with_capacity(capacity);
+                let mut position = 0;
+
+                for &val in test_data.iter() {
+                    position += write_lesqlite_tuned_u32(&mut output, position, val, $cut1, $cut2);
+                }
+
+                output
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode_all(&test_data, 0);
+
+                let mut position = 0;
+                for (i, &val) in test_data.iter().enumerate() {
+                    let (decoded, read) = read_lesqlite_tuned_u32(&encoded, position, $cut1, $cut2);
+                    assert_eq!(decoded, val, "value #{}", i);
+                    position += read;
+                }
+                assert_eq!(position, encoded.len());
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let mut size = 0;
+            let capacity = ((b.bytes * 135) / 100) as usize;
+
+            b.iter(|| {
+                size = $bench_name::encode_all(&test_data, capacity).len();
+            });
+
+            if b.bytes > 0 {
+                print!("cut1: {}, cut2: {}, size: {}%, ", $cut1, $cut2, (100 * size) / (b.bytes as usize));
+            }
+        }
+    )
+}
+
+impl_lesqlite_tuned_bench!(write_lesqlite_tuned_128_249_u32_query_cache, U32, 128, 249, QUERY_CACHE);
+impl_lesqlite_tuned_bench!(write_lesqlite_tuned_185_249_u32_query_cache, U32, 185, 249, QUERY_CACHE);
+impl_lesqlite_tuned_bench!(write_lesqlite_tuned_220_249_u32_query_cache, U32, 220, 249, QUERY_CACHE);
+impl_lesqlite_tuned_bench!(write_lesqlite_tuned_185_253_u32_query_cache, U32, 185, 253, QUERY_CACHE);
+impl_lesqlite_tuned_bench!(write_lesqlite_tuned_240_253_u32_query_cache, U32, 240, 253, QUERY_CACHE);
+
+#[test]
+fn lesqlite_tuned_matches_default() {
+    let mut values = vec![0, 184, 185, 16568, 16569, u32::MAX];
+    for shift in 0 .. 32 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    for &value in values.iter() {
+        let mut expected = Vec::new();
+        let mut actual = Vec::new();
+        impl_write_u32_lesqlite(&mut expected, 0, value);
+        write_lesqlite_tuned_u32(&mut actual, 0, value, 185, 249);
+        assert_eq!(actual, expected);
+    }
+}
+
+#[test]
+fn lesqlite_tuned_round_trip() {
+    let mut values = vec![0, u32::MAX];
+    for shift in 0 .. 32 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+        values.push((1 << shift) + 1);
+    }
+
+    for &(cut1, cut2) in &[(1, 2), (128, 249), (185, 249), (240, 253), (252, 253)] {
+        let mut encoded = Vec::new();
+        let mut position = 0;
+        for &val in values.iter() {
+            position += write_lesqlite_tuned_u32(&mut encoded, position, val, cut1, cut2);
+        }
+
+        let mut position = 0;
+        for &val in values.iter() {
+            let (decoded, read) = read_lesqlite_tuned_u32(&encoded, position, cut1, cut2);
+            assert_eq!(decoded, val);
+            position += read;
+        }
+
+        assert_eq!(position, encoded.len());
+    }
+}
+
+
+// Length-prefixed, checksummed frames -----------------------------------------
+//
+// A frame is the leb128 payload length, the payload itself and the 64 bit
+// FNV-1a hash of the payload in little-endian.
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
+
+pub fn write_framed(out: &mut Vec<u8>, payload: &[u8]) -> usize {
+    let start_position = out.len();
+
+    write_leb128c_usize(out, start_position, payload.len());
+    out.extend_from_slice(payload);
+    out.extend_from_slice(&fnv1a(payload).to_le_bytes());
+
+    out.len() - start_position
+}
+
+pub fn read_framed(data: &[u8], start_position: usize) -> Result<(&[u8], usize), DecodeError> {
+    let (len, read) = read_leb128_usize_checked(data, start_position)?;
+    let rest = &data[start_position + read ..];
+
+    if len > rest.len() || rest.len() - len < 8 {
+        return Err(DecodeError::UnexpectedEof);
+    }
+
+    let payload = &rest[.. len];
+    let mut checksum = [0u8; 8];
+    checksum.copy_from_slice(&rest[len .. len + 8]);
+
+    if u64::from_le_bytes(checksum) != fnv1a(payload) {
+        return Err(DecodeError::ChecksumMismatch);
+    }
+
+    Ok((payload, read + len + 8))
+}
+
+#[test]
+fn framed_round_trip() {
+    let first: Vec<u8> = (0 .. 300).map(|i| i as u8).collect();
+    let second = b"second frame";
+
+    let mut out = Vec::new();
+    let first_len = write_framed(&mut out, &first);
+    let second_len = write_framed(&mut out, second);
+    write_framed(&mut out, &[]);
+
+    let (payload, read) = read_framed(&out, 0).unwrap();
+    assert_eq!(payload, &first[..]);
+    assert_eq!(read, first_len);
+
+    let (payload, read) = read_framed(&out, first_len).unwrap();
+    assert_eq!(payload, &second[..]);
+    assert_eq!(read, second_len);
+
+    let (payload, read) = read_framed(&out, first_len + second_len).unwrap();
+    assert!(payload.is_empty());
+    assert_eq!(first_len + second_len + read, out.len());
+}
+
+#[test]
+fn framed_corruption() {
+    let mut out = Vec::new();
+    write_framed(&mut out, b"some payload");
+
+    // Length pointing past the end of the buffer.
+    let mut corrupted = out.clone();
+    corrupted[0] = 0x7F;
+    assert_eq!(read_framed(&corrupted, 0), Err(DecodeError::UnexpectedEof));
+
+    // Length still within the buffer, but covering the wrong bytes.
+    let mut corrupted = out.clone();
+    corrupted[0] -= 1;
+    assert_eq!(read_framed(&corrupted, 0), Err(DecodeError::ChecksumMismatch));
+
+    // Damaged payload.
+    let mut corrupted = out.clone();
+    corrupted[3] ^= 0x01;
+    assert_eq!(read_framed(&corrupted, 0), Err(DecodeError::ChecksumMismatch));
+
+    // Unterminated length.
+    assert_eq!(read_framed(&[0x80, 0x80], 0), Err(DecodeError::UnexpectedEof));
+    assert_eq!(read_framed(&[], 0), Err(DecodeError::UnexpectedEof));
+}
+
+
+// Encoding in shuffled order --------------------------------------------------
+//
+// Same as `impl_bench!`, but the values are shuffled once up front, so runs of
+// similar values in the original files don't help the branch predictor.
+
+const SHUFFLE_SEED: u64 = 0x9E37_79B9_7F4A_7C15;
+
+macro_rules! impl_shuffled_bench {
+    ($bench_name:ident, $variant:ident, $encoder:ty, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data, $encoder);
+
+            pub fn shuffled_data() -> Vec<variant_ty!($variant)> {
+                let mut test_data = test_data();
+                shuffle(&mut test_data, SHUFFLE_SEED);
+                test_data
+            }
+
+            #[inline]
+            pub fn encode_all(test_data: &[variant_ty!($variant)], capacity: usize) -> Vec<u8> {
+                let mut output = Vec::with_capacity(capacity);
+                let mut position = 0;
+
+                for &val in test_data.iter() {
+                    position += <$encoder as VarintEncoder>::encode(&mut output, position, val as u128);
+                }
+
+                output
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = shuffled_data();
+                assert_eq!(encode_all(&test_data, 0), encode(&test_data));
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::shuffled_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let mut size = 0;
+            let capacity = ((b.bytes * 135) / 100) as usize;
+
+            b.iter(|| {
+                size = $bench_name::encode_all(&test_data, capacity).len();
+            });
+
+            if b.bytes > 0 {
+                print!("size: {}%, ", (100 * size) / (b.bytes as usize));
+            }
+        }
+    )
+}
+
+impl_shuffled_bench!(write_shuffled_leb128c_u32_metadata, U32, Leb128CU32, METADATA);
+impl_shuffled_bench!(write_shuffled_leb128c_u32_dep_graph, U32, Leb128CU32, DEP_GRAPH);
+impl_shuffled_bench!(write_shuffled_leb128c_u32_query_cache, U32, Leb128CU32, QUERY_CACHE);
+
+impl_shuffled_bench!(write_shuffled_lesqlite_u32_metadata, U32, LesqliteU32, METADATA);
+impl_shuffled_bench!(write_shuffled_lesqlite_u32_dep_graph, U32, LesqliteU32, DEP_GRAPH);
+impl_shuffled_bench!(write_shuffled_lesqlite_u32_query_cache, U32, LesqliteU32, QUERY_CACHE);
+
+impl_shuffled_bench!(write_shuffled_raw_u32_skewed_metadata, U32, RawU32Skewed, METADATA);
+impl_shuffled_bench!(write_shuffled_raw_u32_skewed_dep_graph, U32, RawU32Skewed, DEP_GRAPH);
+impl_shuffled_bench!(write_shuffled_raw_u32_skewed_query_cache, U32, RawU32Skewed, QUERY_CACHE);
+
+#[test]
+fn shuffle_is_deterministic_permutation() {
+    let original: Vec<u32> = (0 .. 1000).collect();
+
+    let mut a = original.clone();
+    let mut b = original.clone();
+    shuffle(&mut a, SHUFFLE_SEED);
+    shuffle(&mut b, SHUFFLE_SEED);
+
+    assert_eq!(a, b);
+    assert!(a != original);
+
+    a.sort();
+    assert_eq!(a, original);
+}
+
+
+// leb128 into a stack buffer --------------------------------------------------
+
+#[inline]
+pub fn write_leb128_u64_arr(mut value: u64) -> ([u8; 10], usize) {
+    let mut out = [0u8; 10];
+    let mut position = 0;
+
+    loop {
+        let mut byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+        }
+
+        out[position] = byte;
+        position += 1;
+
+        if value == 0 {
+            return (out, position);
+        }
+    }
+}
+
+macro_rules! impl_arr_bench {
+    ($bench_name:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[inline]
+            pub fn write_all(test_data: &[variant_ty!($variant)]) -> usize {
+                let mut total = 0;
+
+                for &val in test_data.iter() {
+                    let (bytes, len) = write_leb128_u64_arr(val as u64);
+                    ::core::hint::black_box(&bytes);
+                    total += len;
+                }
+
+                total
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode(&test_data);
+                assert_eq!(write_all(&test_data), encoded.len());
+
+                let mut position = 0;
+                for &val in test_data.iter() {
+                    let (bytes, len) = write_leb128_u64_arr(val as u64);
+                    assert_eq!(bytes[.. len], encoded[position .. position + len]);
+                    position += len;
+                }
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let mut size = 0;
+
+            b.iter(|| {
+                size = $bench_name::write_all(&test_data);
+            });
+
+            if b.bytes > 0 {
+                print!("size: {}%, ", (100 * size) / (b.bytes as usize));
+            }
+        }
+    )
+}
+
+impl_arr_bench!(write_leb128_arr_u64_metadata, U64, METADATA);
+impl_arr_bench!(write_leb128_arr_u64_dep_graph, U64, DEP_GRAPH);
+impl_arr_bench!(write_leb128_arr_u64_query_cache, U64, QUERY_CACHE);
+
+#[test]
+fn leb128_arr_matches_vec() {
+    let mut values = vec![0, u64::MAX];
+    for shift in 0 .. 64 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    for &value in values.iter() {
+        let mut expected = Vec::new();
+        let expected_len = write_leb128c_u64(&mut expected, 0, value);
+
+        let (bytes, len) = write_leb128_u64_arr(value);
+        assert_eq!(len, expected_len);
+        assert_eq!(&bytes[.. len], &expected[..]);
+    }
+}
+
+
+// Decoding out of a memory-mapped file ----------------------------------------
+//
+// rustc reads metadata straight out of mmap'd rlibs, so these decode the same
+// data as the `read_leb128_unsafe_u64_*` benches but from a mapping of a temp
+// file instead of from a `Vec` on the heap.
+
+macro_rules! impl_mmap_bench {
+    ($bench_name:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[inline]
+            pub fn decode_all(mapped: &[u8], test_data: &[variant_ty!($variant)], verify: bool) {
+                let mut position = 0;
+                for i in 0 .. test_data.len() {
+                    let (val, count) = read_leb128_unsafe_u64(mapped, position);
+                    ::core::hint::black_box(val);
+                    position += count;
+
+                    if verify {
+                        assert_eq!(val, test_data[i] as u64, "value #{}", i);
+                    }
+                }
+            }
+
+            // The mapping itself only exists in the bench, this runs the loop
+            // over the same bytes on the heap.
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                decode_all(&encode(&test_data), &test_data, true);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+            use std::io::Write;
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let encoded = $bench_name::encode(&test_data);
+
+            let path = ::std::env::temp_dir().join(format!("encoding-bench-{}-{}",
+                                                           stringify!($bench_name),
+                                                           ::std::process::id()));
+            File::create(&path).unwrap().write_all(&encoded).unwrap();
+
+            let file = File::open(&path).unwrap();
+            let map = unsafe { memmap2::Mmap::map(&file).unwrap() };
+            let mapped: &[u8] = &map;
+
+            b.iter(|| {
+                $bench_name::decode_all(mapped, &test_data, false);
+            });
+
+            drop(map);
+            ::std::fs::remove_file(&path).unwrap();
+        }
+    )
+}
+
+impl_mmap_bench!(read_leb128_unsafe_u64_mmap_metadata, U64, METADATA);
+impl_mmap_bench!(read_leb128_unsafe_u64_mmap_dep_graph, Usize, DEP_GRAPH);
+impl_mmap_bench!(read_leb128_unsafe_u64_mmap_query_cache, U64, QUERY_CACHE);
+
+
+// Pre-sized vs growing output vectors -----------------------------------------
+//
+// The regular write benches hand the encoder a vector that already has room
+// for everything. These start from `values.len() * 2` bytes and from an empty
+// vector respectively, which shows what the capacity check in the `d` variant
+// costs once no reallocation is needed anymore, compared to `c`, which leaves
+// growing to `Vec::push`.
+
+macro_rules! impl_capacity_bench {
+    ($presized_bench:ident, $growing_bench:ident, $variant:ident, $encoder:ty, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $presized_bench {
+            use super::*;
+
+            impl_bench_data!($variant, $data, $encoder);
+
+            #[inline]
+            pub fn encode_all(test_data: &[variant_ty!($variant)], capacity: usize) -> Vec<u8> {
+                let mut output = Vec::with_capacity(capacity);
+                let mut position = 0;
+
+                for &val in test_data.iter() {
+                    position += <$encoder as VarintEncoder>::encode(&mut output, position, val as u128);
+                }
+
+                output
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode(&test_data);
+
+                assert_eq!(encode_all(&test_data, test_data.len() * 2), encoded);
+                assert_eq!(encode_all(&test_data, 0), encoded);
+            }
+        }
+
+        impl_capacity_bench!(@bench $presized_bench, $presized_bench, |len: usize| len * 2);
+        impl_capacity_bench!(@bench $growing_bench, $presized_bench, |_: usize| 0);
+    );
+    (@bench $bench_name:ident, $module:ident, $capacity:expr) => (
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $module::test_data();
+            b.bytes = $module::input_bytes(&test_data);
+
+            let capacity = ($capacity)(test_data.len());
+
+            b.iter(|| $module::encode_all(&test_data, capacity));
+        }
+    )
+}
+
+impl_capacity_bench!(write_leb128d_u64_presized_metadata, write_leb128d_u64_growing_metadata, U64, Leb128DU64, METADATA);
+impl_capacity_bench!(write_leb128d_u64_presized_dep_graph, write_leb128d_u64_growing_dep_graph, U64, Leb128DU64, DEP_GRAPH);
+impl_capacity_bench!(write_leb128d_u64_presized_query_cache, write_leb128d_u64_growing_query_cache, U64, Leb128DU64, QUERY_CACHE);
+
+impl_capacity_bench!(write_leb128c_u64_presized_metadata, write_leb128c_u64_growing_metadata, U64, Leb128CU64, METADATA);
+impl_capacity_bench!(write_leb128c_u64_presized_dep_graph, write_leb128c_u64_growing_dep_graph, U64, Leb128CU64, DEP_GRAPH);
+impl_capacity_bench!(write_leb128c_u64_presized_query_cache, write_leb128c_u64_growing_query_cache, U64, Leb128CU64, QUERY_CACHE);
+
+
+// lesqlite decoding via a first-byte table ------------------------------------
+//
+// Looks the encoded length up by tag byte instead of walking the CUT1/CUT2
+// comparison chain of `read_lesqlite_u32`.
+
+// Total encoded length (tag byte included) of a lesqlite u32, indexed by the
+// tag byte.
+const LESQLITE_U32_LEN: [u8; 256] = lesqlite_u32_len_table();
+
+const fn lesqlite_u32_len_table() -> [u8; 256] {
+    const CUT1: usize = 185;
+    const CUT2: usize = lesqlite_cut2!(u32);
+
+    let mut table = [0u8; 256];
+    let mut first = 0;
+
+    while first < 256 {
+        table[first] = if first < CUT1 {
+            1
+        } else if first < CUT2 {
+            2
+        } else {
+            (first - CUT2 + 3) as u8
+        };
+        first += 1;
+    }
+
+    table
+}
+
+#[inline]
+pub fn read_lesqlite_u32_table(data: &[u8], start_position: usize) -> (u32, usize) {
+    const CUT1: u32 = 185;
+
+    let first = data[start_position];
+    let len = LESQLITE_U32_LEN[first as usize] as usize;
+
+    let value = match len {
+        1 => first as u32,
+        2 => CUT1 + ((first as u32 - CUT1) << 8) + data[start_position + 1] as u32,
+        _ => {
+            let mut bytes = [0u8; 4];
+            bytes[.. len - 1].copy_from_slice(&data[start_position + 1 .. start_position + len]);
+            u32::from_le_bytes(bytes)
+        }
+    };
+
+    (value, len)
+}
+
+impl_varint_decoder!(LesqliteTableU32, read_lesqlite_u32_table);
+
+impl_read_bench!(read_lesqlite_u32_metadata, U32, read_lesqlite_u32, METADATA, LesqliteU32);
+impl_read_bench!(read_lesqlite_u32_dep_graph, U32, read_lesqlite_u32, DEP_GRAPH, LesqliteU32);
+impl_read_bench!(read_lesqlite_u32_query_cache, U32, read_lesqlite_u32, QUERY_CACHE, LesqliteU32);
+
+impl_read_bench!(read_lesqlite_u32_table_metadata, U32, read_lesqlite_u32_table, METADATA, LesqliteU32);
+impl_read_bench!(read_lesqlite_u32_table_dep_graph, U32, read_lesqlite_u32_table, DEP_GRAPH, LesqliteU32);
+impl_read_bench!(read_lesqlite_u32_table_query_cache, U32, read_lesqlite_u32_table, QUERY_CACHE, LesqliteU32);
+
+impl_read_bench!(read_lesqlite_u32_encoded_query_cache, U32, read_lesqlite_u32, QUERY_CACHE, LesqliteU32, encoded);
+impl_read_bench!(read_leb128_ref_u32_encoded_query_cache, U32, read_leb128_ref_u32, QUERY_CACHE, encoded);
+
+#[test]
+fn lesqlite_u32_table_round_trip() {
+    let mut values = vec![0, 184, 185, 16568, 16569, u32::MAX];
+    for shift in 0 .. 32 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        position += impl_write_u32_lesqlite(&mut encoded, position, val);
+    }
+
+    let mut position = 0;
+    for &val in values.iter() {
+        let expected = read_lesqlite_u32(&encoded, position);
+        assert_eq!(read_lesqlite_u32_table(&encoded, position), expected);
+        assert_eq!(expected.0, val);
+        position += expected.1;
+    }
+
+    assert_eq!(position, encoded.len());
+}
+
+
+// Count-prefixed sequences ----------------------------------------------------
+//
+// The way rustc writes a `Vec`: the element count in leb128, then each
+// element.
+
+pub fn write_seq_leb128_u64(out: &mut Vec<u8>, values: &[u64]) -> usize {
+    let start_position = out.len();
+    let mut position = start_position;
+
+    position += write_leb128c_u64(out, position, values.len() as u64);
+    for &val in values {
+        position += write_leb128c_u64(out, position, val);
+    }
+
+    position - start_position
+}
+
+pub fn read_seq_leb128_u64(data: &[u8], start_position: usize) -> Result<(Vec<u64>, usize), DecodeError> {
+    let (count, mut position) = read_leb128_ref_checked(data, start_position)?;
+    position += start_position;
+
+    // Every element takes at least one byte, so don't trust a count that
+    // can't possibly fit into the rest of the buffer when allocating.
+    let mut values = Vec::with_capacity(cmp::min(count, (data.len() - position) as u128) as usize);
+
+    for _ in 0 .. count {
+        let (val, read) = read_leb128_ref_checked(data, position)?;
+        if val > u64::MAX as u128 {
+            return Err(DecodeError::Overflow);
+        }
+        values.push(val as u64);
+        position += read;
+    }
+
+    Ok((values, position - start_position))
+}
+
+#[test]
+fn seq_leb128_u64_round_trip() {
+    for values in &[vec![], vec![0], vec![1, 300, u64::MAX, 0, 1 << 35]] {
+        let mut out = vec![0xFF];
+        let written = write_seq_leb128_u64(&mut out, values);
+        assert_eq!(written, out.len() - 1);
+        assert_eq!(read_seq_leb128_u64(&out, 1), Ok((values.clone(), written)));
+    }
+
+    let mut out = Vec::new();
+    write_seq_leb128_u64(&mut out, &[]);
+    assert_eq!(out, [0x00]);
+
+    // Declares five elements but only carries two.
+    let truncated = [0x05, 0x01, 0x02];
+    assert_eq!(read_seq_leb128_u64(&truncated, 0), Err(DecodeError::UnexpectedEof));
+}
+
+
+// Decoding two values at once -------------------------------------------------
+//
+// The start of the second value only depends on the continuation bits of the
+// first one. Once that is known, the payloads of both values are accumulated
+// in the same loop so the CPU can overlap the two dependency chains.
+
+#[inline]
+pub fn read_leb128_pair_u64(data: &[u8], start_position: usize) -> (u64, u64, usize) {
+    let mut len_a = 1;
+    while (data[start_position + len_a - 1] & 0x80) != 0 {
+        len_a += 1;
+    }
+
+    let start_b = start_position + len_a;
+    let mut a: u64 = 0;
+    let mut b: u64 = 0;
+    let mut len_b = 0;
+
+    for i in 0 .. leb128_size!(u64) {
+        if i < len_a {
+            a |= ((data[start_position + i] & 0x7F) as u64) << (i * 7);
+        }
+
+        if len_b == 0 {
+            let byte = data[start_b + i];
+            b |= ((byte & 0x7F) as u64) << (i * 7);
+            if (byte & 0x80) == 0 {
+                len_b = i + 1;
+            }
+        }
+
+        if len_b != 0 && i + 1 >= len_a {
+            break;
+        }
+    }
+
+    (a, b, len_a + len_b)
+}
+
+macro_rules! impl_pair_bench {
+    ($bench_name:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[inline]
+            pub fn decode_all(encoded: &[u8], test_data: &[variant_ty!($variant)], verify: bool) {
+                let mut position = 0;
+                for pair in test_data.chunks(2) {
+                    if pair.len() == 2 {
+                        let (x, y, count) = read_leb128_pair_u64(encoded, position);
+                        if verify {
+                            assert_eq!((x, y), (pair[0] as u64, pair[1] as u64));
+                        }
+                        ::core::hint::black_box(x);
+                        ::core::hint::black_box(y);
+                        position += count;
+                    } else {
+                        let (x, count) = read_leb128_unsafe_u64(encoded, position);
+                        if verify {
+                            assert_eq!(x, pair[0] as u64);
+                        }
+                        ::core::hint::black_box(x);
+                        position += count;
+                    }
+                }
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode(&test_data);
+                decode_all(&encoded, &test_data, true);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let encoded = $bench_name::encode(&test_data);
+
+            b.iter(|| $bench_name::decode_all(&encoded, &test_data, false));
+        }
+    )
+}
+
+impl_pair_bench!(read_leb128_pair_u64_metadata, U64, METADATA);
+impl_pair_bench!(read_leb128_pair_u64_dep_graph, Usize, DEP_GRAPH);
+impl_pair_bench!(read_leb128_pair_u64_query_cache, U64, QUERY_CACHE);
+
+#[test]
+fn read_leb128_pair_u64_matches_sequential() {
+    let mut values = vec![0, 1, u64::MAX, 0, 300, u64::MAX, u64::MAX, 1 << 63];
+    for shift in 0 .. 64 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        position += write_leb128c_u64(&mut encoded, position, val);
+    }
+
+    let mut position = 0;
+    for pair in values.chunks(2) {
+        let (first, first_len) = read_leb128_ref_u64(&encoded, position);
+        let (second, second_len) = read_leb128_ref_u64(&encoded, position + first_len);
+        assert_eq!((first, second), (pair[0], pair[1]));
+
+        assert_eq!(read_leb128_pair_u64(&encoded, position), (first, second, first_len + second_len));
+        position += first_len + second_len;
+    }
+
+    assert_eq!(position, encoded.len());
+}
+
+
+// Bit-packed small fields -----------------------------------------------------
+//
+// Booleans and small enums don't need a whole byte each. Fields are packed
+// least significant bit first; the last byte is padded with zero bits.
+
+#[derive(Default)]
+pub struct BitWriter {
+    out: Vec<u8>,
+    acc: u128,
+    acc_bits: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter::default()
+    }
+
+    #[inline]
+    pub fn write_bits(&mut self, value: u64, nbits: u32) {
+        debug_assert!(nbits <= 64);
+        debug_assert!(nbits == 64 || (value >> nbits) == 0);
+
+        self.acc |= (value as u128) << self.acc_bits;
+        self.acc_bits += nbits;
+
+        while self.acc_bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.acc_bits -= 8;
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.acc_bits > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    acc: u128,
+    acc_bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            position: 0,
+            acc: 0,
+            acc_bits: 0,
+        }
+    }
+
+    #[inline]
+    pub fn read_bits(&mut self, nbits: u32) -> u64 {
+        debug_assert!(nbits <= 64);
+
+        while self.acc_bits < nbits {
+            self.acc |= (self.data[self.position] as u128) << self.acc_bits;
+            self.position += 1;
+            self.acc_bits += 8;
+        }
+
+        let value = (self.acc & ((1u128 << nbits) - 1)) as u64;
+        self.acc >>= nbits;
+        self.acc_bits -= nbits;
+        value
+    }
+}
+
+#[test]
+fn bit_writer_round_trip() {
+    let fields: Vec<(u64, u32)> = (0 .. 100u64).map(|i| {
+        if i % 3 == 0 { (i % 8, 3) } else { (i % 2, 1) }
+    }).chain(vec![(u64::MAX, 64), (1, 1), (0x1234, 16)]).collect();
+
+    let mut writer = BitWriter::new();
+    for &(value, nbits) in fields.iter() {
+        writer.write_bits(value, nbits);
+    }
+    let packed = writer.finish();
+
+    let total_bits: u32 = fields.iter().map(|&(_, nbits)| nbits).sum();
+    assert_eq!(packed.len(), total_bits.div_ceil(8) as usize);
+
+    let mut reader = BitReader::new(&packed);
+    for &(value, nbits) in fields.iter() {
+        assert_eq!(reader.read_bits(nbits), value);
+    }
+}
+
+// The u8 values in the datasets mix flags and enum tags with raw bytes, so
+// these only look at the ones that fit into `$nbits` and encode that subset
+// once bit-packed and once with a leb128 byte per value.
+macro_rules! impl_bits_bench {
+    ($bits_bench:ident, $leb128_bench:ident, $data:ident, $nbits:expr) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bits_bench {
+            use super::*;
+
+            impl_bench_data!(@values U8, $data);
+
+            pub fn fitting(test_data: &[u8]) -> Vec<u8> {
+                test_data.iter().cloned().filter(|&val| (val as u64) < (1 << $nbits)).collect()
+            }
+
+            #[inline]
+            pub fn write_bits(values: &[u8]) -> usize {
+                let mut writer = BitWriter::new();
+                for &val in values {
+                    writer.write_bits(val as u64, $nbits);
+                }
+                writer.finish().len()
+            }
+
+            #[inline]
+            pub fn write_leb128(values: &[u8]) -> usize {
+                let mut output = Vec::with_capacity(values.len());
+                let mut position = 0;
+                for &val in values {
+                    position += write_leb128c_u64(&mut output, position, val as u64);
+                }
+                position
+            }
+
+            #[test]
+            fn run_once() {
+                let values = fitting(&test_data());
+                assert_eq!(write_bits(&values), (values.len() * $nbits).div_ceil(8));
+                assert_eq!(write_leb128(&values), values.len());
+            }
+        }
+
+        impl_bits_bench!(@bench $bits_bench, $bits_bench, $bits_bench::write_bits);
+        impl_bits_bench!(@bench $leb128_bench, $bits_bench, $bits_bench::write_leb128);
+    );
+    (@bench $bench_name:ident, $module:ident, $encode:path) => (
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $module::fitting(&$module::test_data());
+            b.bytes = test_data.len() as u64;
+
+            let mut size = 0;
+
+            b.iter(|| {
+                size = $encode(&test_data);
+            });
+
+            if !test_data.is_empty() {
+                print!("bits per value: {:.2}, ", (size * 8) as f64 / test_data.len() as f64);
+            }
+        }
+    )
+}
+
+impl_bits_bench!(write_bits_1_metadata, write_bits_1_leb128_metadata, METADATA, 1);
+impl_bits_bench!(write_bits_1_query_cache, write_bits_1_leb128_query_cache, QUERY_CACHE, 1);
+impl_bits_bench!(write_bits_3_metadata, write_bits_3_leb128_metadata, METADATA, 3);
+impl_bits_bench!(write_bits_3_query_cache, write_bits_3_leb128_query_cache, QUERY_CACHE, 3);
+
+
+// Width in the top bits of the first byte -------------------------------------
+//
+// The top three bits of the first byte hold the total length minus one, the
+// low five bits the least significant bits of the value, and the following
+// bytes the rest of it in little-endian. Seven bytes hold up to 53 bits. With
+// a three bit header, eight bytes could only hold 61 bits, so the tag 7 is
+// used as an escape instead: it is followed by the full 8-byte value.
+
+#[inline]
+pub fn write_width_prefixed_u64(out: &mut Vec<u8>, start_position: usize, value: u64) -> usize {
+    if value < (1 << 53) {
+        let bits = 64 - value.leading_zeros() as usize;
+        let len = 1 + bits.saturating_sub(5).div_ceil(8);
+
+        let word = ((value >> 5) << 8) | (((len - 1) as u64) << 5) | (value & 0x1F);
+        write_slice_to_vec(out, start_position, &word.to_le_bytes()[.. len]);
+        len
+    } else {
+        write_to_vec(out, start_position, 7 << 5);
+        write_slice_to_vec(out, start_position + 1, &value.to_le_bytes());
+        9
+    }
+}
+
+#[inline]
+pub fn read_width_prefixed_u64(data: &[u8], start_position: usize) -> (u64, usize) {
+    let len = (data[start_position] >> 5) as usize + 1;
+    let mut bytes = [0u8; 8];
+
+    if len < 8 {
+        bytes[.. len].copy_from_slice(&data[start_position .. start_position + len]);
+        let word = u64::from_le_bytes(bytes);
+        (((word >> 8) << 5) | (word & 0x1F), len)
+    } else {
+        bytes.copy_from_slice(&data[start_position + 1 .. start_position + 9]);
+        (u64::from_le_bytes(bytes), 9)
+    }
+}
+
+impl_varint_codec!(WidthPrefixedU64, write_width_prefixed_u64, read_width_prefixed_u64, u64);
+
+impl_bench!(write_width_prefixed_u64_metadata, U64, WidthPrefixedU64, METADATA);
+impl_bench!(write_width_prefixed_u64_dep_graph, U64, WidthPrefixedU64, DEP_GRAPH);
+impl_bench!(write_width_prefixed_u64_query_cache, U64, WidthPrefixedU64, QUERY_CACHE);
+
+impl_read_bench!(read_width_prefixed_u64_metadata, U64, read_width_prefixed_u64, METADATA, WidthPrefixedU64);
+impl_read_bench!(read_width_prefixed_u64_dep_graph, U64, read_width_prefixed_u64, DEP_GRAPH, WidthPrefixedU64);
+impl_read_bench!(read_width_prefixed_u64_query_cache, U64, read_width_prefixed_u64, QUERY_CACHE, WidthPrefixedU64);
+
+#[test]
+fn width_prefixed_round_trip() {
+    let cases: &[(u64, usize)] = &[
+        (0, 1),
+        (0x1F, 1),
+        (0x20, 2),
+        ((1 << 13) - 1, 2),
+        (1 << 13, 3),
+        ((1 << 45) - 1, 6),
+        (1 << 45, 7),
+        ((1 << 53) - 1, 7),
+        (1 << 53, 9),
+        (u64::MAX, 9),
+    ];
+
+    let mut encoded = vec![0xAA];
+    for &(value, len) in cases {
+        let pos = encoded.len();
+        assert_eq!(write_width_prefixed_u64(&mut encoded, pos, value), len, "value: {:#x}", value);
+        assert_eq!((encoded[pos] >> 5) as usize, cmp::min(len, 8) - 1);
+    }
+
+    let mut position = 1;
+    for &(value, len) in cases {
+        assert_eq!(read_width_prefixed_u64(&encoded, position), (value, len));
+        position += len;
+    }
+
+    assert_eq!(position, encoded.len());
+}
+
+
+// Fixed-shape records ---------------------------------------------------------
+//
+// Decoding a record like `[u32, u64, u8]` field by field without having to
+// thread the position through every call. Integers are leb128, single bytes
+// are written as is.
+
+pub struct RecordWriter {
+    out: Vec<u8>,
+}
+
+impl RecordWriter {
+    pub fn new() -> RecordWriter {
+        RecordWriter { out: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut RecordWriter {
+        let position = self.out.len();
+        write_to_vec(&mut self.out, position, value);
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut RecordWriter {
+        let position = self.out.len();
+        write_leb128c_u32(&mut self.out, position, value);
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut RecordWriter {
+        let position = self.out.len();
+        write_leb128c_u64(&mut self.out, position, value);
+        self
+    }
+
+    pub fn position(&self) -> usize {
+        self.out.len()
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.out
+    }
+}
+
+impl Default for RecordWriter {
+    fn default() -> RecordWriter {
+        RecordWriter::new()
+    }
+}
+
+pub struct RecordReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    pub fn new(data: &'a [u8], position: usize) -> RecordReader<'a> {
+        RecordReader { data, position }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.data.get(self.position).ok_or(DecodeError::UnexpectedEof)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        let value = self.leb128(u32::MAX as u128)?;
+        Ok(value as u32)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        let value = self.leb128(u64::MAX as u128)?;
+        Ok(value as u64)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    // On error the position stays where it was.
+    fn leb128(&mut self, max: u128) -> Result<u128, DecodeError> {
+        let (value, read) = read_leb128_ref_checked(self.data, self.position)?;
+
+        if value > max {
+            return Err(DecodeError::Overflow);
+        }
+
+        self.position += read;
+        Ok(value)
+    }
+}
+
+#[test]
+fn record_round_trip() {
+    let records: &[(u32, u64, u8)] = &[
+        (0, 0, 0),
+        (300, 1 << 35, 0x80),
+        (u32::MAX, u64::MAX, u8::MAX),
+    ];
+
+    let mut writer = RecordWriter::new();
+    for &(a, b, c) in records {
+        writer.u32(a).u64(b).u8(c);
+    }
+    let len = writer.position();
+    let encoded = writer.finish();
+    assert_eq!(len, encoded.len());
+
+    let mut reader = RecordReader::new(&encoded, 0);
+    for &(a, b, c) in records {
+        assert_eq!(reader.u32(), Ok(a));
+        assert_eq!(reader.u64(), Ok(b));
+        assert_eq!(reader.u8(), Ok(c));
+    }
+    assert_eq!(reader.position(), encoded.len());
+    assert_eq!(reader.u8(), Err(DecodeError::UnexpectedEof));
+
+    // A u64 that doesn't fit into the u32 field. The position stays put.
+    let mut writer = RecordWriter::new();
+    writer.u64(u32::MAX as u64 + 1);
+    let encoded = writer.finish();
+    let mut reader = RecordReader::new(&encoded, 0);
+    assert_eq!(reader.u32(), Err(DecodeError::Overflow));
+    assert_eq!(reader.position(), 0);
+}
+
+#[test]
+fn record_reader_remaining() {
+    let mut writer = RecordWriter::new();
+    writer.u32(300).u64(u64::MAX).u32(u32::MAX).u64(0);
+    let encoded = writer.finish();
+
+    let mut reader = RecordReader::new(&encoded, 0);
+    assert_eq!(reader.remaining(), encoded.len());
+
+    assert_eq!(reader.u32(), Ok(300));
+    assert_eq!(reader.remaining(), encoded.len() - 2);
+    assert_eq!(reader.u64(), Ok(u64::MAX));
+    assert_eq!(reader.u32(), Ok(u32::MAX));
+    assert_eq!(reader.remaining(), 1);
+    assert_eq!(reader.u64(), Ok(0));
+
+    assert_eq!(reader.remaining(), 0);
+    assert_eq!(reader.u64(), Err(DecodeError::UnexpectedEof));
+    assert_eq!(reader.remaining(), 0);
+}
+
+// Compare with read_leb128_ref_u64_*: the reader keeps the position itself
+// instead of the loop adding up the returned lengths.
+impl_read_bench!(read_record_reader_u64_dep_graph, Usize, u64, DEP_GRAPH, reader);
+impl_read_bench!(read_record_reader_u64_metadata, U64, u64, METADATA, reader);
+impl_read_bench!(read_record_reader_u64_query_cache, U64, u64, QUERY_CACHE, reader);
+
+
+// Worst-case dataset ----------------------------------------------------------
+//
+// The real datasets are dominated by small values, so most of their values
+// never reach the multi-byte loops. `MAX_LENGTH` only holds values that need
+// the longest encoding of their type, which bounds the tail cost of each
+// scheme.
+
+impl_bench!(write_leb128c_u32_max_length, U32, Leb128CU32, MAX_LENGTH);
+impl_bench!(write_leb128c_u64_max_length, U64, Leb128CU64, MAX_LENGTH);
+impl_bench!(write_leb128c_usize_max_length, Usize, Leb128CUsize, MAX_LENGTH);
+impl_bench!(write_shift_u64_max_length, U64, ShiftU64, MAX_LENGTH);
+impl_bench!(write_vlq_u64_max_length, U64, VlqU64, MAX_LENGTH);
+impl_bench!(write_sqlite4_u64_max_length, U64, Sqlite4U64, MAX_LENGTH);
+impl_bench!(write_width_prefixed_u64_max_length, U64, WidthPrefixedU64, MAX_LENGTH);
+
+impl_read_bench!(read_leb128_ref_u32_max_length, U32, read_leb128_ref_u32, MAX_LENGTH);
+impl_read_bench!(read_leb128_ref_u64_max_length, U64, read_leb128_ref_u64, MAX_LENGTH);
+impl_read_bench!(read_leb128_ref_usize_max_length, Usize, read_leb128_ref_usize, MAX_LENGTH);
+impl_read_bench!(read_leb128_fixed2_u64_max_length, U64, read_leb128_fixed2_u64, MAX_LENGTH);
+impl_read_bench!(read_leb128_unsafe_u64_max_length, U64, read_leb128_unsafe_u64, MAX_LENGTH);
+impl_read_bench!(read_leb128_weird_u64_max_length, U64, read_leb128_weird_u64, MAX_LENGTH);
+impl_read_bench!(read_leb128_fast_u64_max_length, U64, read_leb128_u64_fast, MAX_LENGTH);
+impl_read_bench!(read_leb128_wordwise_u64_max_length, U64, read_leb128_wordwise_u64, MAX_LENGTH);
+impl_read_bench!(read_vlq_u64_max_length, U64, read_vlq_u64, MAX_LENGTH, VlqU64);
+impl_read_bench!(read_sqlite4_u64_max_length, U64, read_sqlite4_varint_u64, MAX_LENGTH, Sqlite4U64);
+impl_read_bench!(read_width_prefixed_u64_max_length, U64, read_width_prefixed_u64, MAX_LENGTH, WidthPrefixedU64);
+
+#[cfg(feature = "std")]
+#[test]
+fn max_length_data_is_max_length() {
+    let data = load_test_data(MAX_LENGTH);
+    assert!(!data.is_empty());
+
+    for value in data.iter() {
+        let mut out = Vec::new();
+        match *value {
+            Value::U32(v) => assert_eq!(write_leb128c_u32(&mut out, 0, v), 5),
+            Value::U64(v) => assert_eq!(write_leb128c_u64(&mut out, 0, v), 10),
+            Value::Usize(v) => assert_eq!(write_leb128c_usize(&mut out, 0, v), USIZE_LEB128_SIZE),
+            _ => {}
+        }
+    }
+}
+
+
+// Rolling back speculative writes ---------------------------------------------
+//
+// For callers that encode a value they might end up discarding: remember the
+// position before writing and truncate back to it if needed.
+
+#[derive(Default)]
+pub struct CheckpointVec {
+    pub buf: Vec<u8>,
+}
+
+impl CheckpointVec {
+    pub fn new() -> CheckpointVec {
+        CheckpointVec { buf: Vec::new() }
+    }
+
+    pub fn checkpoint(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn rollback(&mut self, pos: usize) {
+        assert!(pos <= self.buf.len(), "rollback past the end of the buffer");
+        self.buf.truncate(pos);
+    }
+}
+
+#[test]
+fn checkpoint_vec_rollback() {
+    let mut out = CheckpointVec::new();
+
+    let mut position = 0;
+    position += write_leb128c_u64(&mut out.buf, position, 300);
+    position += write_leb128c_u32(&mut out.buf, position, 7);
+    let first_two = out.buf.clone();
+
+    let checkpoint = out.checkpoint();
+    assert_eq!(checkpoint, position);
+    write_leb128c_u64(&mut out.buf, position, u64::MAX);
+    assert_eq!(out.buf.len(), position + 10);
+
+    out.rollback(checkpoint);
+    assert_eq!(out.buf.len(), position);
+    assert_eq!(out.buf, first_two);
+    assert_eq!(read_leb128_ref_u64(&out.buf, 0), (300, 2));
+    assert_eq!(read_leb128_ref_u32(&out.buf, 2), (7, 1));
+}
+
+
+// Length-prefixed byte blobs --------------------------------------------------
+//
+// How metadata stores strings and byte arrays: the leb128 length followed by
+// the raw bytes. Reading hands out a borrow of the input instead of copying.
+
+pub fn write_bytes_leb128(out: &mut Vec<u8>, start_position: usize, payload: &[u8]) -> usize {
+    let len_size = write_leb128c_usize(out, start_position, payload.len());
+    write_slice_to_vec(out, start_position + len_size, payload);
+
+    len_size + payload.len()
+}
+
+pub fn read_bytes_leb128(data: &[u8], start_position: usize) -> Result<(&[u8], usize), DecodeError> {
+    let (len, read) = read_leb128_usize_checked(data, start_position)?;
+    let rest = &data[start_position + read ..];
+
+    if len > rest.len() {
+        return Err(DecodeError::UnexpectedEof);
+    }
+
+    Ok((&rest[.. len], read + len))
+}
+
+#[test]
+fn bytes_leb128_round_trip() {
+    let large: Vec<u8> = (0 .. 5000).map(|i| (i * 7) as u8).collect();
+
+    let mut out = vec![0xAA];
+    let empty_len = write_bytes_leb128(&mut out, 1, &[]);
+    assert_eq!(empty_len, 1);
+    let large_len = write_bytes_leb128(&mut out, 1 + empty_len, &large);
+    assert_eq!(large_len, 2 + large.len());
+    assert_eq!(out.len(), 1 + empty_len + large_len);
+
+    let (payload, read) = read_bytes_leb128(&out, 1).unwrap();
+    assert!(payload.is_empty());
+    assert_eq!(read, empty_len);
+
+    let (payload, read) = read_bytes_leb128(&out, 1 + empty_len).unwrap();
+    assert_eq!(payload, &large[..]);
+    assert_eq!(read, large_len);
+
+    // The payload is borrowed straight out of the input.
+    assert_eq!(payload.as_ptr(), out[1 + empty_len + 2 ..].as_ptr());
+
+    assert_eq!(read_bytes_leb128(&out[.. out.len() - 1], 1 + empty_len),
+               Err(DecodeError::UnexpectedEof));
+}
+
+// All u8 values of a dataset as one blob versus one leb128 value per byte,
+// where everything from 128 up takes two bytes.
+macro_rules! impl_bytes_blob_bench {
+    ($blob_bench:ident, $per_byte_bench:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $blob_bench {
+            use super::*;
+
+            impl_bench_data!(U8, $data);
+
+            #[inline]
+            pub fn write_blob(bytes: &[u8]) -> Vec<u8> {
+                let mut output = Vec::with_capacity(bytes.len() + MAX_LEB128_LEN_USIZE);
+                write_bytes_leb128(&mut output, 0, bytes);
+                output
+            }
+
+            #[inline]
+            pub fn write_per_byte(bytes: &[u8]) -> Vec<u8> {
+                let mut output = Vec::with_capacity(bytes.len() * 2);
+                let mut position = 0;
+                for &byte in bytes.iter() {
+                    position += write_leb128c_u64(&mut output, position, byte as u64);
+                }
+                output
+            }
+
+            #[test]
+            fn run_once() {
+                let bytes = test_data();
+
+                let blob = write_blob(&bytes);
+                assert_eq!(read_bytes_leb128(&blob, 0), Ok((&bytes[..], blob.len())));
+
+                assert_eq!(write_per_byte(&bytes), encode(&bytes));
+            }
+        }
+
+        impl_bytes_blob_bench!(@bench $blob_bench, $blob_bench, $blob_bench::write_blob);
+        impl_bytes_blob_bench!(@bench $per_byte_bench, $blob_bench, $blob_bench::write_per_byte);
+    );
+    (@bench $bench_name:ident, $module:ident, $write:path) => (
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let bytes = $module::test_data();
+            b.bytes = $module::input_bytes(&bytes);
+
+            let mut size = 0;
+            b.iter(|| {
+                let output = $write(&bytes);
+                size = output.len();
+                test::black_box(output);
+            });
+
+            print!("size: {} bytes, ", size);
+        }
+    )
+}
+
+impl_bytes_blob_bench!(write_bytes_blob_query_cache, write_bytes_per_byte_leb128_query_cache, QUERY_CACHE);
+
+#[test]
+fn bytes_leb128_all_byte_values() {
+    let payload: Vec<u8> = (0 ..= 255).collect();
+
+    let mut out = Vec::new();
+    let written = write_bytes_leb128(&mut out, 0, &payload);
+
+    // Two bytes of length, then the bytes as they are.
+    assert_eq!(written, 2 + 256);
+    assert_eq!(&out[2 ..], &payload[..]);
+    assert_eq!(read_bytes_leb128(&out, 0), Ok((&payload[..], written)));
+}
+
+
+// Leb128 read back to front ---------------------------------------------------
+//
+// For indexes appended to the end of a blob and read starting from its tail:
+// the bytes of the regular leb128 encoding in reverse order, so that a
+// backward scan sees the least significant group first and ends on the byte
+// without the continuation bit.
+
+pub fn write_leb128_reverse_u64(out: &mut Vec<u8>, value: u64) -> usize {
+    let mut bytes = [0u8; leb128_size!(u64)];
+    let len = write_unsigned_leb128_to(value as u128, |i, v| bytes[i] = v);
+
+    out.extend(bytes[.. len].iter().rev());
+    len
+}
+
+// `end_position` is one past the last byte of the value. Returns the value
+// and the number of bytes it took, so the next value ends where this one
+// starts.
+#[inline]
+pub fn read_leb128_reverse_u64(data: &[u8], end_position: usize) -> (u64, usize) {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut position = end_position;
+
+    loop {
+        position -= 1;
+        let byte = data[position];
+        result |= ((byte & 0x7F) as u64) << shift;
+        if (byte & 0x80) == 0 {
+            break;
+        }
+        shift += 7;
+    }
+
+    (result, end_position - position)
+}
+
+macro_rules! impl_reverse_bench {
+    ($bench_name:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!(@values $variant, $data);
+
+            pub fn encode_reverse(test_data: &[variant_ty!($variant)]) -> Vec<u8> {
+                let mut encoded = Vec::new();
+                for &val in test_data.iter() {
+                    write_leb128_reverse_u64(&mut encoded, val as u64);
+                }
+                encoded
+            }
+
+            #[inline]
+            pub fn decode_all(encoded: &[u8], test_data: &[variant_ty!($variant)], verify: bool) {
+                let mut position = encoded.len();
+                for &expected in test_data.iter().rev() {
+                    let (val, count) = read_leb128_reverse_u64(encoded, position);
+                    if verify {
+                        assert_eq!(val, expected as u64);
+                    }
+                    ::core::hint::black_box(val);
+                    position -= count;
+                }
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode_reverse(&test_data);
+                decode_all(&encoded, &test_data, true);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let encoded = $bench_name::encode_reverse(&test_data);
+
+            b.iter(|| $bench_name::decode_all(&encoded, &test_data, false));
+        }
+    )
+}
+
+impl_reverse_bench!(read_leb128_reverse_u64_metadata, U64, METADATA);
+impl_reverse_bench!(read_leb128_reverse_u64_dep_graph, U64, DEP_GRAPH);
+impl_reverse_bench!(read_leb128_reverse_u64_query_cache, U64, QUERY_CACHE);
+
+#[test]
+fn leb128_reverse_round_trip() {
+    let mut values = vec![0, 1, 127, 128, 300, u64::MAX];
+    for shift in 0 .. 64 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    let mut encoded = vec![0xAA];
+    for &val in values.iter() {
+        let mut forward = Vec::new();
+        let len = write_leb128c_u64(&mut forward, 0, val);
+        forward.reverse();
+
+        let start = encoded.len();
+        assert_eq!(write_leb128_reverse_u64(&mut encoded, val), len);
+        assert_eq!(&encoded[start ..], &forward[..]);
+    }
+
+    let mut position = encoded.len();
+    for &val in values.iter().rev() {
+        let (decoded, read) = read_leb128_reverse_u64(&encoded, position);
+        assert_eq!(decoded, val);
+        position -= read;
+    }
+
+    assert_eq!(position, 1);
+}
+
+
+// Leb128 without data-dependent branches --------------------------------------
+//
+// Always goes through all ten bytes a u64 can take. Instead of breaking out of
+// the loop at the terminating byte, `live` turns to zero after it and masks
+// out everything that follows, so the compiler can use conditional moves.
+
+#[inline]
+pub fn read_leb128_branchless_u64(data: &[u8], start_position: usize) -> (u64, usize) {
+    let mut result = 0;
+    let mut len = 0;
+    let mut live = !0u64;
+
+    for i in 0 .. leb128_size!(u64) {
+        // Bytes past the end of the buffer can only follow the terminator of a
+        // well-formed value, where they are masked out anyway.
+        let byte = *data.get(start_position + i).unwrap_or(&0) as u64;
+
+        result |= ((byte & 0x7F) << (i * 7)) & live;
+        len += (live & 1) as usize;
+        live &= 0u64.wrapping_sub(byte >> 7);
+    }
+
+    (result, len)
+}
+
+impl_varint_decoder!(Leb128BranchlessU64, read_leb128_branchless_u64);
+
+impl_read_bench!(read_leb128_branchless_u64_metadata, U64, read_leb128_branchless_u64, METADATA);
+impl_read_bench!(read_leb128_branchless_u64_dep_graph, Usize, read_leb128_branchless_u64, DEP_GRAPH);
+impl_read_bench!(read_leb128_branchless_u64_query_cache, U64, read_leb128_branchless_u64, QUERY_CACHE);
+impl_read_bench!(read_leb128_branchless_u64_max_length, U64, read_leb128_branchless_u64, MAX_LENGTH);
+
+#[cfg(feature = "std")]
+#[test]
+fn read_leb128_branchless_u64_matches_fixed() {
+    let mut encoded = Vec::new();
+
+    for value in 0 .. 256 {
+        let pos = encoded.len();
+        write_leb128c_u64(&mut encoded, pos, value);
+    }
+
+    for &name in &[METADATA, DEP_GRAPH, QUERY_CACHE, MAX_LENGTH] {
+        for entry in load_test_data(name).iter() {
+            let value = match *entry {
+                Value::U8(v) => v as u64,
+                Value::U16(v) => v as u64,
+                Value::U32(v) => v as u64,
+                Value::U64(v) => v,
+                Value::Usize(v) => v as u64,
+                _ => continue,
+            };
+
+            let pos = encoded.len();
+            write_leb128c_u64(&mut encoded, pos, value);
+        }
+    }
+
+    let mut position = 0;
+    while position < encoded.len() {
+        let expected = read_leb128_fixed_u64(&encoded, position);
+        assert_eq!(read_leb128_branchless_u64(&encoded, position), expected);
+        position += expected.1;
+    }
+}
+
+
+// Values at aligned offsets ---------------------------------------------------
+//
+// For formats where every value has to start at a multiple of `align` within
+// the buffer. The gap in front of a value is filled with zero bytes.
+
+pub fn write_leb128_aligned_u64(out: &mut Vec<u8>, align: usize, value: u64) -> usize {
+    let start_position = out.len();
+    let aligned = start_position.next_multiple_of(align);
+
+    out.resize(aligned, 0);
+    let len = write_leb128c_u64(out, aligned, value);
+
+    aligned - start_position + len
+}
+
+pub fn read_leb128_aligned_u64(data: &[u8], start_position: usize, align: usize) -> (u64, usize) {
+    let aligned = start_position.next_multiple_of(align);
+    debug_assert!(data[start_position .. aligned].iter().all(|&b| b == 0), "non-zero padding byte");
+
+    let (value, len) = read_leb128_ref_u64(data, aligned);
+    (value, aligned - start_position + len)
+}
+
+#[test]
+fn leb128_aligned_round_trip() {
+    // Start offsets 0 through 4 need 0, 3, 2, 1 and 0 bytes of padding.
+    for prefix in 0 .. 5 {
+        let mut out = vec![0xFF; prefix];
+        let padding = (4 - prefix % 4) % 4;
+
+        assert_eq!(write_leb128_aligned_u64(&mut out, 4, 300), padding + 2);
+        assert_eq!(out.len(), prefix + padding + 2);
+        assert!(out[prefix .. prefix + padding].iter().all(|&b| b == 0));
+
+        let next = out.len();
+        let second_padding = (4 - next % 4) % 4;
+        assert_eq!(write_leb128_aligned_u64(&mut out, 4, u64::MAX), second_padding + 10);
+
+        assert_eq!(read_leb128_aligned_u64(&out, prefix, 4), (300, padding + 2));
+        assert_eq!(read_leb128_aligned_u64(&out, next, 4), (u64::MAX, second_padding + 10));
+    }
+
+    let mut out = Vec::new();
+    assert_eq!(write_leb128_aligned_u64(&mut out, 1, 5), 1);
+    assert_eq!(out, [5]);
+}
+
+
+// Counting continuation bytes -------------------------------------------------
+//
+// Tells how much of a decode run is spent in the multi-byte path.
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeStats {
+    pub total_bytes: usize,
+    pub continuation_bytes: usize,
+    pub values: usize,
+}
+
+#[inline]
+pub fn read_leb128_u64_counting(data: &[u8], start_position: usize, stats: &mut DecodeStats) -> (u64, usize) {
+    let (value, len) = read_leb128_ref_u64(data, start_position);
+
+    // Every byte but the last one of a value has the continuation bit set.
+    stats.total_bytes += len;
+    stats.continuation_bytes += len - 1;
+    stats.values += 1;
+
+    (value, len)
+}
+
+macro_rules! impl_counting_bench {
+    ($bench_name:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[inline]
+            pub fn decode_all(encoded: &[u8], test_data: &[variant_ty!($variant)], verify: bool) -> DecodeStats {
+                let mut stats = DecodeStats::default();
+                let mut position = 0;
+                for &expected in test_data.iter() {
+                    let (val, count) = read_leb128_u64_counting(encoded, position, &mut stats);
+                    if verify {
+                        assert_eq!(val, expected as u64);
+                    }
+                    ::core::hint::black_box(val);
+                    position += count;
+                }
+                stats
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode(&test_data);
+                let stats = decode_all(&encoded, &test_data, true);
+                assert_eq!(stats.values, test_data.len());
+                assert_eq!(stats.total_bytes, encoded.len());
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let encoded = $bench_name::encode(&test_data);
+
+            let mut stats = DecodeStats::default();
+
+            b.iter(|| {
+                stats = $bench_name::decode_all(&encoded, &test_data, false);
+            });
+
+            if stats.values > 0 {
+                print!("continuation bytes per value: {:.3}, ",
+                       stats.continuation_bytes as f64 / stats.values as f64);
+            }
+        }
+    )
+}
+
+impl_counting_bench!(read_leb128_counting_u64_metadata, U64, METADATA);
+impl_counting_bench!(read_leb128_counting_u64_dep_graph, Usize, DEP_GRAPH);
+impl_counting_bench!(read_leb128_counting_u64_query_cache, U64, QUERY_CACHE);
+impl_counting_bench!(read_leb128_counting_u64_max_length, U64, MAX_LENGTH);
+
+#[test]
+fn read_leb128_u64_counting_single_bytes() {
+    let mut encoded = Vec::new();
+    for value in 0 .. 128 {
+        let pos = encoded.len();
+        write_leb128c_u64(&mut encoded, pos, value);
+    }
+
+    let mut stats = DecodeStats::default();
+    let mut position = 0;
+    while position < encoded.len() {
+        position += read_leb128_u64_counting(&encoded, position, &mut stats).1;
+    }
+
+    assert_eq!(stats, DecodeStats { total_bytes: 128, continuation_bytes: 0, values: 128 });
+
+    let mut encoded = Vec::new();
+    write_leb128c_u64(&mut encoded, 0, u64::MAX);
+    read_leb128_u64_counting(&encoded, 0, &mut stats);
+    assert_eq!(stats, DecodeStats { total_bytes: 138, continuation_bytes: 9, values: 129 });
+}
+
+
+// Picking the smallest of several encodings -----------------------------------
+//
+// Every value gets encoded with leb128, lesqlite and prefix varint and the
+// shortest one is written after a tag byte saying which it is. Whether that
+// beats the individual schemes depends on the tag not eating up the savings.
+
+const ADAPTIVE_LEB128: u8 = 0;
+const ADAPTIVE_LESQLITE: u8 = 1;
+const ADAPTIVE_PREFIX: u8 = 2;
+
+pub fn write_adaptive_u64(out: &mut Vec<u8>, start_position: usize, value: u64) -> usize {
+    let mut scratch = Vec::with_capacity(leb128_size!(u128));
+
+    let leb128 = write_leb128c_u64(&mut scratch, 0, value);
+    let lesqlite = impl_write_u128_lesqlite(&mut scratch, 0, value as u128);
+    let prefix = impl_write_u128_prefix(&mut scratch, 0, value as u128);
+
+    // Ties go to the cheaper decoder.
+    let len = if leb128 <= lesqlite && leb128 <= prefix {
+        write_to_vec(out, start_position, ADAPTIVE_LEB128);
+        write_leb128c_u64(out, start_position + 1, value)
+    } else if lesqlite <= prefix {
+        write_to_vec(out, start_position, ADAPTIVE_LESQLITE);
+        impl_write_u128_lesqlite(out, start_position + 1, value as u128)
+    } else {
+        write_to_vec(out, start_position, ADAPTIVE_PREFIX);
+        impl_write_u128_prefix(out, start_position + 1, value as u128)
+    };
+
+    len + 1
+}
+
+#[inline]
+pub fn read_adaptive_u64(data: &[u8], start_position: usize) -> (u64, usize) {
+    let (value, len) = match data[start_position] {
+        ADAPTIVE_LEB128 => read_leb128_ref_u64(data, start_position + 1),
+        ADAPTIVE_LESQLITE => {
+            let (value, len) = read_lesqlite_u128(data, start_position + 1);
+            (value as u64, len)
+        }
+        ADAPTIVE_PREFIX => {
+            let (value, len) = read_prefix_u128(data, start_position + 1);
+            (value as u64, len)
+        }
+        tag => panic!("unknown adaptive tag {}", tag),
+    };
+
+    (value, len + 1)
+}
+
+impl_varint_codec!(AdaptiveU64, write_adaptive_u64, read_adaptive_u64, u64);
+
+impl_bench!(write_adaptive_u64_metadata, U64, AdaptiveU64, METADATA, encoded);
+impl_bench!(write_adaptive_u64_dep_graph, U64, AdaptiveU64, DEP_GRAPH, encoded);
+impl_bench!(write_adaptive_u64_query_cache, U64, AdaptiveU64, QUERY_CACHE, encoded);
+
+impl_read_bench!(read_adaptive_u64_metadata, U64, read_adaptive_u64, METADATA, AdaptiveU64, encoded);
+impl_read_bench!(read_adaptive_u64_dep_graph, U64, read_adaptive_u64, DEP_GRAPH, AdaptiveU64, encoded);
+impl_read_bench!(read_adaptive_u64_query_cache, U64, read_adaptive_u64, QUERY_CACHE, AdaptiveU64, encoded);
+
+#[test]
+fn adaptive_round_trip() {
+    let mut values = vec![0, 1, 127, 128, 240, 241, 2287, 2288, u64::MAX];
+    for shift in 0 .. 64 {
+        values.push(1 << shift);
+        values.push((1 << shift) - 1);
+    }
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        let len = write_adaptive_u64(&mut encoded, position, val);
+
+        let mut leb128 = Vec::new();
+        assert!(len <= write_leb128c_u64(&mut leb128, 0, val) + 1);
+        position += len;
+    }
+
+    let mut position = 0;
+    for &val in values.iter() {
+        let (decoded, read) = read_adaptive_u64(&encoded, position);
+        assert_eq!(decoded, val);
+        position += read;
+    }
+
+    assert_eq!(position, encoded.len());
+}
+
+
+// Inline output buffer --------------------------------------------------------
+//
+// Encoding a single value into a fresh `Vec` pays for an allocation. A small
+// buffer keeps the first few bytes on the stack and only moves to the heap
+// once it runs out of room.
+
+pub trait EncodeOutput {
+    // Same contract as `write_to_vec`: `position` is at most the current
+    // length, writing at the length appends.
+    fn write_at(&mut self, position: usize, byte: u8);
+    fn as_slice(&self) -> &[u8];
+}
+
+impl EncodeOutput for Vec<u8> {
+    #[inline]
+    fn write_at(&mut self, position: usize, byte: u8) {
+        write_to_vec(self, position, byte);
+    }
+
+    #[inline]
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+}
+
+const SMALL_BUF_CAPACITY: usize = 16;
+
+pub enum SmallBuf {
+    Inline([u8; SMALL_BUF_CAPACITY], usize),
+    Spilled(Vec<u8>),
+}
+
+impl SmallBuf {
+    pub fn new() -> SmallBuf {
+        SmallBuf::Inline([0; SMALL_BUF_CAPACITY], 0)
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        match *self {
+            SmallBuf::Inline(..) => false,
+            SmallBuf::Spilled(_) => true,
+        }
+    }
+}
+
+impl Default for SmallBuf {
+    fn default() -> SmallBuf {
+        SmallBuf::new()
+    }
+}
+
+impl EncodeOutput for SmallBuf {
+    #[inline]
+    fn write_at(&mut self, position: usize, byte: u8) {
+        match *self {
+            SmallBuf::Inline(ref mut bytes, ref mut len) if position < SMALL_BUF_CAPACITY => {
+                assert!(position <= *len);
+                bytes[position] = byte;
+                if position == *len {
+                    *len += 1;
+                }
+                return;
+            }
+            SmallBuf::Inline(ref bytes, len) => {
+                let mut spilled = Vec::with_capacity(SMALL_BUF_CAPACITY * 2);
+                spilled.extend_from_slice(&bytes[.. len]);
+                *self = SmallBuf::Spilled(spilled);
+            }
+            SmallBuf::Spilled(_) => {}
+        }
+
+        if let SmallBuf::Spilled(ref mut vec) = *self {
+            write_to_vec(vec, position, byte);
+        }
+    }
+
+    #[inline]
+    fn as_slice(&self) -> &[u8] {
+        match *self {
+            SmallBuf::Inline(ref bytes, len) => &bytes[.. len],
+            SmallBuf::Spilled(ref vec) => vec,
+        }
+    }
+}
+
+#[inline]
+pub fn write_leb128c_u64_to<O: EncodeOutput>(out: &mut O, start_position: usize, value: u64) -> usize {
+    write_unsigned_leb128_to(value as u128, |i, v| out.write_at(start_position + i, v))
+}
+
+// Every value goes into an output of its own, so the allocation (or its
+// absence) is part of what gets measured.
+macro_rules! impl_output_bench {
+    ($bench_name:ident, $output:ty, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[inline]
+            pub fn write_each<F: FnMut(&$output)>(test_data: &[variant_ty!($variant)], mut f: F) {
+                for &val in test_data.iter() {
+                    let mut out = <$output>::default();
+                    write_leb128c_u64_to(&mut out, 0, val as u64);
+                    f(&out);
+                }
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let mut joined = Vec::new();
+                write_each(&test_data, |out| joined.extend_from_slice(out.as_slice()));
+                assert_eq!(joined, encode(&test_data));
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            b.iter(|| $bench_name::write_each(&test_data, |out| { test::black_box(out); }));
+        }
+    )
+}
+
+impl_output_bench!(write_leb128c_u64_vec_query_cache, Vec<u8>, U64, QUERY_CACHE);
+impl_output_bench!(write_leb128c_u64_small_buf_query_cache, SmallBuf, U64, QUERY_CACHE);
+
+#[test]
+fn small_buf_matches_vec() {
+    let mut vec = Vec::new();
+    let mut small = SmallBuf::new();
+
+    let mut position = 0;
+    for &value in &[0, 300, u64::MAX] {
+        assert_eq!(write_leb128c_u64_to(&mut small, position, value),
+                   write_leb128c_u64_to(&mut vec, position, value));
+        position += leb128_len_u64(value);
+        assert_eq!(small.as_slice(), &vec[..]);
+    }
+
+    // 1 + 2 + 10 bytes still fit inline.
+    assert!(!small.is_spilled());
+
+    write_leb128c_u64_to(&mut small, position, u64::MAX);
+    write_leb128c_u64_to(&mut vec, position, u64::MAX);
+    assert!(small.is_spilled());
+    assert_eq!(small.as_slice(), &vec[..]);
+
+    // Overwriting in place works on both sides of the spill.
+    small.write_at(0, 0x7F);
+    vec.write_at(0, 0x7F);
+    assert_eq!(small.as_slice(), &vec[..]);
+}
+
+
+// Fixed two bytes with an escape ----------------------------------------------
+//
+// Values below 2^15 always take a little-endian u16 with the top bit clear.
+// Anything larger gets the top bit set as an escape marker, followed by the
+// full value as a raw little-endian u32. Small data never branches on the
+// length.
+
+const FLESCAPE_FLAG: u16 = 0x8000;
+
+#[inline]
+pub fn write_flescape_u32(out: &mut Vec<u8>, start_position: usize, value: u32) -> usize {
+    if value < FLESCAPE_FLAG as u32 {
+        write_slice_to_vec(out, start_position, &(value as u16).to_le_bytes());
+        2
+    } else {
+        write_slice_to_vec(out, start_position, &FLESCAPE_FLAG.to_le_bytes());
+        write_slice_to_vec(out, start_position + 2, &value.to_le_bytes());
+        6
+    }
+}
+
+#[inline]
+pub fn read_flescape_u32(data: &[u8], start_position: usize) -> (u32, usize) {
+    let word = u16::from_le_bytes([data[start_position], data[start_position + 1]]);
+
+    if word & FLESCAPE_FLAG == 0 {
+        (word as u32, 2)
+    } else {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[start_position + 2 .. start_position + 6]);
+        (u32::from_le_bytes(bytes), 6)
+    }
+}
+
+impl_varint_codec!(FlEscapeU32, write_flescape_u32, read_flescape_u32, u32);
+
+impl_bench!(write_flescape_u32_query_cache, U32, FlEscapeU32, QUERY_CACHE);
+impl_read_bench!(read_flescape_u32_query_cache, U32, read_flescape_u32, QUERY_CACHE, FlEscapeU32);
+
+#[test]
+fn flescape_round_trip() {
+    let cases: &[(u32, usize)] = &[
+        (0, 2),
+        (1, 2),
+        (0x7FFF, 2),
+        (0x8000, 6),
+        (0x8001, 6),
+        (u32::MAX, 6),
+    ];
+
+    let mut encoded = Vec::new();
+    let mut position = 0;
+    for &(value, len) in cases {
+        assert_eq!(write_flescape_u32(&mut encoded, position, value), len);
+        position += len;
+    }
+
+    assert_eq!(&encoded[4 .. 6], &[0xFF, 0x7F]);
+    assert_eq!(&encoded[6 .. 12], &[0x00, 0x80, 0x00, 0x80, 0x00, 0x00]);
+
+    let mut position = 0;
+    for &(value, len) in cases {
+        assert_eq!(read_flescape_u32(&encoded, position), (value, len));
+        position += len;
+    }
+
+    assert_eq!(position, encoded.len());
+}
+
+
+// Reading across chunk boundaries ---------------------------------------------
+//
+// For input that arrives in several buffers, where a value may start in one
+// chunk and end in the next. The cursor is `(chunk_index, offset)` and is only
+// advanced if a whole value could be read.
+
+pub fn read_leb128_u64_chunked(chunks: &[&[u8]], cursor: &mut (usize, usize)) -> Result<u64, DecodeError> {
+    let (mut chunk, mut offset) = *cursor;
+    let mut result = 0;
+    let mut shift = 0;
+
+    for i in 0 .. leb128_size!(u64) {
+        // Skip over exhausted (or empty) chunks.
+        while chunk < chunks.len() && offset == chunks[chunk].len() {
+            chunk += 1;
+            offset = 0;
+        }
+
+        let byte = *chunks.get(chunk).and_then(|c| c.get(offset)).ok_or(DecodeError::UnexpectedEof)?;
+        offset += 1;
+
+        // The tenth group only has room for the single top bit of a u64.
+        if i == leb128_size!(u64) - 1 && (byte & 0x7E) != 0 {
+            return Err(DecodeError::Overflow);
+        }
+
+        result |= ((byte & 0x7F) as u64) << shift;
+        if (byte & 0x80) == 0 {
+            *cursor = (chunk, offset);
+            return Ok(result);
+        }
+        shift += 7;
+    }
+
+    Err(DecodeError::Overlong)
+}
+
+#[test]
+fn read_leb128_u64_chunked_every_split() {
+    for &value in &[300, 1 << 35, u64::MAX] {
+        let mut encoded = vec![0x05];
+        write_leb128c_u64(&mut encoded, 1, value);
+        encoded.push(0x07);
+
+        for split in 0 ..= encoded.len() {
+            let chunks = [&encoded[.. split], &[][..], &encoded[split ..]];
+            let mut cursor = (0, 0);
+
+            assert_eq!(read_leb128_u64_chunked(&chunks, &mut cursor), Ok(5));
+            assert_eq!(read_leb128_u64_chunked(&chunks, &mut cursor), Ok(value), "split at {}", split);
+            assert_eq!(read_leb128_u64_chunked(&chunks, &mut cursor), Ok(7));
+
+            let end = cursor;
+            assert_eq!(read_leb128_u64_chunked(&chunks, &mut cursor), Err(DecodeError::UnexpectedEof));
+            assert_eq!(cursor, end);
+        }
+    }
+}
+
+#[test]
+fn read_leb128_u64_chunked_overflow() {
+    let encoded = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F];
+
+    // The overflowing tenth byte is the first byte of the second chunk.
+    let chunks = [&encoded[.. 9], &encoded[9 ..]];
+    let mut cursor = (0, 0);
+
+    assert_eq!(read_leb128_u64_chunked(&chunks, &mut cursor), Err(DecodeError::Overflow));
+    assert_eq!(cursor, (0, 0));
+}
+
+
+// Converting between encodings ------------------------------------------------
+//
+// Decodes a whole buffer with one scheme and encodes every value again with
+// another, e.g. to migrate stored data from lesqlite to leb128.
+
+// Longest u64 encoding of all the schemes (prefix and lesqlite in their u128
+// flavors).
+const TRANSCODE_MAX_LEN: usize = 17;
+
+pub fn transcode_u64<R, W>(src: &[u8], mut src_reader: R, mut dst_writer: W) -> Result<Vec<u8>, DecodeError>
+    where R: FnMut(&[u8], usize) -> (u64, usize),
+          W: FnMut(&mut Vec<u8>, usize, u64) -> usize
+{
+    let mut out = Vec::with_capacity(src.len());
+    let mut position = 0;
+
+    let safe_end = src.len().saturating_sub(TRANSCODE_MAX_LEN);
+    while position < safe_end {
+        let (val, read) = src_reader(src, position);
+        position += read;
+        let pos = out.len();
+        dst_writer(&mut out, pos, val);
+    }
+
+    // The readers index without checking, so the last few values are decoded
+    // from a zero-padded copy. A value running past the end of `src` then
+    // shows up as reading too far instead of as a panic.
+    let tail_len = src.len() - position;
+    let mut tail = [0u8; 2 * TRANSCODE_MAX_LEN];
+    tail[.. tail_len].copy_from_slice(&src[position ..]);
+
+    let mut position = 0;
+    while position < tail_len {
+        let (val, read) = src_reader(&tail, position);
+        position += read;
+        if position > tail_len {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let pos = out.len();
+        dst_writer(&mut out, pos, val);
+    }
+
+    Ok(out)
+}
+
+fn read_lesqlite_u64(data: &[u8], start_position: usize) -> (u64, usize) {
+    let (val, read) = read_lesqlite_u128(data, start_position);
+    (val as u64, read)
+}
+
+fn write_lesqlite_u64(out: &mut Vec<u8>, start_position: usize, value: u64) -> usize {
+    impl_write_u128_lesqlite(out, start_position, value as u128)
+}
+
+macro_rules! impl_transcode_bench {
+    ($bench_name:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[inline]
+            pub fn transcode(encoded: &[u8]) -> Vec<u8> {
+                transcode_u64(encoded, read_leb128_ref_u64, write_lesqlite_u64).unwrap()
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let transcoded = transcode(&encode(&test_data));
+
+                let mut position = 0;
+                for &expected in test_data.iter() {
+                    let (val, count) = read_lesqlite_u64(&transcoded, position);
+                    assert_eq!(val, expected as u64);
+                    position += count;
+                }
+                assert_eq!(position, transcoded.len());
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            let encoded = $bench_name::encode(&test_data);
+
+            b.bytes = encoded.len() as u64;
+
+            b.iter(|| {
+                test::black_box($bench_name::transcode(&encoded));
+            });
+        }
+    )
+}
+
+impl_transcode_bench!(transcode_leb128_lesqlite_u64_metadata, U64, METADATA);
+impl_transcode_bench!(transcode_leb128_lesqlite_u64_dep_graph, U64, DEP_GRAPH);
+impl_transcode_bench!(transcode_leb128_lesqlite_u64_query_cache, U64, QUERY_CACHE);
+
+#[cfg(feature = "std")]
+#[test]
+fn transcode_leb128_lesqlite_round_trip() {
+    for &name in &[METADATA, DEP_GRAPH, QUERY_CACHE] {
+        let mut leb128 = Vec::new();
+        let mut lesqlite = Vec::new();
+
+        for entry in load_test_data(name).iter() {
+            if let Value::U64(val) = *entry {
+                let pos = leb128.len();
+                write_leb128c_u64(&mut leb128, pos, val);
+                let pos = lesqlite.len();
+                write_lesqlite_u64(&mut lesqlite, pos, val);
+            }
+        }
+
+        let transcoded = transcode_u64(&leb128, read_leb128_ref_u64, write_lesqlite_u64).unwrap();
+        assert_eq!(transcoded, lesqlite);
+
+        let back = transcode_u64(&transcoded, read_lesqlite_u64, write_leb128c_u64).unwrap();
+        assert_eq!(back, leb128);
+    }
+
+    let mut truncated = Vec::new();
+    write_leb128c_u64(&mut truncated, 0, u64::MAX);
+    truncated.pop();
+    assert_eq!(transcode_u64(&truncated, read_leb128_ref_u64, write_lesqlite_u64),
+               Err(DecodeError::UnexpectedEof));
+}
+
+
+// Zigzag deltas ---------------------------------------------------------------
+//
+// For columns where neighboring values are close to each other: the first
+// value, then the difference to the previous value for every other one. The
+// differences can be negative, so they go through `zigzag_encode_i64` before
+// being written as leb128.
+
+pub fn encode_zigzag_delta_i64(values: &[i64]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut previous = 0i64;
+
+    for &value in values {
+        let pos = out.len();
+        write_leb128c_u64(&mut out, pos, zigzag_encode_i64(value.wrapping_sub(previous)));
+        previous = value;
+    }
+
+    out
+}
+
+pub fn decode_zigzag_delta_i64(data: &[u8]) -> Vec<i64> {
+    let mut values = Vec::new();
+    let mut previous = 0i64;
+    let mut position = 0;
+
+    while position < data.len() {
+        let (delta, read) = read_leb128_ref_u64(data, position);
+        previous = previous.wrapping_add(zigzag_decode_i64(delta));
+        values.push(previous);
+        position += read;
+    }
+
+    values
+}
+
+// Only prints sizes: plain leb128 of the two's complement bit pattern, zigzag
+// without deltas and zigzag deltas.
+macro_rules! impl_zigzag_delta_bench {
+    ($bench_name:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!(@values I64, $data);
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                assert_eq!(decode_zigzag_delta_i64(&encode_zigzag_delta_i64(&test_data)), test_data);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let mut encoded = Vec::new();
+            b.iter(|| {
+                encoded = encode_zigzag_delta_i64(&test_data);
+            });
+
+            let plain: usize = test_data.iter().map(|&v| leb128_len_u64(v as u64)).sum();
+            let zigzag: usize = test_data.iter().map(|&v| leb128_len_u64(zigzag_encode_i64(v))).sum();
+
+            print!("leb128: {} bytes, zigzag: {} bytes, zigzag delta: {} bytes, ",
+                   plain, zigzag, encoded.len());
+        }
+    )
+}
+
+impl_zigzag_delta_bench!(write_zigzag_delta_i64_metadata, METADATA);
+impl_zigzag_delta_bench!(write_zigzag_delta_i64_query_cache, QUERY_CACHE);
+
+#[test]
+fn zigzag_delta_round_trip() {
+    for &(value, zigzag) in &[(0, 0), (-1, 1), (1, 2), (-2, 3), (i64::MAX, u64::MAX - 1), (i64::MIN, u64::MAX)] {
+        assert_eq!(zigzag_encode_i64(value), zigzag);
+        assert_eq!(zigzag_decode_i64(zigzag), value);
+    }
+
+    let values = [100, 105, 103, 103, -20, 7, i64::MIN, i64::MAX, 0];
+    let encoded = encode_zigzag_delta_i64(&values);
+    assert_eq!(&encoded[.. 4], &[0xC8, 0x01, 0x0A, 0x03]);
+    assert_eq!(decode_zigzag_delta_i64(&encoded), values);
+
+    assert!(encode_zigzag_delta_i64(&[]).is_empty());
+    assert!(decode_zigzag_delta_i64(&[]).is_empty());
+}
+
+
+// Scanning lengths ------------------------------------------------------------
+//
+// The length of a leb128 value only depends on where the first byte without
+// the continuation bit is, so it can be found without building the value.
+// That is all a reader needs for stepping over fields it doesn't care about.
+
+#[inline]
+pub fn leb128_encoded_len(data: &[u8], pos: usize) -> usize {
+    let mut position = pos;
+    while data[position] & 0x80 != 0 {
+        position += 1;
+    }
+
+    position + 1 - pos
+}
+
+macro_rules! impl_scan_len_bench {
+    ($bench_name:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[inline]
+            pub fn scan(encoded: &[u8], count: usize) -> usize {
+                let mut position = 0;
+                for _ in 0 .. count {
+                    position += leb128_encoded_len(encoded, position);
+                }
+                position
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode(&test_data);
+                assert_eq!(scan(&encoded, test_data.len()), encoded.len());
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            let encoded = $bench_name::encode(&test_data);
+
+            // Measured against the encoded bytes, there are no values to
+            // relate it to.
+            b.bytes = encoded.len() as u64;
+
+            b.iter(|| {
+                test::black_box($bench_name::scan(&encoded, test_data.len()));
+            });
+        }
+    )
+}
+
+impl_scan_len_bench!(scan_leb128_len_u64_metadata, U64, METADATA);
+impl_scan_len_bench!(scan_leb128_len_u64_dep_graph, Usize, DEP_GRAPH);
+impl_scan_len_bench!(scan_leb128_len_u64_query_cache, U64, QUERY_CACHE);
+impl_scan_len_bench!(scan_leb128_len_u64_max_length, U64, MAX_LENGTH);
+
+#[cfg(feature = "std")]
+#[test]
+fn leb128_encoded_len_matches_read() {
+    for &name in &[METADATA, QUERY_CACHE, MAX_LENGTH] {
+        let mut encoded = Vec::new();
+
+        for entry in load_test_data(name).iter() {
+            if let Value::U64(val) = *entry {
+                let pos = encoded.len();
+                write_leb128c_u64(&mut encoded, pos, val);
+            }
+        }
+
+        let mut position = 0;
+        while position < encoded.len() {
+            let (_, read) = read_leb128_ref_u64(&encoded, position);
+            assert_eq!(leb128_encoded_len(&encoded, position), read);
+            position += read;
+        }
+    }
+}
+
+// Unlike `leb128_encoded_len` this checks its input: running off the end of
+// `data` is an `UnexpectedEof` and a value longer than anything
+// `read_leb128_ref_checked` accepts is `Overlong`.
+pub fn skip_leb128(data: &[u8], pos: usize, n: usize) -> Result<usize, DecodeError> {
+    let mut position = pos;
+
+    for _ in 0 .. n {
+        let start = position;
+        loop {
+            if position - start == LEB128_REF_MAX_BYTES {
+                return Err(DecodeError::Overlong);
+            }
+            let byte = *data.get(position).ok_or(DecodeError::UnexpectedEof)?;
+            position += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+
+    Ok(position)
+}
+
+// Eight values per record, of which the reader only wants the last one.
+const SKIP_RECORD_LEN: usize = 8;
+
+macro_rules! impl_skip_bench {
+    ($skip_bench:ident, $read_bench:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $skip_bench {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            // Whole records only, so every skip finds all the values it expects.
+            pub fn records(test_data: &[variant_ty!($variant)]) -> &[variant_ty!($variant)] {
+                &test_data[.. test_data.len() / SKIP_RECORD_LEN * SKIP_RECORD_LEN]
+            }
+
+            #[inline]
+            pub fn skip_all(encoded: &[u8], records: &[variant_ty!($variant)], verify: bool) {
+                let mut position = 0;
+                for record in records.chunks(SKIP_RECORD_LEN) {
+                    position = skip_leb128(encoded, position, SKIP_RECORD_LEN - 1).unwrap();
+                    let (val, read) = read_leb128_ref_u64(encoded, position);
+                    if verify {
+                        assert_eq!(val, record[SKIP_RECORD_LEN - 1] as u64);
+                    }
+                    ::core::hint::black_box(val);
+                    position += read;
+                }
+            }
+
+            #[inline]
+            pub fn read_all(encoded: &[u8], records: &[variant_ty!($variant)], verify: bool) {
+                let mut position = 0;
+                for record in records.chunks(SKIP_RECORD_LEN) {
+                    let mut val = 0;
+                    for _ in 0 .. SKIP_RECORD_LEN {
+                        let (v, read) = read_leb128_ref_u64(encoded, position);
+                        val = v;
+                        position += read;
+                    }
+                    if verify {
+                        assert_eq!(val, record[SKIP_RECORD_LEN - 1] as u64);
+                    }
+                    ::core::hint::black_box(val);
+                }
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let records = records(&test_data);
+                let encoded = encode(records);
+
+                skip_all(&encoded, records, true);
+                read_all(&encoded, records, true);
+            }
+        }
+
+        impl_skip_bench!(@bench $skip_bench, $skip_bench, $skip_bench::skip_all);
+        impl_skip_bench!(@bench $read_bench, $skip_bench, $skip_bench::read_all);
+    );
+    (@bench $bench_name:ident, $module:ident, $read:path) => (
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $module::test_data();
+            let records = $module::records(&test_data);
+            let encoded = $module::encode(records);
+
+            b.bytes = encoded.len() as u64;
+
+            b.iter(|| $read(&encoded, records, false));
+        }
+    )
+}
+
+impl_skip_bench!(skip_leb128_u64_metadata, skip_by_reading_u64_metadata, U64, METADATA);
+impl_skip_bench!(skip_leb128_u64_dep_graph, skip_by_reading_u64_dep_graph, Usize, DEP_GRAPH);
+impl_skip_bench!(skip_leb128_u64_query_cache, skip_by_reading_u64_query_cache, U64, QUERY_CACHE);
+impl_skip_bench!(skip_leb128_u64_max_length, skip_by_reading_u64_max_length, U64, MAX_LENGTH);
+
+#[cfg(feature = "std")]
+#[test]
+fn skip_leb128_matches_reading() {
+    let mut encoded = Vec::new();
+
+    for entry in load_test_data(QUERY_CACHE).iter() {
+        if let Value::U64(val) = *entry {
+            let pos = encoded.len();
+            write_leb128c_u64(&mut encoded, pos, val);
+        }
+    }
+
+    let mut position = 0;
+    let mut skipped = 0;
+    while position < encoded.len() {
+        let mut expected = position;
+        let mut n = 0;
+        while n < SKIP_RECORD_LEN && expected < encoded.len() {
+            expected += read_leb128_ref_u64(&encoded, expected).1;
+            n += 1;
+        }
+
+        assert_eq!(skip_leb128(&encoded, position, n), Ok(expected));
+        position = expected;
+        skipped += n;
+    }
+    assert!(skipped > SKIP_RECORD_LEN);
+
+    assert_eq!(skip_leb128(&encoded, 0, 0), Ok(0));
+    assert_eq!(skip_leb128(&[0x80, 0x80], 0, 1), Err(DecodeError::UnexpectedEof));
+    assert_eq!(skip_leb128(&[0x01], 0, 2), Err(DecodeError::UnexpectedEof));
+    assert_eq!(skip_leb128(&[0x80; 20], 0, 1), Err(DecodeError::Overlong));
+}
+
+// Columnar readers that only want every `STRIDE`th value.
+const STRIDE: usize = 4;
+
+pub fn decode_stride_leb128_u64(data: &[u8], count: usize, stride: usize) -> Vec<u64> {
+    let mut values = Vec::with_capacity(count.div_ceil(stride));
+    let mut position = 0;
+
+    for i in (0 .. count).step_by(stride) {
+        let (val, read) = read_leb128_ref_u64(data, position);
+        values.push(val);
+        position += read;
+
+        // Nothing to skip after the last wanted value.
+        let skip = cmp::min(stride - 1, count - i - 1);
+        position = skip_leb128(data, position, skip).unwrap();
+    }
+
+    values
+}
+
+macro_rules! impl_stride_bench {
+    ($skip_bench:ident, $read_bench:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $skip_bench {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            #[inline]
+            pub fn read_all(encoded: &[u8], count: usize) -> Vec<u64> {
+                let mut values = Vec::with_capacity(count.div_ceil(STRIDE));
+                let mut position = 0;
+                for i in 0 .. count {
+                    let (val, read) = read_leb128_ref_u64(encoded, position);
+                    if i % STRIDE == 0 {
+                        values.push(val);
+                    }
+                    position += read;
+                }
+                values
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode(&test_data);
+                let expected: Vec<u64> = test_data.iter().step_by(STRIDE).map(|&val| val as u64).collect();
+
+                assert_eq!(decode_stride_leb128_u64(&encoded, test_data.len(), STRIDE), expected);
+                assert_eq!(read_all(&encoded, test_data.len()), expected);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $skip_bench(b: &mut test::Bencher) {
+
+            let test_data = $skip_bench::test_data();
+            let encoded = $skip_bench::encode(&test_data);
+
+            b.bytes = encoded.len() as u64;
+
+            b.iter(|| {
+                test::black_box(decode_stride_leb128_u64(&encoded, test_data.len(), STRIDE));
+            });
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $read_bench(b: &mut test::Bencher) {
+
+            let test_data = $skip_bench::test_data();
+            let encoded = $skip_bench::encode(&test_data);
+
+            b.bytes = encoded.len() as u64;
+
+            b.iter(|| {
+                test::black_box($skip_bench::read_all(&encoded, test_data.len()));
+            });
+        }
+    )
+}
+
+impl_stride_bench!(stride_skip_u64_metadata, stride_read_all_u64_metadata, U64, METADATA);
+impl_stride_bench!(stride_skip_u64_dep_graph, stride_read_all_u64_dep_graph, Usize, DEP_GRAPH);
+impl_stride_bench!(stride_skip_u64_query_cache, stride_read_all_u64_query_cache, U64, QUERY_CACHE);
+impl_stride_bench!(stride_skip_u64_max_length, stride_read_all_u64_max_length, U64, MAX_LENGTH);
+
+#[cfg(feature = "std")]
+#[test]
+fn decode_stride_matches_every_nth() {
+    let values: Vec<u64> = load_test_data(QUERY_CACHE).iter().filter_map(|entry| match *entry {
+        Value::U64(val) => Some(val),
+        _ => None,
+    }).collect();
+
+    let mut encoded = Vec::new();
+    for &val in values.iter() {
+        let pos = encoded.len();
+        write_leb128c_u64(&mut encoded, pos, val);
+    }
+
+    // Counts that do and don't end on a wanted value.
+    for &count in &[0, 1, 4, 5, 7, values.len()] {
+        let expected: Vec<u64> = values[.. count].iter().cloned().step_by(STRIDE).collect();
+        assert_eq!(decode_stride_leb128_u64(&encoded, count, STRIDE), expected);
+    }
+}
+
+
+// Continuation bit in the low bit ---------------------------------------------
+//
+// Same groups of seven bits as leb128, but every byte stores the payload in
+// its high seven bits and the continuation flag in bit 0. Encoded lengths are
+// identical, only the shifts and masks the compiler has to emit differ.
+
+#[inline]
+pub fn write_leb128_lsbcont_u64(out: &mut Vec<u8>, start_position: usize, mut value: u64) -> usize {
+    let mut position = start_position;
+    loop {
+        let byte = ((value & 0x7F) as u8) << 1;
+        value >>= 7;
+
+        if value == 0 {
+            write_to_vec(out, position, byte);
+            position += 1;
+            break;
+        }
+
+        write_to_vec(out, position, byte | 1);
+        position += 1;
+    }
+
+    position - start_position
+}
+
+#[inline]
+pub fn read_leb128_lsbcont_u64(data: &[u8], start_position: usize) -> (u64, usize) {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut position = start_position;
+    loop {
+        let byte = data[position];
+        position += 1;
+        result |= ((byte >> 1) as u64) << shift;
+        if (byte & 1) == 0 {
+            break;
+        }
+        shift += 7;
+    }
+
+    (result, position - start_position)
+}
+
+impl_varint_codec!(Leb128LsbContU64, write_leb128_lsbcont_u64, read_leb128_lsbcont_u64, u64, leb128_len_u64);
+
+// Compare with write_leb128c_u64_* and read_leb128_ref_u64_*.
+impl_bench!(write_leb128_lsbcont_u64_metadata, U64, Leb128LsbContU64, METADATA);
+impl_bench!(write_leb128_lsbcont_u64_dep_graph, U64, Leb128LsbContU64, DEP_GRAPH);
+impl_bench!(write_leb128_lsbcont_u64_query_cache, U64, Leb128LsbContU64, QUERY_CACHE);
+
+impl_read_bench!(read_leb128_lsbcont_u64_metadata, U64, read_leb128_lsbcont_u64, METADATA, Leb128LsbContU64);
+impl_read_bench!(read_leb128_lsbcont_u64_dep_graph, U64, read_leb128_lsbcont_u64, DEP_GRAPH, Leb128LsbContU64);
+impl_read_bench!(read_leb128_lsbcont_u64_query_cache, U64, read_leb128_lsbcont_u64, QUERY_CACHE, Leb128LsbContU64);
+
+#[cfg(feature = "std")]
+#[test]
+fn leb128_lsbcont_round_trip() {
+    let mut encoded = Vec::new();
+    assert_eq!(write_leb128_lsbcont_u64(&mut encoded, 0, 300), 2);
+    // 300 = 0b10_0101100: low group 0x2C with the flag, then 0x02.
+    assert_eq!(encoded, [0x2C << 1 | 1, 0x02 << 1]);
+
+    let mut values: Vec<u64> = vec![0, 1, 127, 128, 16383, 16384, u64::MAX];
+    values.extend(load_test_data(METADATA).iter().filter_map(|entry| match *entry {
+        Value::U64(val) => Some(val),
+        _ => None,
+    }));
+
+    let mut encoded = Vec::new();
+    for &value in values.iter() {
+        let pos = encoded.len();
+        assert_eq!(write_leb128_lsbcont_u64(&mut encoded, pos, value), leb128_len_u64(value));
+    }
+
+    let mut position = 0;
+    for &value in values.iter() {
+        let (decoded, read) = read_leb128_lsbcont_u64(&encoded, position);
+        assert_eq!(decoded, value);
+        position += read;
+    }
+
+    assert_eq!(position, encoded.len());
+}
+
+
+// Tags in a separate stream ---------------------------------------------------
+//
+// Structure-of-arrays layout: the byte width of every value (1 to 4, stored
+// as 0 to 3) goes into a tag stream, four 2-bit tags per byte starting at the
+// low bits. The value bytes themselves go into a data stream, little-endian
+// and without any flag bits, so the decoder knows each length before touching
+// the data.
+
+#[inline]
+fn split_stream_width(value: u32) -> usize {
+    cmp::max(1, (32 - value.leading_zeros() as usize).div_ceil(8))
+}
+
+pub fn write_split_stream_u32(values: &[u32]) -> (Vec<u8>, Vec<u8>) {
+    let mut tags = vec![0u8; values.len().div_ceil(4)];
+    let mut data = Vec::with_capacity(values.len() * 4);
+
+    for (i, &value) in values.iter().enumerate() {
+        let width = split_stream_width(value);
+        tags[i / 4] |= ((width - 1) as u8) << (2 * (i % 4));
+        data.extend_from_slice(&value.to_le_bytes()[.. width]);
+    }
+
+    (tags, data)
+}
+
+// `count` is needed because the last tag byte may only be partially used.
+pub fn read_split_stream_u32(tags: &[u8], data: &[u8], count: usize) -> Vec<u32> {
+    let mut values = Vec::with_capacity(count);
+    let mut position = 0;
+
+    for i in 0 .. count {
+        let width = ((tags[i / 4] >> (2 * (i % 4))) & 3) as usize + 1;
+
+        let value = if position + 4 <= data.len() {
+            // One unaligned load, masked down to the bytes of this value.
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&data[position .. position + 4]);
+            u32::from_le_bytes(bytes) & (u32::MAX >> (32 - 8 * width))
+        } else {
+            let mut bytes = [0u8; 4];
+            bytes[.. width].copy_from_slice(&data[position .. position + width]);
+            u32::from_le_bytes(bytes)
+        };
+
+        values.push(value);
+        position += width;
+    }
+
+    values
+}
+
+macro_rules! impl_split_stream_bench {
+    ($split_bench:ident, $leb128_bench:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $split_bench {
+            use super::*;
+
+            impl_bench_data!(U32, $data);
+
+            // Decodes into a Vec as well, so both sides pay for the output.
+            #[inline]
+            pub fn read_leb128(encoded: &[u8], count: usize) -> Vec<u32> {
+                let mut values = Vec::with_capacity(count);
+                let mut position = 0;
+                for _ in 0 .. count {
+                    let (val, read) = read_leb128_unsafe_u32(encoded, position);
+                    values.push(val);
+                    position += read;
+                }
+                values
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+
+                let (tags, data) = write_split_stream_u32(&test_data);
+                assert_eq!(read_split_stream_u32(&tags, &data, test_data.len()), test_data);
+
+                assert_eq!(read_leb128(&encode(&test_data), test_data.len()), test_data);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $split_bench(b: &mut test::Bencher) {
+
+            let test_data = $split_bench::test_data();
+            b.bytes = $split_bench::input_bytes(&test_data);
+
+            let (tags, data) = write_split_stream_u32(&test_data);
+
+            b.iter(|| {
+                test::black_box(read_split_stream_u32(&tags, &data, test_data.len()));
+            });
+
+            print!("tags: {} bytes, data: {} bytes, ", tags.len(), data.len());
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $leb128_bench(b: &mut test::Bencher) {
+
+            let test_data = $split_bench::test_data();
+            b.bytes = $split_bench::input_bytes(&test_data);
+
+            let encoded = $split_bench::encode(&test_data);
+
+            b.iter(|| {
+                test::black_box($split_bench::read_leb128(&encoded, test_data.len()));
+            });
+
+            print!("leb128: {} bytes, ", encoded.len());
+        }
+    )
+}
+
+impl_split_stream_bench!(read_split_stream_u32_dep_graph, read_leb128_unsafe_u32_vec_dep_graph, DEP_GRAPH);
+
+#[cfg(feature = "std")]
+#[test]
+fn split_stream_round_trip() {
+    let values = [0, 0xFF, 0x100, 0xFFFF, 0x1_0000, 0xFF_FFFF, 0x100_0000, u32::MAX, 7];
+    assert_eq!(values.len() % 4, 1);
+
+    let (tags, data) = write_split_stream_u32(&values);
+    assert_eq!(tags, [0b01_01_00_00, 0b11_11_10_10, 0b00]);
+    assert_eq!(data.len(), 1 + 1 + 2 + 2 + 3 + 3 + 4 + 4 + 1);
+    assert_eq!(read_split_stream_u32(&tags, &data, values.len()), values);
+
+    let dep_graph: Vec<u32> = load_test_data(DEP_GRAPH).iter().filter_map(|entry| match *entry {
+        Value::U32(val) => Some(val),
+        _ => None,
+    }).take(4003).collect();
+
+    let (tags, data) = write_split_stream_u32(&dep_graph);
+    assert_eq!(read_split_stream_u32(&tags, &data, dep_graph.len()), dep_graph);
+
+    let (tags, data) = write_split_stream_u32(&[]);
+    assert!(tags.is_empty() && data.is_empty());
+    assert!(read_split_stream_u32(&tags, &data, 0).is_empty());
+}
+
+
+// Sign flag byte --------------------------------------------------------------
+//
+// A separate byte for the sign followed by the magnitude as unsigned leb128.
+// Zigzag folds the sign into the lowest bit of the magnitude instead, which
+// costs at most one extra bit of payload where this costs a whole byte on
+// every value. It's only here to put a number on that.
+
+#[inline]
+pub fn write_signflag_i64(out: &mut Vec<u8>, start_position: usize, value: i64) -> usize {
+    // Zero is never negative, so it always gets sign 0.
+    write_to_vec(out, start_position, (value < 0) as u8);
+    1 + write_leb128c_u64(out, start_position + 1, value.unsigned_abs())
+}
+
+#[inline]
+pub fn read_signflag_i64(data: &[u8], start_position: usize) -> (i64, usize) {
+    let negative = data[start_position] != 0;
+    let (magnitude, read) = read_leb128_ref_u64(data, start_position + 1);
+
+    // `i64::MIN` has a magnitude of 2^63, which only fits through wrapping.
+    let value = if negative {
+        (magnitude as i64).wrapping_neg()
+    } else {
+        magnitude as i64
+    };
+
+    (value, 1 + read)
+}
+
+macro_rules! impl_signflag_bench {
+    ($bench_name:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bench_name {
+            use super::*;
+
+            impl_bench_data!(@values I64, $data);
+
+            #[inline]
+            pub fn encode_signflag(test_data: &[i64]) -> Vec<u8> {
+                let mut output = Vec::new();
+                let mut position = 0;
+                for &val in test_data.iter() {
+                    position += write_signflag_i64(&mut output, position, val);
+                }
+                output
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let encoded = encode_signflag(&test_data);
+
+                let mut position = 0;
+                for &expected in test_data.iter() {
+                    let (val, count) = read_signflag_i64(&encoded, position);
+                    assert_eq!(val, expected);
+                    position += count;
+                }
+                assert_eq!(position, encoded.len());
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bench_name(b: &mut test::Bencher) {
+
+            let test_data = $bench_name::test_data();
+            b.bytes = $bench_name::input_bytes(&test_data);
+
+            let mut size = 0;
+            b.iter(|| {
+                size = $bench_name::encode_signflag(&test_data).len();
+            });
+
+            let zigzag: usize = test_data.iter().map(|&v| leb128_len_u64(zigzag_encode_i64(v))).sum();
+
+            print!("sign flag: {} bytes, zigzag: {} bytes, ", size, zigzag);
+        }
+    )
+}
+
+impl_signflag_bench!(write_signflag_i64_metadata, METADATA);
+impl_signflag_bench!(write_signflag_i64_query_cache, QUERY_CACHE);
+
+#[test]
+fn signflag_round_trip() {
+    let cases: &[(i64, &[u8])] = &[
+        (0, &[0x00, 0x00]),
+        (1, &[0x00, 0x01]),
+        (-1, &[0x01, 0x01]),
+        (-128, &[0x01, 0x80, 0x01]),
+        (i64::MAX, &[0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F]),
+        (i64::MIN, &[0x01, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]),
+    ];
+
+    let mut encoded = Vec::new();
+    for &(value, bytes) in cases {
+        let pos = encoded.len();
+        assert_eq!(write_signflag_i64(&mut encoded, pos, value), bytes.len());
+        assert_eq!(&encoded[pos ..], bytes);
+    }
+
+    let mut position = 0;
+    for &(value, bytes) in cases {
+        assert_eq!(read_signflag_i64(&encoded, position), (value, bytes.len()));
+        position += bytes.len();
+    }
+
+    // Never shorter than zigzag.
+    for &(value, bytes) in cases {
+        assert!(bytes.len() >= leb128_len_u64(zigzag_encode_i64(value)));
+    }
+}
+
+
+// Decoders that accept any input ----------------------------------------------
+//
+// `read_leb128_ref_u64` and `read_lesqlite_u32` trust their input: a truncated
+// value indexes past the end of the slice, and `read_lesqlite_u32` shifts past
+// 32 bits for a first byte of 252 or more, which no u32 writer produces. The
+// variants below return an error for every input they can't decode instead,
+// which the test at the end of this section checks on generated buffers.
+
+pub fn try_read_leb128_u64(data: &[u8], start_position: usize) -> Result<(u64, usize), DecodeError> {
+    let mut result = 0;
+    let mut shift = 0;
+    let mut position = start_position;
+
+    for i in 0 .. leb128_size!(u64) {
+        let byte = *data.get(position).ok_or(DecodeError::UnexpectedEof)?;
+        position += 1;
+
+        // Only bit 63 is left for the tenth group.
+        if i == leb128_size!(u64) - 1 && (byte & 0x7E) != 0 {
+            return Err(DecodeError::Overflow);
+        }
+
+        result |= ((byte & 0x7F) as u64) << shift;
+        if (byte & 0x80) == 0 {
+            return Ok((result, position - start_position));
+        }
+        shift += 7;
+    }
+
+    Err(DecodeError::Overlong)
+}
+
+pub fn try_read_lesqlite_u32(data: &[u8], start_position: usize) -> Result<(u32, usize), DecodeError> {
+    const CUT1: u32 = 185;
+    const CUT2: u32 = lesqlite_cut2!(u32);
+
+    let first = *data.get(start_position).ok_or(DecodeError::UnexpectedEof)? as u32;
+
+    if first < CUT1 {
+        return Ok((first, 1));
+    }
+
+    if first < CUT2 {
+        let second = *data.get(start_position + 1).ok_or(DecodeError::UnexpectedEof)? as u32;
+        return Ok((CUT1 + ((first - CUT1) << 8) + second, 2));
+    }
+
+    let bytes = (first - CUT2) as usize + 2;
+    if bytes > mem::size_of::<u32>() {
+        return Err(DecodeError::Overflow);
+    }
+
+    let payload = data.get(start_position + 1 .. start_position + 1 + bytes)
+                      .ok_or(DecodeError::UnexpectedEof)?;
+
+    let mut le = [0u8; 4];
+    le[.. bytes].copy_from_slice(payload);
+    Ok((u32::from_le_bytes(le), bytes + 1))
+}
+
+// Every buffer of up to two bytes, then random buffers whose bytes are biased
+// towards continuation bits and lesqlite length markers, decoded from every
+// start position including the end of the buffer and one past it.
+#[test]
+fn try_decoders_are_total() {
+    fn check(data: &[u8]) {
+        for start in 0 .. data.len() + 2 {
+            if let Ok((value, read)) = try_read_leb128_u64(data, start) {
+                assert!(read >= 1 && start + read <= data.len());
+                assert_eq!(read_leb128_ref_u64(data, start), (value, read));
+            }
+
+            if let Ok((value, read)) = try_read_lesqlite_u32(data, start) {
+                assert!(read >= 1 && start + read <= data.len());
+                assert_eq!(read_lesqlite_u32(data, start), (value, read));
+            }
+        }
+    }
+
+    check(&[]);
+    for a in 0 ..= 255u8 {
+        check(&[a]);
+        for b in 0 ..= 255u8 {
+            check(&[a, b]);
+        }
+    }
+
+    let mut state = 0x9E37_79B9_7F4A_7C15;
+    let mut buffer = Vec::new();
+    for _ in 0 .. 20_000 {
+        buffer.clear();
+        let len = (xorshift64(&mut state) % 24) as usize;
+
+        for _ in 0 .. len {
+            let random = xorshift64(&mut state);
+            buffer.push(match random % 4 {
+                0 => 0x80 | (random >> 8) as u8,
+                1 => 0xF9 + (random >> 8) as u8 % 7,
+                _ => (random >> 8) as u8,
+            });
+        }
+
+        check(&buffer);
+    }
+
+    assert_eq!(try_read_leb128_u64(&[0x80; 11], 0), Err(DecodeError::Overlong));
+    assert_eq!(try_read_leb128_u64(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02], 0),
+               Err(DecodeError::Overflow));
+    assert_eq!(try_read_lesqlite_u32(&[0xFC, 0, 0, 0, 0, 0], 0), Err(DecodeError::Overflow));
+    assert_eq!(try_read_lesqlite_u32(&[0xFB, 1, 2, 3], 0), Err(DecodeError::UnexpectedEof));
+}
+
+// For looking at a buffer when a round trip goes wrong: one line per value
+// with its offset, its bytes in hex and what they decode to. Stops at the
+// first value that doesn't decode and prints the error instead.
+pub fn dump_leb128(data: &[u8]) -> String {
+    use core::fmt::Write;
+
+    let mut out = String::new();
+    let mut position = 0;
+
+    while position < data.len() {
+        write!(out, "@{}:", position).unwrap();
+
+        match try_read_leb128_u64(data, position) {
+            Ok((value, read)) => {
+                for byte in &data[position .. position + read] {
+                    write!(out, " {:02x}", byte).unwrap();
+                }
+                writeln!(out, " -> {}", value).unwrap();
+                position += read;
+            }
+            Err(err) => {
+                writeln!(out, " {:?}", err).unwrap();
+                break;
+            }
+        }
+    }
+
+    out
+}
+
+#[test]
+fn dump_leb128_annotates_values() {
+    let data = [0x05, 0xAC, 0x02, 0x81, 0x00, 0x80, 0x80];
+
+    assert_eq!(dump_leb128(&data),
+               "@0: 05 -> 5\n\
+                @1: ac 02 -> 300\n\
+                @3: 81 00 -> 1\n\
+                @5: UnexpectedEof\n");
+
+    assert_eq!(dump_leb128(&data[.. 5]), "@0: 05 -> 5\n@1: ac 02 -> 300\n@3: 81 00 -> 1\n");
+    assert_eq!(dump_leb128(&[]), "");
+}
+
+
+// Prefetching ahead of the decoder --------------------------------------------
+//
+// For the big datasets that don't fit into the cache: before decoding a value,
+// ask for the cache line one line ahead of it. The values are consumed
+// front to back, so that's where the reader will be a few values later.
+// Decoding itself is `read_leb128_unsafe_u64`, with the same contract.
+
+const PREFETCH_DISTANCE: usize = 64;
+
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn prefetch_read(data: &[u8], position: usize) {
+    use core::arch::x86_64::*;
+
+    // A prefetch never faults, but the pointer arithmetic must not leave the
+    // allocation as far as the compiler knows, hence `wrapping_add`.
+    unsafe {
+        _mm_prefetch::<_MM_HINT_T0>(data.as_ptr().wrapping_add(position) as *const i8);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+#[inline]
+fn prefetch_read(_data: &[u8], _position: usize) {}
+
+/// Decodes the leb128 u64 at `start_position` after prefetching the cache line
+/// `PREFETCH_DISTANCE` bytes ahead of it.
+///
+/// # Safety
+///
+/// The value's terminating byte must lie within `data`. Like
+/// `read_leb128_unsafe_u64`, the bytes are read without bounds checks.
+#[inline]
+pub unsafe fn read_leb128_prefetch_u64(data: &[u8], start_position: usize) -> (u64, usize) {
+    prefetch_read(data, start_position + PREFETCH_DISTANCE);
+    read_leb128_unsafe_u64(data, start_position)
+}
+
+// Compare with read_benches::read_leb128_unsafe_u64_*.
+impl_read_bench!(read_leb128_prefetch_u64_dep_graph, Usize, read_leb128_prefetch_u64, DEP_GRAPH, unsafe);
+impl_read_bench!(read_leb128_prefetch_u64_query_cache, U64, read_leb128_prefetch_u64, QUERY_CACHE, unsafe);
+
+#[cfg(feature = "std")]
+#[test]
+fn read_leb128_prefetch_matches_ref() {
+    for &name in &[DEP_GRAPH, QUERY_CACHE] {
+        let mut encoded = Vec::new();
+
+        for entry in load_test_data(name).iter() {
+            if let Value::U64(val) = *entry {
+                let pos = encoded.len();
+                write_leb128c_u64(&mut encoded, pos, val);
+            }
+        }
+
+        // Includes the values in the last 64 bytes, which prefetch past the
+        // end of the buffer.
+        let mut position = 0;
+        while position < encoded.len() {
+            let expected = read_leb128_ref_u64(&encoded, position);
+            // The loop only starts at the beginning of a complete value.
+            assert_eq!(unsafe { read_leb128_prefetch_u64(&encoded, position) }, expected);
+            position += expected.1;
+        }
+    }
+}
+
+
+// Writing with a cursor -------------------------------------------------------
+//
+// The writing side of `RecordReader`: the output and the position to write at
+// next travel together. In contrast to `RecordWriter` it can start in the
+// middle of an existing buffer and overwrite from there, like the free writers.
+
+pub struct Writer {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer::with_capacity(0)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Writer {
+        Writer { buf: Vec::with_capacity(capacity), pos: 0 }
+    }
+
+    // Continues writing `buf` at `pos`, which may be anywhere up to its end.
+    pub fn from_vec(buf: Vec<u8>, pos: usize) -> Writer {
+        assert!(pos <= buf.len());
+        Writer { buf, pos }
+    }
+
+    #[inline]
+    pub fn push_u64(&mut self, v: u64) {
+        self.pos += write_leb128c_u64(&mut self.buf, self.pos, v);
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Writer {
+        Writer::new()
+    }
+}
+
+#[test]
+fn writer_matches_manual_loop() {
+    let values = [0, 1, 127, 128, 300, 1 << 35, u64::MAX, 42];
+
+    let mut expected = Vec::new();
+    let mut position = 0;
+    for &val in values.iter() {
+        position += write_leb128c_u64(&mut expected, position, val);
+    }
+
+    let mut writer = Writer::new();
+    for &val in values.iter() {
+        writer.push_u64(val);
+    }
+    assert_eq!(writer.position(), position);
+    assert_eq!(writer.finish(), expected);
+
+    // Picking up in the middle overwrites from there on.
+    let mut writer = Writer::from_vec(expected.clone(), 1);
+    writer.push_u64(2);
+    assert_eq!(writer.position(), 2);
+    let overwritten = writer.finish();
+    assert_eq!(overwritten.len(), expected.len());
+    assert_eq!(overwritten[1], 2);
+}
+
+
+// Continuation bits in a separate bitmap --------------------------------------
+//
+// The groups of seven bits are the same as in leb128, but the continuation
+// bits are taken out of the bytes and packed into a bitmap, one bit per
+// payload byte, lowest bit first. The payload bytes then never need masking.
+// Layout: the number of payload bytes as leb128, the bitmap, the payload.
+
+pub fn write_bitplane_u64(values: &[u64]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(values.len() * 2);
+    let mut bitmap = Vec::with_capacity(values.len() / 4 + 1);
+
+    for &value in values.iter() {
+        let mut value = value;
+        loop {
+            let index = payload.len();
+            if index % 8 == 0 {
+                bitmap.push(0);
+            }
+
+            payload.push((value & 0x7F) as u8);
+            value >>= 7;
+
+            if value == 0 {
+                break;
+            }
+            bitmap[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    let mut out = Vec::with_capacity(MAX_LEB128_LEN_USIZE + bitmap.len() + payload.len());
+    write_leb128c_usize(&mut out, 0, payload.len());
+    out.extend_from_slice(&bitmap);
+    out.extend_from_slice(&payload);
+    out
+}
+
+pub fn read_bitplane_u64(data: &[u8]) -> Vec<u64> {
+    let (len, read) = read_leb128_ref_usize(data, 0);
+    let bitmap = &data[read .. read + len.div_ceil(8)];
+    let payload = &data[read + bitmap.len() .. read + bitmap.len() + len];
+
+    let mut values = Vec::with_capacity(len);
+    let mut value = 0;
+    let mut shift = 0;
+
+    for (i, &byte) in payload.iter().enumerate() {
+        value |= (byte as u64) << shift;
+
+        if bitmap[i / 8] & (1 << (i % 8)) == 0 {
+            values.push(value);
+            value = 0;
+            shift = 0;
+        } else {
+            shift += 7;
+        }
+    }
+
+    values
+}
+
+macro_rules! impl_bitplane_bench {
+    ($bitplane_bench:ident, $leb128_bench:ident, $variant:ident, $data:ident) => (
+        #[cfg(all(test, feature = "std"))]
+        pub mod $bitplane_bench {
+            use super::*;
+
+            impl_bench_data!($variant, $data);
+
+            pub fn widen(test_data: &[variant_ty!($variant)]) -> Vec<u64> {
+                test_data.iter().map(|&val| val as u64).collect()
+            }
+
+            // Decodes into a Vec as well, so both sides pay for the output.
+            #[inline]
+            pub fn read_leb128(encoded: &[u8], count: usize) -> Vec<u64> {
+                let mut values = Vec::with_capacity(count);
+                let mut position = 0;
+                for _ in 0 .. count {
+                    let (val, read) = read_leb128_unsafe_u64(encoded, position);
+                    values.push(val);
+                    position += read;
+                }
+                values
+            }
+
+            #[test]
+            fn run_once() {
+                let test_data = test_data();
+                let widened = widen(&test_data);
+
+                assert_eq!(read_bitplane_u64(&write_bitplane_u64(&widened)), widened);
+                assert_eq!(read_leb128(&encode(&test_data), test_data.len()), widened);
+            }
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $bitplane_bench(b: &mut test::Bencher) {
+
+            let test_data = $bitplane_bench::test_data();
+            b.bytes = $bitplane_bench::input_bytes(&test_data);
+
+            let encoded = write_bitplane_u64(&$bitplane_bench::widen(&test_data));
+
+            b.iter(|| {
+                test::black_box(read_bitplane_u64(&encoded));
+            });
+
+            print!("size: {} bytes, ", encoded.len());
+        }
+
+        #[cfg(all(test, feature = "nightly-bench"))]
+        #[bench]
+        fn $leb128_bench(b: &mut test::Bencher) {
+
+            let test_data = $bitplane_bench::test_data();
+            b.bytes = $bitplane_bench::input_bytes(&test_data);
+
+            let encoded = $bitplane_bench::encode(&test_data);
+
+            b.iter(|| {
+                test::black_box($bitplane_bench::read_leb128(&encoded, test_data.len()));
+            });
+
+            print!("size: {} bytes, ", encoded.len());
+        }
+    )
+}
+
+impl_bitplane_bench!(read_bitplane_u64_metadata, read_leb128_unsafe_u64_vec_metadata, U64, METADATA);
+impl_bitplane_bench!(read_bitplane_u64_dep_graph, read_leb128_unsafe_u64_vec_dep_graph, Usize, DEP_GRAPH);
+impl_bitplane_bench!(read_bitplane_u64_query_cache, read_leb128_unsafe_u64_vec_query_cache, U64, QUERY_CACHE);
+
+#[cfg(feature = "std")]
+#[test]
+fn bitplane_round_trip() {
+    // 1 + 2 + 1 + 10 + 1 = 15 payload bytes, so the second bitmap byte is
+    // only partially used.
+    let values = [5, 300, 0, u64::MAX, 127];
+
+    let encoded = write_bitplane_u64(&values);
+    assert_eq!(encoded[0], 15);
+    // Continuation bits set for the first byte of 300 and the first nine
+    // of u64::MAX (payload bytes 1 and 4 to 12).
+    assert_eq!(&encoded[1 .. 3], &[0b1111_0010, 0b0001_1111]);
+    assert_eq!(encoded.len(), 1 + 2 + 15);
+    assert!(encoded[3 ..].iter().all(|&byte| byte < 0x80));
+    assert_eq!(read_bitplane_u64(&encoded), values);
+
+    let metadata: Vec<u64> = load_test_data(METADATA).iter().filter_map(|entry| match *entry {
+        Value::U64(val) => Some(val),
+        _ => None,
+    }).collect();
+    assert_eq!(read_bitplane_u64(&write_bitplane_u64(&metadata)), metadata);
+
+    assert!(read_bitplane_u64(&write_bitplane_u64(&[])).is_empty());
+}
+
+
+// Group sizes other than seven bits -------------------------------------------
+//
+// leb128 with the group size as a parameter: every byte carries `BITS` bits of
+// payload and the bit above them says whether another byte follows. The bits
+// above that stay zero. `BITS = 7` is plain leb128, smaller groups need more
+// bytes per value but leave room for other flags in each byte.
+
+#[inline]
+pub fn write_groupn_u64<const BITS: u32>(out: &mut Vec<u8>, start_position: usize, mut value: u64) -> usize {
+    const { assert!(BITS >= 1 && BITS <= 7) };
+
+    let mask = (1u64 << BITS) - 1;
+    let mut position = start_position;
+
+    loop {
+        let mut byte = (value & mask) as u8;
+        value >>= BITS;
+        if value != 0 {
+            byte |= 1 << BITS;
+        }
+
+        write_to_vec(out, position, byte);
+        position += 1;
+
+        if value == 0 {
+            break;
+        }
+    }
+
+    position - start_position
+}
+
+#[inline]
+pub fn read_groupn_u64<const BITS: u32>(data: &[u8], start_position: usize) -> (u64, usize) {
+    const { assert!(BITS >= 1 && BITS <= 7) };
+
+    let mask = (1u8 << BITS) - 1;
+    let mut result = 0;
+    let mut shift = 0;
+    let mut position = start_position;
+
+    loop {
+        let byte = data[position];
+        position += 1;
+        result |= ((byte & mask) as u64) << shift;
+        if (byte >> BITS) & 1 == 0 {
+            break;
+        }
+        shift += BITS;
+    }
+
+    (result, position - start_position)
+}
+
+#[inline]
+pub fn groupn_len_u64<const BITS: u32>(value: u64) -> usize {
+    let bits = 64 - (value | 1).leading_zeros() as usize;
+    bits.div_ceil(BITS as usize)
+}
+
+// The bench macros want plain function names.
+macro_rules! impl_groupn {
+    ($marker:ident, $write:ident, $read:ident, $len:ident, $bits:expr) => (
+        #[inline]
+        pub fn $write(out: &mut Vec<u8>, start_position: usize, value: u64) -> usize {
+            write_groupn_u64::<$bits>(out, start_position, value)
+        }
+
+        #[inline]
+        pub fn $read(data: &[u8], start_position: usize) -> (u64, usize) {
+            read_groupn_u64::<$bits>(data, start_position)
+        }
+
+        #[inline]
+        pub fn $len(value: u64) -> usize {
+            groupn_len_u64::<$bits>(value)
+        }
+
+        impl_varint_codec!($marker, $write, $read, u64, $len);
+    )
+}
+
+impl_groupn!(Group6U64, write_group6_u64, read_group6_u64, group6_len_u64, 6);
+impl_groupn!(Group7U64, write_group7_u64, read_group7_u64, group7_len_u64, 7);
+
+impl_bench!(write_group6_u64_metadata, U64, Group6U64, METADATA);
+impl_bench!(write_group6_u64_dep_graph, U64, Group6U64, DEP_GRAPH);
+impl_bench!(write_group6_u64_query_cache, U64, Group6U64, QUERY_CACHE);
+impl_bench!(write_group6_u64_max_length, U64, Group6U64, MAX_LENGTH);
+
+impl_bench!(write_group7_u64_metadata, U64, Group7U64, METADATA);
+impl_bench!(write_group7_u64_dep_graph, U64, Group7U64, DEP_GRAPH);
+impl_bench!(write_group7_u64_query_cache, U64, Group7U64, QUERY_CACHE);
+impl_bench!(write_group7_u64_max_length, U64, Group7U64, MAX_LENGTH);
+
+impl_read_bench!(read_group6_u64_metadata, U64, read_group6_u64, METADATA, Group6U64);
+impl_read_bench!(read_group6_u64_dep_graph, U64, read_group6_u64, DEP_GRAPH, Group6U64);
+impl_read_bench!(read_group6_u64_query_cache, U64, read_group6_u64, QUERY_CACHE, Group6U64);
+impl_read_bench!(read_group6_u64_max_length, U64, read_group6_u64, MAX_LENGTH, Group6U64);
+
+impl_read_bench!(read_group7_u64_metadata, U64, read_group7_u64, METADATA, Group7U64);
+impl_read_bench!(read_group7_u64_dep_graph, U64, read_group7_u64, DEP_GRAPH, Group7U64);
+impl_read_bench!(read_group7_u64_query_cache, U64, read_group7_u64, QUERY_CACHE, Group7U64);
+impl_read_bench!(read_group7_u64_max_length, U64, read_group7_u64, MAX_LENGTH, Group7U64);
+
+#[cfg(feature = "std")]
+#[test]
+fn groupn_round_trip() {
+    let mut values: Vec<u64> = vec![0, 1, 63, 64, 127, 128, 4095, 4096, 1 << 35, u64::MAX];
+    values.extend(load_test_data(QUERY_CACHE).iter().filter_map(|entry| match *entry {
+        Value::U64(val) => Some(val),
+        _ => None,
+    }));
+
+    let mut group6 = Vec::new();
+    let mut group7 = Vec::new();
+    for &value in values.iter() {
+        let pos = group6.len();
+        assert_eq!(write_group6_u64(&mut group6, pos, value), group6_len_u64(value));
+        let pos = group7.len();
+        assert_eq!(write_group7_u64(&mut group7, pos, value), group7_len_u64(value));
+    }
+
+    // Seven bit groups are leb128.
+    let mut leb128 = Vec::new();
+    for &value in values.iter() {
+        let pos = leb128.len();
+        write_leb128c_u64(&mut leb128, pos, value);
+    }
+    assert_eq!(group7, leb128);
+
+    let mut position6 = 0;
+    let mut position7 = 0;
+    for &value in values.iter() {
+        let (decoded, read) = read_group6_u64(&group6, position6);
+        assert_eq!(decoded, value);
+        position6 += read;
+
+        let (decoded, read) = read_group7_u64(&group7, position7);
+        assert_eq!(decoded, value);
+        position7 += read;
+    }
+    assert_eq!((position6, position7), (group6.len(), group7.len()));
+
+    let mut out = Vec::new();
+    assert_eq!(write_group6_u64(&mut out, 0, 64), 2);
+    assert_eq!(out, [0x40, 0x01]);
+    assert_eq!(write_group6_u64(&mut out, 0, u64::MAX), 11);
+}
+
+
+// Instruction and branch miss counts ------------------------------------------
+//
+// Hardware counters for the encode loop of a bench, which are much steadier
+// than wall-clock time on a shared machine. Meant for questions like whether
+// the `skewed` raw writers really mispredict less than the `slice` ones:
+//
+//     cargo test --features perf-counters perf_counters -- --nocapture
+//
+// Linux only, and the kernel has to allow perf events for the user (see
+// /proc/sys/kernel/perf_event_paranoid). Without that it says so and returns.
+
+#[cfg(all(test, feature = "perf-counters"))]
+fn print_counters_per_value<F: FnMut()>(name: &str, values: usize, mut run: F) {
+    use perf_event::{Builder, Group};
+    use perf_event::events::Hardware;
+
+    const ROUNDS: usize = 10;
+
+    let counters = Group::new().and_then(|mut group| {
+        let instructions = Builder::new().group(&mut group).kind(Hardware::INSTRUCTIONS).build()?;
+        let branch_misses = Builder::new().group(&mut group).kind(Hardware::BRANCH_MISSES).build()?;
+        Ok((group, instructions, branch_misses))
+    });
+
+    let (mut group, instructions, branch_misses) = match counters {
+        Ok(counters) => counters,
+        Err(err) => {
+            println!("{}: perf counters not available: {}", name, err);
+            return;
+        }
+    };
+
+    if values == 0 {
+        return;
+    }
+
+    // Warm up caches and the branch predictor outside of the measurement.
+    run();
+
+    group.enable().unwrap();
+    for _ in 0 .. ROUNDS {
+        run();
+    }
+    group.disable().unwrap();
+
+    let counts = group.read().unwrap();
+    let per_value = |count: u64| count as f64 / (ROUNDS * values) as f64;
+
+    println!("{}: {:.2} instructions, {:.4} branch misses per value",
+             name, per_value(counts[&instructions]), per_value(counts[&branch_misses]));
+}
+
+// Counts the `encode_all` of the `impl_bench!` module `$group::$bench`.
+macro_rules! impl_perf_counters {
+    ($test_name:ident, $group:ident, $bench:ident) => (
+        #[cfg(all(test, feature = "perf-counters"))]
+        #[test]
+        fn $test_name() {
+            let test_data = $group::$bench::test_data();
+            let capacity = $group::$bench::capacity(&test_data);
+
+            print_counters_per_value(stringify!($bench), test_data.len(), || {
+                ::core::hint::black_box($group::$bench::encode_all(&test_data, capacity));
+            });
+        }
+    )
+}
+
+impl_perf_counters!(perf_counters_raw_u64_slice_metadata, write_raw_benches, write_raw_u64_slice_metadata);
+impl_perf_counters!(perf_counters_raw_u64_slice_dep_graph, write_raw_benches, write_raw_u64_slice_dep_graph);
+impl_perf_counters!(perf_counters_raw_u64_slice_query_cache, write_raw_benches, write_raw_u64_slice_query_cache);
+
+impl_perf_counters!(perf_counters_raw_u64_skewed_metadata, write_raw_benches, write_raw_u64_skewed_metadata);
+impl_perf_counters!(perf_counters_raw_u64_skewed_dep_graph, write_raw_benches, write_raw_u64_skewed_dep_graph);
+impl_perf_counters!(perf_counters_raw_u64_skewed_query_cache, write_raw_benches, write_raw_u64_skewed_query_cache);